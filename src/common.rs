@@ -4,15 +4,16 @@
 
 pub use std::{
     borrow::{Borrow, ToOwned},
-    fmt::Display,
+    fmt::{self, Display},
     fs::{self, File},
     io::{self, prelude::*},
     mem,
     os::unix,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     process,
     rc::Rc,
     result::{self, Result as StdResult},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 // ##################################################
@@ -31,9 +32,14 @@ pub use walkdir::{self, WalkDir};
 
 pub use crate::{
     config::{Config, FsConfig},
-    env,
+    doctor, env,
     file_operations::FileOperations,
+    git,
     hermit::{Error, Hermit, Result},
-    message,
-    shell::Shell,
+    manifest::{self, Manifest},
+    message, redact, secrets,
+    shell::{Conflict, ConflictPolicy, Inputs, LinkMode, LinkState, Placement, Shell},
+    shell_name::ShellName,
+    state::{self, State},
+    template,
 };
@@ -1,4 +1,8 @@
-use crate::common::*;
+use crate::{common::*, hignore::IgnoreRules};
+
+/// The name of the file at the top of a shell that lists the shells
+/// it's layered on top of, one per line, lowest layer first.
+pub const BASES_FILE_NAME: &str = ".hbases";
 
 pub trait Config {
     type IntoIterator: IntoIterator<Item = PathBuf>;
@@ -9,27 +13,79 @@ pub trait Config {
         self.root_path().join("shells")
     }
 
-    fn current_shell_name(&self) -> Option<&str>;
+    /// Where nuked shells are kept until they expire.
+    fn trash_root_path(&self) -> PathBuf {
+        self.root_path().join("trash")
+    }
+
+    /// Where the key for encrypted secrets is kept while they're
+    /// unlocked. It lives outside of every shell, so it never gets
+    /// committed.
+    fn secrets_key_path(&self) -> PathBuf {
+        self.root_path().join("secrets.key")
+    }
+
+    /// Where this machine's values for templates are kept. Like the
+    /// secrets key, they're specific to the machine, not the shell.
+    fn machine_values_path(&self) -> PathBuf {
+        self.root_path().join("machine.toml")
+    }
+
+    /// Where hermit records what it has put into the home directory.
+    fn state_path(&self) -> PathBuf {
+        self.root_path().join("state.toml")
+    }
+
+    /// Where the batches of changes hermit has made are kept, so they
+    /// can be undone.
+    fn history_path(&self) -> PathBuf {
+        self.root_path().join("history")
+    }
+
+    fn current_shell_name(&self) -> Option<&ShellName>;
 
     fn current_shell_path(&self) -> Option<PathBuf> {
         self.current_shell_name()
             .map(|name| self.shell_root_path().join(name))
     }
 
-    fn set_current_shell_name(&mut self, name: &str) -> io::Result<()>;
+    /// The file that records which shell is current.
+    fn current_shell_file(&self) -> PathBuf {
+        config_path(self.root_path())
+    }
+
+    fn set_current_shell_name(&mut self, name: &ShellName) -> io::Result<()>;
+
+    fn shell_exists(&self, name: &ShellName) -> bool;
 
-    fn shell_exists(&self, name: &str) -> bool;
+    /// The names of every shell, in alphabetical order.
+    fn shell_names(&self) -> Vec<ShellName>;
 
-    fn shell_files(&self, name: &str) -> Self::IntoIterator;
+    /// The files in the shell called `name`, relative to its root.
+    /// When the shell has a manifest, only the files it includes.
+    fn shell_files(&self, name: &ShellName) -> Result<Self::IntoIterator>;
+
+    /// The shells that the shell called `name` is directly layered
+    /// on top of. Blank lines and `#` comments are left out.
+    fn base_shells(&self, name: &ShellName) -> Result<Vec<ShellName>> {
+        let path = self.shell_root_path().join(name).join(BASES_FILE_NAME);
+        let contents = fs::read_to_string(path).unwrap_or_default();
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(ShellName::new)
+            .collect()
+    }
 }
 
 #[derive(Clone)]
 pub struct FsConfig {
     root_path: PathBuf,
-    current_shell: Option<String>,
+    current_shell: Option<ShellName>,
 }
 
-fn read_shell_from_path(path: &PathBuf) -> io::Result<String> {
+fn read_shell_from_path(path: &Path) -> io::Result<String> {
     let mut file = File::open(path)?;
     let mut current_shell = String::new();
 
@@ -38,7 +94,7 @@ fn read_shell_from_path(path: &PathBuf) -> io::Result<String> {
     Ok(current_shell)
 }
 
-fn config_path(root_path: &PathBuf) -> PathBuf {
+fn config_path(root_path: &Path) -> PathBuf {
     root_path.join("current_shell")
 }
 
@@ -47,17 +103,17 @@ impl FsConfig {
         let root_path = PathBuf::from(root_path.as_ref());
         fs::create_dir_all(&root_path)?; // TODO: what do I do with this error?
         let config_path = config_path(&root_path);
-        let current_shell = read_shell_from_path(&config_path).ok();
+        // A current shell that isn't a valid name is ignored, so it
+        // can't point anywhere outside of the shell root.
+        let current_shell = read_shell_from_path(&config_path)
+            .ok()
+            .and_then(|name| ShellName::new(name).ok());
 
         Ok(FsConfig {
             root_path,
             current_shell,
         })
     }
-
-    fn config_path(&self) -> PathBuf {
-        config_path(&self.root_path())
-    }
 }
 
 impl Config for FsConfig {
@@ -67,27 +123,44 @@ impl Config for FsConfig {
         &self.root_path
     }
 
-    fn current_shell_name(&self) -> Option<&str> {
-        self.current_shell.as_ref().map(Borrow::borrow)
+    fn current_shell_name(&self) -> Option<&ShellName> {
+        self.current_shell.as_ref()
     }
 
-    fn set_current_shell_name(&mut self, name: &str) -> io::Result<()> {
-        let mut file = File::create(&self.config_path())?;
+    fn set_current_shell_name(&mut self, name: &ShellName) -> io::Result<()> {
+        let mut file = File::create(self.current_shell_file())?;
 
-        file.write_all(name.as_bytes())?;
+        file.write_all(name.as_str().as_bytes())?;
 
-        self.current_shell = Some(name.to_string());
+        self.current_shell = Some(name.clone());
 
         Ok(())
     }
 
-    fn shell_exists(&self, name: &str) -> bool {
+    fn shell_exists(&self, name: &ShellName) -> bool {
         let shell_path = self.shell_root_path().join(name);
         shell_path.is_dir()
     }
 
-    fn shell_files(&self, _name: &str) -> Self::IntoIterator {
-        Files::new(self.current_shell_path())
+    fn shell_names(&self) -> Vec<ShellName> {
+        let entries = match fs::read_dir(self.shell_root_path()) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        let mut names = entries
+            .filter_map(StdResult::ok)
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .filter_map(|name| ShellName::new(name).ok())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn shell_files(&self, name: &ShellName) -> Result<Self::IntoIterator> {
+        let shell_path = self.shell_root_path().join(name);
+        let manifest = Manifest::load(&shell_path)?;
+        Ok(Files::new(Some(shell_path)).with_manifest(manifest))
     }
 }
 
@@ -95,22 +168,30 @@ impl Config for FsConfig {
 ///
 /// This type can only be constructed by the `Files` wrapper, and it
 /// handles cleaning up the iterator of `DirEntry`s into an iterator
-/// of `PathBuf` to the files in that stream, and stripping them of
+/// of `PathBuf` to the files in that stream, skipping anything the
+/// shell's ignore rules or manifest leave out, and stripping them of
 /// the walk root path prefix.
-pub struct FilesIter<T>(Option<(T, PathBuf)>);
+pub struct FilesIter(
+    Option<(walkdir::IntoIter, PathBuf, IgnoreRules)>,
+    Option<Manifest>,
+);
 
-impl<T> Iterator for FilesIter<T>
-where
-    T: Iterator<Item = StdResult<walkdir::DirEntry, walkdir::Error>>,
-{
+impl Iterator for FilesIter {
     type Item = PathBuf;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if let Some((ref mut iter, ref prefix_path)) = self.0 {
+        let manifest = &self.1;
+        if let Some((ref mut iter, ref prefix_path, ref rules)) = self.0 {
             loop {
                 match iter.next() {
                     Some(Ok(entry)) => {
                         let file_type = entry.file_type();
+                        if rules.is_ignored(entry.path(), file_type.is_dir()) {
+                            if file_type.is_dir() {
+                                iter.skip_current_dir();
+                            }
+                            continue;
+                        }
                         if file_type.is_dir() {
                             continue;
                         }
@@ -120,7 +201,12 @@ where
                             .strip_prefix(prefix_path)
                             .unwrap() // this unwrap is safe because
                             .to_path_buf(); // of the Files::new constructor
-                        return Some(shell_relative_path);
+                        let included = manifest
+                            .as_ref()
+                            .is_none_or(|manifest| manifest.includes(&shell_relative_path));
+                        if included {
+                            return Some(shell_relative_path);
+                        }
                     }
                     Some(Err(_)) => continue,
                     None => return None,
@@ -140,7 +226,7 @@ where
 /// `PathBuf::strip_prefix`, and since the `WalkDir` was created with
 /// the same path as `FilesIter` will use to strip the prefix, it is
 /// always safe to just unwrap the result returned by `strip_prefix`.
-pub struct Files(Option<(WalkDir, PathBuf)>);
+pub struct Files(Option<(WalkDir, PathBuf)>, Option<Manifest>);
 
 impl Files {
     /// Constructs a new `Files` from a directory path.
@@ -151,18 +237,26 @@ impl Files {
                 PathBuf::from(path.as_ref()),
             )
         });
-        Files(walker)
+        Files(walker, None)
+    }
+
+    /// Only yields the files that `manifest` includes, if there is one.
+    pub fn with_manifest(self, manifest: Option<Manifest>) -> Files {
+        Files(self.0, manifest)
     }
 }
 
 impl IntoIterator for Files {
     type Item = PathBuf;
-    type IntoIter = FilesIter<walkdir::IntoIter>;
+    type IntoIter = FilesIter;
 
     fn into_iter(self) -> Self::IntoIter {
-        let Files(opt) = self;
-        let iter_opt = opt.map(|(walker, path)| (walker.into_iter(), path));
-        FilesIter(iter_opt)
+        let Files(opt, manifest) = self;
+        let iter_opt = opt.map(|(walker, path)| {
+            let rules = IgnoreRules::load(&path);
+            (walker.into_iter(), path, rules)
+        });
+        FilesIter(iter_opt, manifest)
     }
 }
 
@@ -170,34 +264,45 @@ impl IntoIterator for Files {
 pub mod mock {
     use super::Config;
 
-    use std::borrow::Borrow;
+    use std::collections::BTreeMap;
     use std::io;
     use std::path::{Path, PathBuf};
 
+    use crate::{hermit::Result, shell_name::ShellName, test_helpers::shell_name};
+
     #[derive(Clone, Debug, Eq, PartialEq)]
     pub struct MockConfig {
         root_path: PathBuf,
-        current_shell: String,
-        allowed_shell_names: Vec<String>,
+        current_shell: ShellName,
+        allowed_shell_names: Vec<ShellName>,
         files: Vec<PathBuf>,
+        shell_files: BTreeMap<ShellName, Vec<PathBuf>>,
+    }
+
+    impl Default for MockConfig {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
     impl MockConfig {
         pub fn new() -> MockConfig {
             MockConfig {
                 root_path: PathBuf::from("/"),
-                allowed_shell_names: vec!["default".to_owned()],
-                current_shell: "default".to_owned(),
+                allowed_shell_names: vec![shell_name("default")],
+                current_shell: shell_name("default"),
                 files: vec![],
+                shell_files: BTreeMap::new(),
             }
         }
 
         pub fn with_root(root: impl AsRef<Path>) -> MockConfig {
             MockConfig {
                 root_path: PathBuf::from(root.as_ref()),
-                allowed_shell_names: vec!["default".to_owned()],
-                current_shell: "default".to_owned(),
+                allowed_shell_names: vec![shell_name("default")],
+                current_shell: shell_name("default"),
                 files: vec![],
+                shell_files: BTreeMap::new(),
             }
         }
 
@@ -207,6 +312,18 @@ pub mod mock {
                 .map(|p| PathBuf::from(p.as_ref()))
                 .collect();
         }
+
+        /// Adds another shell called `name`, with its own `paths`.
+        /// Shells without their own paths all have the ones given to
+        /// `set_paths`.
+        pub fn add_shell(&mut self, name: &str, paths: Vec<impl AsRef<Path>>) {
+            self.allowed_shell_names.push(shell_name(name));
+            let paths = paths
+                .into_iter()
+                .map(|p| PathBuf::from(p.as_ref()))
+                .collect();
+            self.shell_files.insert(shell_name(name), paths);
+        }
     }
 
     impl Config for MockConfig {
@@ -216,21 +333,27 @@ pub mod mock {
             &self.root_path
         }
 
-        fn current_shell_name(&self) -> Option<&str> {
-            Some(&self.current_shell).map(|shell_name| shell_name.borrow())
+        fn current_shell_name(&self) -> Option<&ShellName> {
+            Some(&self.current_shell)
         }
 
-        fn set_current_shell_name(&mut self, name: &str) -> io::Result<()> {
-            self.current_shell = name.to_owned();
+        fn set_current_shell_name(&mut self, name: &ShellName) -> io::Result<()> {
+            self.current_shell = name.clone();
             Ok(())
         }
 
-        fn shell_exists(&self, name: &str) -> bool {
-            self.allowed_shell_names.contains(&name.to_owned())
+        fn shell_exists(&self, name: &ShellName) -> bool {
+            self.allowed_shell_names.contains(name)
+        }
+
+        fn shell_names(&self) -> Vec<ShellName> {
+            let mut names = self.allowed_shell_names.clone();
+            names.sort();
+            names
         }
 
-        fn shell_files(&self, _name: &str) -> Self::IntoIterator {
-            self.files.clone()
+        fn shell_files(&self, name: &ShellName) -> Result<Self::IntoIterator> {
+            Ok(self.shell_files.get(name).unwrap_or(&self.files).clone())
         }
     }
 }
@@ -245,6 +368,8 @@ mod test {
 
     use tempfile::{tempdir, TempDir};
 
+    use crate::test_helpers::shell_name;
+
     fn set_up(current: &str, shells: Vec<&str>) -> TempDir {
         let test_root_dir = tempdir().expect("failed to create tempdir");
         let test_root = test_root_dir.path();
@@ -267,7 +392,7 @@ mod test {
     fn has_a_root_path() {
         let test_root_dir = set_up("default", vec!["default"]);
         let test_root = test_root_dir.path();
-        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let config = FsConfig::new(test_root).expect("failed to create FSConfig");
         assert_eq!(config.root_path(), &test_root);
     }
 
@@ -287,22 +412,33 @@ mod test {
         let test_root = set_up("current", vec!["current"]);
         let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
 
-        assert_eq!(*config.current_shell_name().unwrap(), "current".to_string());
+        assert_eq!(*config.current_shell_name().unwrap(), "current");
+    }
+
+    #[test]
+    fn ignores_a_current_shell_that_is_not_a_valid_name() {
+        let test_root = set_up("../../.ssh", vec![]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+
+        assert_eq!(config.current_shell_name(), None);
+        assert_eq!(config.current_shell_path(), None);
     }
 
     #[test]
     fn can_set_the_current_shell_name() {
         let test_root_dir = set_up("default", vec!["default"]);
         let test_root = test_root_dir.path();
-        let mut config = FsConfig::new(&test_root).expect("failed to create FSConfig");
-        config.set_current_shell_name("current").unwrap();
+        let mut config = FsConfig::new(test_root).expect("failed to create FSConfig");
+        config
+            .set_current_shell_name(&shell_name("current"))
+            .unwrap();
 
-        let mut config_file = File::open(&test_root.join("current_shell")).unwrap();
+        let mut config_file = File::open(test_root.join("current_shell")).unwrap();
         let mut name_on_disk = String::new();
         config_file.read_to_string(&mut name_on_disk).unwrap();
 
         let current = "current".to_string();
-        assert_eq!(*config.current_shell_name().unwrap(), current);
+        assert_eq!(*config.current_shell_name().unwrap(), "current");
         assert_eq!(name_on_disk, current);
     }
 
@@ -311,7 +447,7 @@ mod test {
         let test_root = set_up("default", vec!["default", "other"]);
         let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
 
-        assert!(config.shell_exists("other"));
+        assert!(config.shell_exists(&shell_name("other")));
     }
 
     #[test]
@@ -319,7 +455,19 @@ mod test {
         let test_root = set_up("default", vec!["default", "other"]);
         let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
 
-        assert!(!config.shell_exists("another"));
+        assert!(!config.shell_exists(&shell_name("another")));
+    }
+
+    #[test]
+    fn lists_every_shell_by_name() {
+        let test_root = set_up("default", vec!["work", "default", ".hidden"]);
+        File::create(test_root.path().join("shells/stray-file")).unwrap();
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+
+        assert_eq!(
+            config.shell_names(),
+            vec![shell_name("default"), shell_name("work")]
+        );
     }
 
     #[test]
@@ -327,10 +475,11 @@ mod test {
         let test_root = set_up("default", vec!["default"]);
         let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
         let shell_root = config.shell_root_path().join("default");
-        File::create(&shell_root.join("file1")).expect("Failed to create test file");
+        File::create(shell_root.join("file1")).expect("Failed to create test file");
 
         let files = config
-            .shell_files("default")
+            .shell_files(&shell_name("default"))
+            .unwrap()
             .into_iter()
             .map(|f| f.to_string_lossy().to_string())
             .collect::<Vec<_>>();
@@ -345,7 +494,7 @@ mod test {
         for path in paths {
             let full_path = root_path.join(path.as_ref());
             let dir_path = full_path.parent().expect("Path had no parent");
-            fs::create_dir_all(&dir_path).expect("Failed to create dir path");
+            fs::create_dir_all(dir_path).expect("Failed to create dir path");
             File::create(&full_path).expect("Could not create file");
         }
     }
@@ -358,7 +507,8 @@ mod test {
         create_paths(shell_root, vec!["file1", "subdir/file2"]);
 
         let files = config
-            .shell_files("default")
+            .shell_files(&shell_name("default"))
+            .unwrap()
             .into_iter()
             .map(|f| f.to_string_lossy().to_string())
             .collect::<Vec<_>>();
@@ -366,4 +516,67 @@ mod test {
         assert!(files.contains(&"subdir/file2".into()));
         assert!(!files.contains(&"subdir".into()));
     }
+
+    #[test]
+    fn walking_a_directory_skips_git_and_ignored_files() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec![
+                ".git/HEAD",
+                "README.md",
+                ".bashrc",
+                ".config/.hignore",
+                ".config/app.log",
+                ".config/app.conf",
+            ],
+        );
+        fs::write(shell_root.join(".config/.hignore"), "*.log").unwrap();
+
+        let files = config
+            .shell_files(&shell_name("default"))
+            .unwrap()
+            .into_iter()
+            .map(|f| f.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert!(files.contains(&".bashrc".into()));
+        assert!(files.contains(&".config/app.conf".into()));
+        assert_eq!(files.len(), 2);
+    }
+
+    #[test]
+    fn walking_a_directory_only_finds_what_its_manifest_includes() {
+        let test_root = set_up("default", vec!["default"]);
+        let config = FsConfig::new(&test_root).expect("failed to create FSConfig");
+        let shell_root = config.shell_root_path().join("default");
+        create_paths(
+            &shell_root,
+            vec![
+                ".bashrc",
+                ".profile",
+                "notes.md",
+                ".config/nvim/init.lua",
+                ".config/nvim/.init.lua.swp",
+            ],
+        );
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "include = [\".bashrc\", \".config/nvim\"]\nexclude = [\"**/*.swp\"]\n",
+        )
+        .unwrap();
+
+        let mut files = config
+            .shell_files(&shell_name("default"))
+            .unwrap()
+            .into_iter()
+            .map(|f| f.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, vec![".bashrc", ".config/nvim/init.lua"]);
+
+        fs::write(shell_root.join("hermit.toml"), "include = \".bashrc\"\n").unwrap();
+        assert!(config.shell_files(&shell_name("default")).is_err());
+    }
 }
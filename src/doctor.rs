@@ -0,0 +1,676 @@
+use std::collections::BTreeSet;
+
+use crate::{
+    common::*,
+    file_operations::{normalize_path, Op, DEFAULT_FILE_MODE},
+    shell::{self, LinkState},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Status::Pass => "ok",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        };
+        f.pad(label)
+    }
+}
+
+/// A single observation made by a `Check`, and why it matters.
+///
+/// Findings that hermit knows how to repair carry the operations
+/// that would do so in `fix`, which is empty otherwise.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub status: Status,
+    pub explanation: String,
+    pub fix: Vec<Op>,
+}
+
+impl Finding {
+    pub fn pass(explanation: impl Into<String>) -> Finding {
+        Finding::new(Status::Pass, explanation)
+    }
+
+    pub fn warn(explanation: impl Into<String>) -> Finding {
+        Finding::new(Status::Warn, explanation)
+    }
+
+    pub fn fail(explanation: impl Into<String>) -> Finding {
+        Finding::new(Status::Fail, explanation)
+    }
+
+    pub fn with_fix(mut self, fix: Vec<Op>) -> Finding {
+        self.fix = fix;
+        self
+    }
+
+    fn new(status: Status, explanation: impl Into<String>) -> Finding {
+        Finding {
+            status,
+            explanation: explanation.into(),
+            fix: vec![],
+        }
+    }
+}
+
+/// Everything a `Check` is allowed to look at.
+pub struct Context<'a, C: Config> {
+    pub config: &'a C,
+    pub home: &'a Path,
+}
+
+pub trait Check<C: Config> {
+    fn name(&self) -> &'static str;
+
+    fn run(&self, context: &Context<'_, C>) -> Vec<Finding>;
+}
+
+/// The findings of one check, labelled with the check that made them.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Diagnosis {
+    pub check: &'static str,
+    pub finding: Finding,
+}
+
+/// All of the checks that `hermit doctor` runs, in order.
+pub fn checks<C: Config>() -> Vec<Box<dyn Check<C>>> {
+    vec![
+        Box::new(HermitRoot),
+        Box::new(CurrentShell),
+        Box::new(ShellRepositories),
+        Box::new(DanglingLinks),
+        Box::new(UnlinkedFiles),
+    ]
+}
+
+pub fn diagnose<C: Config>(
+    checks: &[Box<dyn Check<C>>],
+    context: &Context<'_, C>,
+) -> Vec<Diagnosis> {
+    checks
+        .iter()
+        .flat_map(|check| {
+            check
+                .run(context)
+                .into_iter()
+                .map(move |finding| Diagnosis {
+                    check: check.name(),
+                    finding,
+                })
+        })
+        .collect()
+}
+
+pub struct HermitRoot;
+
+impl<C: Config> Check<C> for HermitRoot {
+    fn name(&self) -> &'static str {
+        "hermit root"
+    }
+
+    fn run(&self, context: &Context<'_, C>) -> Vec<Finding> {
+        let root = context.config.root_path();
+        let finding = if root.is_relative() {
+            Finding::warn(format!(
+                "{} is a relative path, so hermit will behave differently \
+                 depending on where you run it from",
+                root.display()
+            ))
+        } else {
+            Finding::pass(format!("Using {}", root.display()))
+        };
+        vec![finding]
+    }
+}
+
+pub struct CurrentShell;
+
+impl<C: Config> Check<C> for CurrentShell {
+    fn name(&self) -> &'static str {
+        "current shell"
+    }
+
+    fn run(&self, context: &Context<'_, C>) -> Vec<Finding> {
+        let config = context.config;
+        let finding = match config.current_shell_name() {
+            Some(name) if !config.shell_exists(name) => Finding::fail(format!(
+                "The current shell is {}, but there is no shell by that name",
+                name
+            ))
+            .with_fix(vec![Op::Remove(config.current_shell_file())]),
+            Some(name) => Finding::pass(format!("Inhabiting {}", name)),
+            None => match fs::read_to_string(config.current_shell_file()) {
+                Ok(contents) => Finding::fail(format!(
+                    "The current shell is {:?}, which isn't a valid shell name",
+                    contents
+                ))
+                .with_fix(current_shell_fix(config, &contents)),
+                Err(_) => Finding::warn("No shell is active right now"),
+            },
+        };
+        vec![finding]
+    }
+}
+
+/// Rewrites a current shell name that only needs tidying up (like a
+/// stray newline from a hand edit), and otherwise forgets it.
+fn current_shell_fix<C: Config>(config: &C, contents: &str) -> Vec<Op> {
+    match ShellName::new(contents.trim()) {
+        Ok(name) if config.shell_exists(&name) => vec![Op::WriteFile {
+            path: config.current_shell_file(),
+            contents: name.to_string(),
+            mode: DEFAULT_FILE_MODE,
+        }],
+        _ => vec![Op::Remove(config.current_shell_file())],
+    }
+}
+
+pub struct ShellRepositories;
+
+impl<C: Config> Check<C> for ShellRepositories {
+    fn name(&self) -> &'static str {
+        "shells"
+    }
+
+    fn run(&self, context: &Context<'_, C>) -> Vec<Finding> {
+        let shell_root = context.config.shell_root_path();
+        let entries = match fs::read_dir(&shell_root) {
+            Ok(entries) => entries,
+            Err(_) => return vec![Finding::warn("You don't have any shells yet")],
+        };
+
+        let mut findings = vec![];
+        for entry in entries.filter_map(StdResult::ok) {
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !path.is_dir() {
+                findings.push(Finding::warn(format!(
+                    "{} is in your shells directory but is not a shell",
+                    name
+                )));
+            } else if !path.join(".git").is_dir() {
+                findings.push(Finding::warn(format!(
+                    "The shell {} is not a git repository",
+                    name
+                )));
+            }
+        }
+
+        if findings.is_empty() {
+            findings.push(Finding::pass("All shells are git repositories"));
+        }
+        findings
+    }
+}
+
+pub struct DanglingLinks;
+
+impl<C: Config> Check<C> for DanglingLinks {
+    fn name(&self) -> &'static str {
+        "dangling links"
+    }
+
+    fn run(&self, context: &Context<'_, C>) -> Vec<Finding> {
+        let hermit_root = context.config.root_path();
+        let mut findings = vec![];
+        for path in known_paths(context) {
+            let target = match fs::read_link(&path) {
+                Ok(target) => target,
+                Err(_) => continue,
+            };
+            // Relative targets are relative to the directory the link
+            // is in, rather than to wherever hermit was run from.
+            let resolved = match path.parent() {
+                Some(parent) => normalize_path(&parent.join(&target)),
+                None => target.clone(),
+            };
+            if resolved.starts_with(hermit_root) && !resolved.exists() {
+                findings.push(
+                    Finding::fail(format!(
+                        "{} links to {}, which no longer exists",
+                        path.display(),
+                        target.display()
+                    ))
+                    .with_fix(vec![Op::Remove(path)]),
+                );
+            }
+        }
+
+        if findings.is_empty() {
+            findings.push(Finding::pass("No broken links into your hermit root"));
+        }
+        findings
+    }
+}
+
+/// The paths in the home directory that hermit knows it may have put
+/// something at: everything in its state, and the current shell's
+/// files.
+fn known_paths<C: Config>(context: &Context<'_, C>) -> BTreeSet<PathBuf> {
+    let config = context.config;
+    let mut paths = State::load(&config.state_path())
+        .map(|state| state.records.into_iter().map(|record| record.path))
+        .into_iter()
+        .flatten()
+        .collect::<BTreeSet<_>>();
+    if let Some(name) = config.current_shell_name() {
+        if let Ok(folding) = shell::fold(config, name, context.home, |_| false) {
+            paths.extend(
+                folding
+                    .files
+                    .iter()
+                    .map(|file| context.home.join(&file.path)),
+            );
+        }
+    }
+    paths
+}
+
+pub struct UnlinkedFiles;
+
+impl<C: Config> Check<C> for UnlinkedFiles {
+    fn name(&self) -> &'static str {
+        "links"
+    }
+
+    fn run(&self, context: &Context<'_, C>) -> Vec<Finding> {
+        let name = match context.config.current_shell_name() {
+            Some(name) if context.config.shell_exists(name) => name,
+            _ => return vec![],
+        };
+
+        let inputs = match Inputs::load(context.config) {
+            Ok(inputs) => inputs,
+            Err(err) => return vec![Finding::fail(err.to_string())],
+        };
+        let folding = match shell::fold(context.config, name, context.home, |_| false) {
+            Ok(folding) => folding,
+            Err(err) => return vec![Finding::fail(err.to_string())],
+        };
+        let recorded = State::load(&context.config.state_path()).unwrap_or_default();
+        let mut findings = vec![];
+        for (dir, owner) in &folding.unfold {
+            let home_path = context.home.join(dir);
+            findings.push(
+                Finding::warn(format!(
+                    "{} links to {}, but other files have to share it now",
+                    home_path.display(),
+                    owner.join(dir).display()
+                ))
+                .with_fix(vec![Op::RemoveLink {
+                    path: home_path,
+                    owner: owner.clone(),
+                }]),
+            );
+        }
+        for file in &folding.files {
+            let home_path = context.home.join(&file.path);
+            let layer_root = context.config.shell_root_path().join(&file.layer);
+            let shell_file = layer_root.join(&file.source);
+            let placement = match Placement::of(&layer_root, &file.source, file.mode, &inputs) {
+                Ok(placement) => placement,
+                Err(Error::SecretsLocked) => {
+                    findings.push(Finding::warn(format!(
+                        "{} can't be checked while secrets are locked",
+                        home_path.display()
+                    )));
+                    continue;
+                }
+                Err(err) => {
+                    findings.push(Finding::fail(err.to_string()));
+                    continue;
+                }
+            };
+            let should = match placement {
+                Placement::Link(_) => format!("link to {}", shell_file.display()),
+                Placement::RelativeLink(_) => {
+                    format!("link relatively to {}", shell_file.display())
+                }
+                Placement::Copy(_) => format!("be a copy of {}", shell_file.display()),
+                Placement::HardLink(_) => format!("be a hard link to {}", shell_file.display()),
+                Placement::Generate(_) => format!("be generated from {}", shell_file.display()),
+                Placement::Render(..) => format!("be rendered from {}", shell_file.display()),
+            };
+            let place = placement.op(&home_path);
+            let state = if folding.is_unfolding(&file.path) {
+                LinkState::Missing
+            } else {
+                placement.state(&home_path, recorded.get(&home_path))
+            };
+            match state {
+                LinkState::Linked => (),
+                LinkState::Missing => {
+                    let parent = home_path.parent().unwrap_or(context.home).to_path_buf();
+                    findings.push(
+                        Finding::warn(format!(
+                            "{} is missing, it should {}",
+                            home_path.display(),
+                            should
+                        ))
+                        .with_fix(vec![Op::MkDir(parent), place]),
+                    )
+                }
+                LinkState::Blocked => findings.push(Finding::fail(format!(
+                    "{} is a real {}, but it should {}",
+                    home_path.display(),
+                    if file.folded { "directory" } else { "file" },
+                    should
+                ))),
+                LinkState::Changed => findings.push(Finding::warn(format!(
+                    "{} has changes that aren't in {} yet",
+                    home_path.display(),
+                    shell_file.display()
+                ))),
+                LinkState::Drifted => findings.push(Finding::warn(format!(
+                    "{} is out of sync with {}, it should {}",
+                    home_path.display(),
+                    shell_file.display(),
+                    should
+                ))),
+                LinkState::Stale => findings.push(
+                    Finding::warn(format!(
+                        "{} is out of date, it should {}",
+                        home_path.display(),
+                        should
+                    ))
+                    .with_fix(vec![Op::Remove(home_path.clone()), place]),
+                ),
+                LinkState::LinkedElsewhere(_) => findings.push(
+                    Finding::warn(format!(
+                        "{} links somewhere else, but it should {}",
+                        home_path.display(),
+                        should
+                    ))
+                    .with_fix(vec![Op::Remove(home_path.clone()), place]),
+                ),
+            }
+        }
+
+        if findings.is_empty() {
+            findings.push(Finding::pass(format!("All files in {} are linked", name)));
+        }
+        findings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{fs, os::unix};
+
+    use crate::{
+        config::{mock::MockConfig, FsConfig},
+        state::Record,
+        test_helpers::{filesystem::set_up, shell_name},
+    };
+
+    fn run_check<C: Config>(check: impl Check<C>, config: &C, home: &Path) -> Vec<Finding> {
+        let context = Context { config, home };
+        check.run(&context)
+    }
+
+    fn statuses(findings: &[Finding]) -> Vec<Status> {
+        findings.iter().map(|finding| finding.status).collect()
+    }
+
+    #[test]
+    fn warns_when_hermit_root_is_relative() {
+        let config = MockConfig::with_root("relative/hermit/root");
+        let findings = run_check(HermitRoot, &config, Path::new("/"));
+        assert_eq!(statuses(&findings), vec![Status::Warn]);
+    }
+
+    #[test]
+    fn fails_when_current_shell_does_not_exist() {
+        let mut config = MockConfig::new();
+        config.set_current_shell_name(&shell_name("gone")).unwrap();
+
+        let findings = run_check(CurrentShell, &config, Path::new("/"));
+
+        assert_eq!(statuses(&findings), vec![Status::Fail]);
+        assert!(findings[0].explanation.contains("gone"));
+        assert_eq!(
+            findings[0].fix,
+            vec![Op::Remove(PathBuf::from("/current_shell"))]
+        );
+    }
+
+    #[test]
+    fn fixes_a_current_shell_with_stray_whitespace() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::create_dir_all(test_root.join("shells/default")).unwrap();
+        fs::write(test_root.join("current_shell"), "default\n").unwrap();
+        let config = FsConfig::new(test_root).unwrap();
+
+        let findings = run_check(CurrentShell, &config, test_root);
+
+        assert_eq!(statuses(&findings), vec![Status::Fail]);
+        assert_eq!(
+            findings[0].fix,
+            vec![Op::WriteFile {
+                path: test_root.join("current_shell"),
+                contents: "default".to_owned(),
+                mode: DEFAULT_FILE_MODE,
+            }]
+        );
+    }
+
+    #[test]
+    fn forgets_a_current_shell_that_is_not_a_valid_name() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("current_shell"), "../../.ssh").unwrap();
+        let config = FsConfig::new(test_root).unwrap();
+
+        let findings = run_check(CurrentShell, &config, test_root);
+
+        assert_eq!(statuses(&findings), vec![Status::Fail]);
+        assert_eq!(
+            findings[0].fix,
+            vec![Op::Remove(test_root.join("current_shell"))]
+        );
+    }
+
+    #[test]
+    fn warns_about_shells_that_are_not_git_repositories() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let config = MockConfig::with_root(test_root);
+        fs::create_dir_all(test_root.join("shells/plain")).unwrap();
+        fs::create_dir_all(test_root.join("shells/tracked/.git")).unwrap();
+
+        let findings = run_check(ShellRepositories, &config, test_root);
+
+        assert_eq!(statuses(&findings), vec![Status::Warn]);
+        assert!(findings[0].explanation.contains("plain"));
+    }
+
+    #[test]
+    fn fails_on_dangling_links_into_the_hermit_root() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let config = MockConfig::with_root(home.join(".hermit"));
+        fs::create_dir_all(home.join(".hermit/shells/default")).unwrap();
+        fs::write(home.join(".hermit/shells/default/.vimrc"), "").unwrap();
+        unix::fs::symlink(
+            home.join(".hermit/shells/default/.vimrc"),
+            home.join(".vimrc"),
+        )
+        .unwrap();
+        unix::fs::symlink(
+            home.join(".hermit/shells/default/.bashrc"),
+            home.join(".bashrc"),
+        )
+        .unwrap();
+        unix::fs::symlink(home.join("elsewhere"), home.join(".profile")).unwrap();
+        unix::fs::symlink(
+            home.join(".hermit/shells/default/.unknown"),
+            home.join(".unknown"),
+        )
+        .unwrap();
+        fs::create_dir_all(home.join(".config")).unwrap();
+        unix::fs::symlink(
+            "../.hermit/shells/default/.vimrc",
+            home.join(".config/vimrc"),
+        )
+        .unwrap();
+        unix::fs::symlink(
+            "../.hermit/shells/default/.inputrc",
+            home.join(".config/inputrc"),
+        )
+        .unwrap();
+        let mut state = State::default();
+        for path in &[
+            ".vimrc",
+            ".bashrc",
+            ".profile",
+            ".config/vimrc",
+            ".config/inputrc",
+        ] {
+            state.record(Record::new(
+                &home.join(path),
+                &home.join(".hermit/shells/default").join(path),
+                &Placement::Link(PathBuf::new()),
+            ));
+        }
+        state.save(&home.join(".hermit/state.toml")).unwrap();
+
+        let findings = run_check(DanglingLinks, &config, home);
+
+        assert_eq!(statuses(&findings), vec![Status::Fail, Status::Fail]);
+        assert!(findings[0].explanation.contains(".bashrc"));
+        assert_eq!(findings[0].fix, vec![Op::Remove(home.join(".bashrc"))]);
+        assert!(findings[1].explanation.contains(".inputrc"));
+        assert_eq!(
+            findings[1].fix,
+            vec![Op::Remove(home.join(".config/inputrc"))]
+        );
+    }
+
+    #[test]
+    fn reports_shell_files_that_are_not_linked() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".bashrc", ".vimrc", ".profile"]);
+        let shell_root = home.join(".hermit/shells/default");
+        unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        fs::write(home.join(".vimrc"), "").unwrap();
+
+        let findings = run_check(UnlinkedFiles, &config, home);
+
+        assert_eq!(statuses(&findings), vec![Status::Fail, Status::Warn]);
+        assert!(findings[0].explanation.contains(".vimrc"));
+        assert!(findings[0].fix.is_empty());
+        assert!(findings[1].explanation.contains(".profile"));
+        assert_eq!(
+            findings[1].fix,
+            vec![
+                Op::MkDir(home.to_path_buf()),
+                Op::Link {
+                    path: home.join(".profile"),
+                    target: shell_root.join(".profile"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unfolds_directories_that_have_to_be_shared() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".vim/vimrc"]);
+        config.add_shell("team-base", vec![".vim/team.vim"]);
+        let shells = home.join(".hermit/shells");
+        fs::create_dir_all(shells.join("default/.vim")).unwrap();
+        fs::write(shells.join("default/.hbases"), "team-base\n").unwrap();
+        unix::fs::symlink(shells.join("default/.vim"), home.join(".vim")).unwrap();
+
+        let findings = run_check(UnlinkedFiles, &config, home);
+
+        assert_eq!(
+            statuses(&findings),
+            vec![Status::Warn, Status::Warn, Status::Warn]
+        );
+        assert_eq!(
+            findings[0].fix,
+            vec![Op::RemoveLink {
+                path: home.join(".vim"),
+                owner: shells.join("default"),
+            }]
+        );
+        assert_eq!(
+            findings[1].fix,
+            vec![
+                Op::MkDir(home.join(".vim")),
+                Op::Link {
+                    path: home.join(".vim/team.vim"),
+                    target: shells.join("team-base/.vim/team.vim"),
+                },
+            ]
+        );
+        assert!(findings[2].explanation.contains("vimrc is missing"));
+    }
+
+    #[test]
+    fn only_rewrites_renders_that_were_not_edited_by_hand() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".gitconfig.tmpl", ".hgrc.tmpl"]);
+        let shell_root = home.join(".hermit/shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(shell_root.join(".gitconfig.tmpl"), "{{ machine.email }}\n").unwrap();
+        fs::write(shell_root.join(".hgrc.tmpl"), "{{ machine.email }}\n").unwrap();
+        fs::write(home.join(".hermit/machine.toml"), "email = \"new\"\n").unwrap();
+        let old = Placement::Render("old\n".to_owned(), 0o644);
+        let state = State {
+            records: vec![
+                Record::new(
+                    &home.join(".gitconfig"),
+                    &shell_root.join(".gitconfig.tmpl"),
+                    &old,
+                ),
+                Record::new(&home.join(".hgrc"), &shell_root.join(".hgrc.tmpl"), &old),
+            ],
+        };
+        state.save(&config.state_path()).unwrap();
+        fs::write(home.join(".gitconfig"), "old\n").unwrap();
+        fs::write(home.join(".hgrc"), "edited\n").unwrap();
+
+        let findings = run_check(UnlinkedFiles, &config, home);
+
+        assert_eq!(statuses(&findings), vec![Status::Warn, Status::Warn]);
+        assert!(findings[0].explanation.contains("out of date"));
+        assert_eq!(findings[0].fix[0], Op::Remove(home.join(".gitconfig")));
+        assert!(findings[1].explanation.contains("has changes"));
+        assert!(findings[1].fix.is_empty());
+    }
+
+    #[test]
+    fn diagnoses_with_every_check() {
+        let config = MockConfig::new();
+        let context = Context {
+            config: &config,
+            home: Path::new("/nonexistent-home"),
+        };
+
+        let diagnoses = diagnose(&checks(), &context);
+
+        assert_eq!(diagnoses[0].check, "hermit root");
+        assert_eq!(diagnoses[0].finding.status, Status::Pass);
+        assert!(diagnoses.iter().any(|d| d.check == "current shell"));
+    }
+}
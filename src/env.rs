@@ -2,6 +2,8 @@ use crate::common::*;
 
 use std::env;
 
+pub use std::env::{current_dir, temp_dir, vars};
+
 pub use dirs::home_dir;
 
 pub fn get_program_name() -> String {
@@ -13,6 +15,11 @@ pub fn get_program_name() -> String {
         .unwrap_or_else(|| "hermit".to_owned())
 }
 
+/// The arguments hermit was run with, like `inhabit work`.
+pub fn command_line() -> String {
+    env::args().skip(1).collect::<Vec<_>>().join(" ")
+}
+
 pub fn get_hermit_dir() -> Option<PathBuf> {
     env::var("HERMIT_ROOT")
         .map(PathBuf::from)
@@ -24,6 +31,13 @@ pub fn default_hermit_dir() -> Option<PathBuf> {
     dirs::config_dir().map(|home| home.join("hermit"))
 }
 
+/// The command to edit files with, following the usual conventions.
+pub fn editor() -> String {
+    env::var("VISUAL")
+        .or_else(|_| env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_owned())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
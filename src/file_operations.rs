@@ -1,11 +1,213 @@
 use crate::common::*;
 
-#[derive(Debug, PartialEq, Eq)]
+use std::{
+    ffi::OsStr,
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+};
+
+/// The mode files are written with, before the umask is applied.
+pub const DEFAULT_FILE_MODE: u32 = 0o666;
+
+/// The mode of written files that can hold secrets, which only their
+/// owner can read.
+pub const PRIVATE_FILE_MODE: u32 = 0o600;
+
+/// The mode of directories kept in the history, which only their
+/// owner can look inside.
+const PRIVATE_DIR_MODE: u32 = 0o700;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    git,
+    history::{Batch, History},
+    state::{self, Change, Record},
+};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
 pub enum Op {
     MkDir(PathBuf),
     GitInit(PathBuf),
-    Link { path: PathBuf, target: PathBuf },
+    GitClone {
+        url: String,
+        path: PathBuf,
+    },
+    Link {
+        path: PathBuf,
+        target: PathBuf,
+    },
+    /// Like `Link`, but the link's target is written relative to the
+    /// directory the link is in.
+    RelativeLink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+    HardLink {
+        path: PathBuf,
+        target: PathBuf,
+    },
+    /// Copies a file, refusing to replace anything already at `to`.
+    Copy {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    Move {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Moves a stash that was kept in the history back to `to`, giving
+    /// it back the `mode` it had before it was made private.
+    Restore {
+        from: PathBuf,
+        to: PathBuf,
+        mode: u32,
+    },
     Remove(PathBuf),
+    RemoveLink {
+        path: PathBuf,
+        owner: PathBuf,
+    },
+    RemoveDir(PathBuf),
+    /// Removes the file at `path`, which only goes ahead if its
+    /// contents still have the `hash` they were written with.
+    RemoveUnchanged {
+        path: PathBuf,
+        hash: String,
+    },
+    /// Removes a directory, which only goes ahead if it's empty.
+    RemoveEmptyDir(PathBuf),
+    /// Removes the git repository at `path`, which is either a
+    /// repository's `.git` or a directory holding one and nothing
+    /// else. It only goes ahead while the repository's HEAD is still
+    /// `head`, and nothing in it has been changed since.
+    RemoveRepo {
+        path: PathBuf,
+        head: Option<String>,
+    },
+    /// Removes a file for good. Nothing is stashed, so neither a
+    /// rollback nor an undo can bring it back, which is what secrets
+    /// need.
+    Discard(PathBuf),
+    /// Writes a new file with `mode`, less the umask.
+    WriteFile {
+        path: PathBuf,
+        contents: String,
+        mode: u32,
+    },
+}
+
+impl Op {
+    /// The path that this operation takes away, if it takes one away.
+    pub fn removed_path(&self) -> Option<&Path> {
+        match self {
+            Op::Remove(path)
+            | Op::Discard(path)
+            | Op::RemoveLink { path, .. }
+            | Op::RemoveDir(path)
+            | Op::RemoveUnchanged { path, .. }
+            | Op::RemoveEmptyDir(path)
+            | Op::RemoveRepo { path, .. }
+            | Op::Move { from: path, .. }
+            | Op::Restore { from: path, .. } => Some(path),
+            _ => None,
+        }
+    }
+
+    /// Whether this operation, when it undoes an earlier one, can go
+    /// ahead without losing anything that has changed since: what it
+    /// removes has to still be what the earlier operation left, and
+    /// where it puts something has to be free. Paths that are
+    /// `cleared` by the operations before it count as gone already.
+    /// Operations that can't be checked never go ahead.
+    pub fn can_undo(&self, cleared: &[PathBuf]) -> bool {
+        let is_cleared = |path: &Path| cleared.iter().any(|gone| path.starts_with(gone));
+        let is_free = |path: &Path| fs::symlink_metadata(path).is_err() || is_cleared(path);
+        match self {
+            Op::RemoveLink { path, owner } => links_into(path, owner),
+            Op::RemoveUnchanged { path, hash } => is_unchanged(path, hash),
+            Op::RemoveEmptyDir(dir) => fs::read_dir(dir).is_ok_and(|mut entries| {
+                entries.all(|entry| entry.is_ok_and(|entry| is_cleared(&entry.path())))
+            }),
+            Op::RemoveRepo { path, head } => is_unchanged_repo(path, head.as_deref()),
+            Op::Move { from, to } | Op::Restore { from, to, .. } => {
+                fs::symlink_metadata(from).is_ok() && is_free(to)
+            }
+            Op::Link { path, .. } | Op::RelativeLink { path, .. } => is_free(path),
+            _ => false,
+        }
+    }
+
+    /// Describes this operation with any path inside `home` written
+    /// as `~/...`, the way people are used to reading them.
+    pub fn display_from<'a>(&'a self, home: &'a Path) -> impl Display + 'a {
+        Described {
+            op: self,
+            home: Some(home),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Described {
+            op: self,
+            home: None,
+        }
+        .fmt(f)
+    }
+}
+
+struct Described<'a> {
+    op: &'a Op,
+    home: Option<&'a Path>,
+}
+
+impl Described<'_> {
+    fn path(&self, path: &Path) -> String {
+        match self.home.and_then(|home| path.strip_prefix(home).ok()) {
+            Some(rest) if rest.as_os_str().is_empty() => "~".to_owned(),
+            Some(rest) => format!("~/{}", rest.display()),
+            None => path.display().to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Described<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.op {
+            Op::MkDir(dir) => write!(f, "create directory {}", self.path(dir)),
+            Op::GitInit(dir) => write!(f, "initialize a git repository in {}", self.path(dir)),
+            Op::GitClone { url, path } => write!(f, "clone {} into {}", url, self.path(path)),
+            Op::Link { path, target } => {
+                write!(f, "link {} -> {}", self.path(path), self.path(target))
+            }
+            Op::RelativeLink { path, target } => write!(
+                f,
+                "link {} -> {} relatively",
+                self.path(path),
+                self.path(target)
+            ),
+            Op::HardLink { path, target } => {
+                write!(f, "hard link {} to {}", self.path(path), self.path(target))
+            }
+            Op::Copy { from, to } => write!(f, "copy {} to {}", self.path(from), self.path(to)),
+            Op::Move { from, to } => write!(f, "move {} to {}", self.path(from), self.path(to)),
+            Op::Restore { to, .. } => write!(f, "restore {}", self.path(to)),
+            Op::Remove(file) => write!(f, "remove {}", self.path(file)),
+            Op::RemoveLink { path, .. } => write!(f, "remove the link {}", self.path(path)),
+            Op::RemoveDir(dir) => write!(f, "remove {} and everything in it", self.path(dir)),
+            Op::RemoveUnchanged { path, .. } => {
+                write!(f, "remove {} if it hasn't changed", self.path(path))
+            }
+            Op::RemoveEmptyDir(dir) => write!(f, "remove the empty directory {}", self.path(dir)),
+            Op::RemoveRepo { path, .. } => {
+                write!(f, "remove the git repository {}", self.path(path))
+            }
+            Op::Discard(file) => write!(f, "remove {} for good", self.path(file)),
+            Op::WriteFile { path, .. } => write!(f, "write {}", self.path(path)),
+        }
+    }
 }
 
 pub type Result = anyhow::Result<()>;
@@ -14,6 +216,14 @@ pub struct FileOperations {
     root: PathBuf,
     operations: Vec<Op>,
     git_init_opts: git2::RepositoryInitOptions,
+    state_path: Option<PathBuf>,
+    /// Changes to the state, each with the number of operations that
+    /// were queued before it.
+    changes: Vec<(usize, Change)>,
+    /// Where committed batches are kept, and what to call this one.
+    history: Option<(History, String)>,
+    /// The batches that the queued operations undo, newest first.
+    undoing: Vec<Batch>,
 }
 
 impl FileOperations {
@@ -22,9 +232,33 @@ impl FileOperations {
             root: PathBuf::from(path.as_ref()),
             operations: vec![],
             git_init_opts: FileOperations::default_git_opts(),
+            state_path: None,
+            changes: vec![],
+            history: None,
+            undoing: vec![],
         }
     }
 
+    /// Keeps each committed batch of operations in the history at
+    /// `dir`, with this one called `description`, so it can be undone.
+    pub fn keep_history(&mut self, dir: impl AsRef<Path>, description: impl Into<String>) {
+        self.history = Some((History::new(dir), description.into()));
+    }
+
+    /// Says that the queued operations undo `batches`, newest first.
+    /// Once they've been committed the batches are forgotten, and the
+    /// state is put back the way it was before the oldest of them,
+    /// with any changes queued on top.
+    pub fn undoing(&mut self, batches: Vec<Batch>) {
+        self.undoing = batches;
+    }
+
+    /// Keeps the state file at `path` up to date with everything that
+    /// gets committed.
+    pub fn keep_state(&mut self, path: impl AsRef<Path>) {
+        self.state_path = Some(path.as_ref().to_path_buf());
+    }
+
     fn default_git_opts() -> git2::RepositoryInitOptions {
         let mut opts = git2::RepositoryInitOptions::new();
         opts.no_reinit(true);
@@ -32,16 +266,51 @@ impl FileOperations {
         opts
     }
 
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
     #[allow(dead_code)]
     pub fn operations(&self) -> &Vec<Op> {
         &self.operations
     }
 
+    #[allow(dead_code)]
+    pub fn changes(&self) -> impl Iterator<Item = &Change> {
+        self.changes.iter().map(|(_, change)| change)
+    }
+
     #[allow(dead_code)]
     pub fn create_dir(&mut self, name: impl AsRef<Path>) {
         self.operations.push(Op::MkDir(self.root.join(name)))
     }
 
+    /// Whether `path` is going to be removed by an operation that's
+    /// already been queued. Removing a link to a directory removes
+    /// every path that was reached through it too.
+    pub fn will_remove(&self, path: impl AsRef<Path>) -> bool {
+        let path = self.root.join(path);
+        self.operations.iter().any(|op| match op {
+            Op::Remove(removed)
+            | Op::RemoveLink { path: removed, .. }
+            | Op::RemoveDir(removed)
+            | Op::RemoveUnchanged { path: removed, .. }
+            | Op::RemoveEmptyDir(removed)
+            | Op::RemoveRepo { path: removed, .. } => path.starts_with(removed),
+            _ => false,
+        })
+    }
+
+    /// Whether `dir` is going to exist once the directories that have
+    /// already been queued are created.
+    pub fn will_create_dir(&self, dir: impl AsRef<Path>) -> bool {
+        let dir = self.root.join(dir);
+        self.operations.iter().any(|op| match op {
+            Op::MkDir(created) => created.starts_with(&dir),
+            _ => false,
+        })
+    }
+
     pub fn link(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) {
         self.operations.push(Op::Link {
             path: self.root.join(path),
@@ -49,49 +318,730 @@ impl FileOperations {
         });
     }
 
+    pub fn relative_link(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) {
+        self.operations.push(Op::RelativeLink {
+            path: self.root.join(path),
+            target: target.as_ref().to_path_buf(),
+        });
+    }
+
+    pub fn hard_link(&mut self, path: impl AsRef<Path>, target: impl AsRef<Path>) {
+        self.operations.push(Op::HardLink {
+            path: self.root.join(path),
+            target: target.as_ref().to_path_buf(),
+        });
+    }
+
+    pub fn copy_file(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        self.operations.push(Op::Copy {
+            from: from.as_ref().to_path_buf(),
+            to: self.root.join(to),
+        });
+    }
+
+    pub fn move_file(&mut self, from: impl AsRef<Path>, to: impl AsRef<Path>) {
+        self.operations.push(Op::Move {
+            from: self.root.join(from),
+            to: to.as_ref().to_path_buf(),
+        });
+    }
+
     pub fn remove(&mut self, file: impl AsRef<Path>) {
         self.operations.push(Op::Remove(self.root.join(file)));
     }
 
+    /// Queues removal of the symlink at `path`, which only goes ahead
+    /// if it still links to somewhere inside `owner` at commit time.
+    pub fn remove_link(&mut self, path: impl AsRef<Path>, owner: impl AsRef<Path>) {
+        self.operations.push(Op::RemoveLink {
+            path: self.root.join(path),
+            owner: owner.as_ref().to_path_buf(),
+        });
+    }
+
+    /// Removes `file` without keeping a copy of it anywhere, for
+    /// secrets that shouldn't outlive it.
+    pub fn discard(&mut self, file: impl AsRef<Path>) {
+        self.operations.push(Op::Discard(self.root.join(file)));
+    }
+
+    pub fn remove_dir(&mut self, dir: impl AsRef<Path>) {
+        self.operations.push(Op::RemoveDir(self.root.join(dir)));
+    }
+
+    pub fn write_file(&mut self, file: impl AsRef<Path>, contents: impl Into<String>) {
+        self.operations.push(Op::WriteFile {
+            path: self.root.join(file),
+            contents: contents.into(),
+            mode: DEFAULT_FILE_MODE,
+        });
+    }
+
+    /// Writes a file that only its owner can read, for anything that
+    /// holds secrets.
+    pub fn write_private(&mut self, file: impl AsRef<Path>, contents: impl Into<String>) {
+        self.operations.push(Op::WriteFile {
+            path: self.root.join(file),
+            contents: contents.into(),
+            mode: PRIVATE_FILE_MODE,
+        });
+    }
+
+    /// Queues `record` to be kept in the state file, once the
+    /// operations queued so far have been committed.
+    pub fn record(&mut self, record: Record) {
+        self.changes
+            .push((self.operations.len(), Change::Record(record)));
+    }
+
+    /// Queues `path` to be forgotten from the state file, for records
+    /// of things that are already gone.
+    pub fn forget(&mut self, path: impl AsRef<Path>) {
+        let path = self.root.join(path);
+        self.changes
+            .push((self.operations.len(), Change::Forget(path)));
+    }
+
+    /// Queues an already constructed operation, for callers that
+    /// work out their operations ahead of time.
+    pub fn push(&mut self, op: Op) {
+        self.operations.push(op);
+    }
+
     pub fn create_git_repo(&mut self, name: impl AsRef<Path>) {
         self.operations.push(Op::GitInit(self.root.join(name)))
     }
 
-    pub fn commit(mut self) -> Vec<Result> {
-        mem::replace(&mut self.operations, vec![])
-            .into_iter()
-            .map(|op| self.do_op(op))
-            .collect::<Vec<_>>()
+    pub fn clone_git_repo(&mut self, url: impl Into<String>, name: impl AsRef<Path>) {
+        self.operations.push(Op::GitClone {
+            url: url.into(),
+            path: self.root.join(name),
+        })
+    }
+
+    /// Performs every operation, or none of them.
+    ///
+    /// As each operation succeeds its inverse is recorded, and
+    /// anything it would destroy is stashed next to where it was. On
+    /// the first failure the recorded inverses are replayed in
+    /// reverse, putting things back the way they were. Stashes are
+    /// only cleaned up once every operation has succeeded, and the
+    /// state file is updated along with them.
+    ///
+    /// When there's a history, the batch is added to it instead of
+    /// cleaning up, with its stashes moved in so it can be undone
+    /// later. Batches that were being undone are forgotten.
+    pub fn commit_atomic(mut self) -> Result {
+        let previous_state = match self.state_path {
+            Some(ref path) => Some(State::load(path)?),
+            None => None,
+        };
+        let state = self.next_state()?;
+        let mut completed: Vec<Applied> = vec![];
+        for op in mem::take(&mut self.operations) {
+            let description = op.to_string();
+            match self.apply(op, &completed) {
+                Ok(applied) => completed.push(applied),
+                Err(err) => {
+                    let failure = format!("could not {}: {}", description, err);
+                    return self.fail(failure, completed);
+                }
+            }
+        }
+
+        if let (Some(path), Some(state)) = (&self.state_path, &state) {
+            if let Err(err) = state.save(path) {
+                let failure = format!(
+                    "could not save hermit's state to {}: {}",
+                    path.display(),
+                    err
+                );
+                return self.fail(failure, completed);
+            }
+        }
+
+        let history = self.history.take();
+        if let Some((history, description)) = &history {
+            let undoable = completed.iter().any(|applied| !applied.inverse.is_empty());
+            if self.undoing.is_empty() && undoable {
+                let is_secret = |path: &Path| {
+                    previous_state
+                        .iter()
+                        .chain(&state)
+                        .any(|state| state.get(path).is_some_and(Record::is_secret))
+                };
+                // The batch has already happened, so failing to keep it
+                // only means it can't be undone.
+                let _ = keep_batch(
+                    history,
+                    description,
+                    completed,
+                    previous_state.clone(),
+                    is_secret,
+                );
+                return Ok(());
+            }
+        }
+
+        for cleanup in completed.into_iter().flat_map(|applied| applied.cleanup) {
+            // A leftover stash is untidy but harmless, so there's no
+            // reason to fail a commit that has already happened.
+            let _ = self.do_op(cleanup);
+        }
+        if let Some((history, _)) = &history {
+            for batch in &self.undoing {
+                history.remove(batch).map_err(|err| {
+                    anyhow::anyhow!("undid batch {}, but could not forget it: {}", batch.id, err)
+                })?;
+            }
+        }
+        Ok(())
+    }
+
+    // Private Methods
+
+    /// Rolls back the `completed` operations after a `failure`.
+    fn fail(&mut self, failure: String, completed: Vec<Applied>) -> Result {
+        match self.roll_back(completed) {
+            Ok(()) => Err(anyhow::anyhow!("{}. Nothing was changed.", failure)),
+            Err(rollback_err) => Err(anyhow::anyhow!(
+                "{}. Undoing the changes made so far also failed: {}",
+                failure,
+                rollback_err
+            )),
+        }
     }
 
-    /// Private Methods
+    /// The state as it will be once every queued operation has been
+    /// committed, if there's a state file to keep. Whatever is removed
+    /// or moved away is forgotten, and records are kept in the order
+    /// they were queued in among the operations.
+    fn next_state(&mut self) -> anyhow::Result<Option<State>> {
+        let path = match self.state_path {
+            Some(ref path) => path,
+            None => return Ok(None),
+        };
+        if let Some(mut state) = self.undoing.last().and_then(|oldest| oldest.state.clone()) {
+            for (_, change) in mem::take(&mut self.changes) {
+                state.apply(change);
+            }
+            return Ok(Some(state));
+        }
+        let mut state = State::load(path)?;
+        let mut changes = mem::take(&mut self.changes).into_iter().peekable();
+        for (index, op) in self.operations.iter().enumerate() {
+            while let Some((_, change)) = changes.next_if(|(queued, _)| *queued <= index) {
+                state.apply(change);
+            }
+            if let Some(removed) = op.removed_path() {
+                state.forget(removed);
+            }
+        }
+        for (_, change) in changes {
+            state.apply(change);
+        }
+        Ok(Some(state))
+    }
+
+    fn roll_back(&mut self, completed: Vec<Applied>) -> Result {
+        for applied in completed.into_iter().rev() {
+            for op in applied.inverse {
+                self.do_op(op)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Performs `op`, first working out how to undo it. The operations
+    /// `completed` before it may have left stashes behind.
+    fn apply(&mut self, op: Op, completed: &[Applied]) -> anyhow::Result<Applied> {
+        let mut applied = Applied::default();
+        match op {
+            Op::MkDir(ref dir) => {
+                applied
+                    .inverse
+                    .extend(missing_dirs(dir).into_iter().map(Op::RemoveEmptyDir));
+            }
+            Op::GitInit(ref dir) | Op::GitClone { path: ref dir, .. } => {
+                let created = missing_dirs(dir);
+                let repo = match created.first() {
+                    Some(repo) => repo.clone(),
+                    None => dir.join(".git"),
+                };
+                self.do_op(op.clone())?;
+                applied.inverse.push(Op::RemoveRepo {
+                    head: git::head(&repo),
+                    path: repo,
+                });
+                let above = created.into_iter().skip(1).map(Op::RemoveEmptyDir);
+                applied.inverse.extend(above);
+                return Ok(applied);
+            }
+            Op::Link {
+                ref path,
+                ref target,
+            }
+            | Op::RelativeLink {
+                ref path,
+                ref target,
+            } => applied.inverse.push(Op::RemoveLink {
+                path: path.clone(),
+                owner: target.clone(),
+            }),
+            Op::HardLink {
+                ref path,
+                target: ref source,
+            }
+            | Op::Copy {
+                from: ref source,
+                to: ref path,
+            } => {
+                let hash = state::hash_file(source)
+                    .ok_or_else(|| anyhow::anyhow!("could not read {}", source.display()))?;
+                applied.inverse.push(Op::RemoveUnchanged {
+                    path: path.clone(),
+                    hash,
+                });
+            }
+            Op::Move { ref from, ref to }
+            | Op::Restore {
+                ref from, ref to, ..
+            } => {
+                applied.inverse.push(Op::Move {
+                    from: to.clone(),
+                    to: from.clone(),
+                });
+                applied.stash(to)?;
+            }
+            Op::Remove(ref file) => {
+                if fs::symlink_metadata(file)?.file_type().is_symlink() {
+                    let target = fs::read_link(file)?;
+                    fs::remove_file(file)?;
+                    applied.inverse.push(Op::Link {
+                        path: file.clone(),
+                        target,
+                    });
+                } else {
+                    applied.stash(file)?;
+                }
+                return Ok(applied);
+            }
+            Op::RemoveLink { ref path, .. } => {
+                let target = fs::read_link(path)?;
+                applied.inverse.push(Op::Link {
+                    path: path.clone(),
+                    target,
+                });
+            }
+            Op::Discard(_) => (),
+            Op::RemoveDir(ref dir) => {
+                fs::symlink_metadata(dir)?;
+                applied.stash(dir)?;
+                return Ok(applied);
+            }
+            Op::WriteFile {
+                ref path,
+                ref contents,
+                ..
+            } => {
+                applied.inverse.push(Op::RemoveUnchanged {
+                    path: path.clone(),
+                    hash: state::hash(contents.as_bytes()),
+                });
+                applied.stash(path)?;
+            }
+            Op::RemoveUnchanged { ref path, ref hash } => {
+                if !is_unchanged(path, hash) {
+                    anyhow::bail!("{} has changed", path.display());
+                }
+                applied.stash(path)?;
+                return Ok(applied);
+            }
+            Op::RemoveEmptyDir(ref dir) => {
+                // Removing what was in the directory stashed it there,
+                // so the stashes go along with the directory.
+                let stashes = completed
+                    .iter()
+                    .flat_map(|applied| &applied.cleanup)
+                    .filter_map(Op::removed_path)
+                    .collect::<Vec<_>>();
+                let is_empty = fs::read_dir(dir)?.all(|entry| {
+                    entry.is_ok_and(|entry| stashes.contains(&entry.path().as_path()))
+                });
+                if !is_empty {
+                    anyhow::bail!("{} isn't empty", dir.display());
+                }
+                applied.stash(dir)?;
+                return Ok(applied);
+            }
+            Op::RemoveRepo { ref path, ref head } => {
+                if !is_unchanged_repo(path, head.as_deref()) {
+                    anyhow::bail!("{} has changed", path.display());
+                }
+                applied.stash(path)?;
+                return Ok(applied);
+            }
+        }
+        self.do_op(op)?;
+        Ok(applied)
+    }
 
     fn do_op(&mut self, op: Op) -> Result {
         match op {
             Op::MkDir(dir) => fs::create_dir_all(dir)?,
             Op::GitInit(dir) => git_init(dir, &self.git_init_opts)?,
+            Op::GitClone { url, path } => git_clone(&url, path)?,
             Op::Link { path, target } => unix::fs::symlink(target, path)?,
-            Op::Remove(file) => fs::remove_file(file)?,
+            Op::RelativeLink { path, target } => {
+                let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+                unix::fs::symlink(relative_path(&target, dir), path)?
+            }
+            Op::HardLink { path, target } => fs::hard_link(target, path)?,
+            Op::Copy { from, to } => {
+                if fs::symlink_metadata(&to).is_ok() {
+                    anyhow::bail!("{} already exists", to.display());
+                }
+                fs::copy(from, to)?;
+            }
+            Op::Move { from, to } => move_file(from, to)?,
+            Op::Restore { from, to, mode } => {
+                move_file(from, to.clone())?;
+                fs::set_permissions(to, fs::Permissions::from_mode(mode))?
+            }
+            Op::Remove(file) | Op::Discard(file) => fs::remove_file(file)?,
+            Op::RemoveLink { path, owner } => {
+                if !links_into(&path, &owner) {
+                    anyhow::bail!("{} does not link into {}", path.display(), owner.display());
+                }
+                fs::remove_file(path)?
+            }
+            Op::RemoveDir(dir) => fs::remove_dir_all(dir)?,
+            Op::RemoveUnchanged { path, hash } => {
+                if !is_unchanged(&path, &hash) {
+                    anyhow::bail!("{} has changed", path.display());
+                }
+                fs::remove_file(path)?
+            }
+            Op::RemoveEmptyDir(dir) => fs::remove_dir(dir)?,
+            Op::RemoveRepo { path, head } => {
+                if !is_unchanged_repo(&path, head.as_deref()) {
+                    anyhow::bail!("{} has changed", path.display());
+                }
+                fs::remove_dir_all(path)?
+            }
+            Op::WriteFile {
+                path,
+                contents,
+                mode,
+            } => write_with_mode(&path, &contents, mode)?,
+        };
+        Ok(())
+    }
+}
+
+/// How to undo an operation that has been performed, and what to
+/// tidy up once it's certain it won't need undoing.
+#[derive(Default)]
+struct Applied {
+    inverse: Vec<Op>,
+    cleanup: Vec<Op>,
+}
+
+impl Applied {
+    /// Moves whatever is at `path` out of the way, if anything, so
+    /// that it can be put back on rollback.
+    fn stash(&mut self, path: &Path) -> Result {
+        let metadata = match fs::symlink_metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(()),
         };
+
+        let stash = stash_path(path);
+        fs::rename(path, &stash)?;
+        self.inverse.push(Op::Move {
+            from: stash.clone(),
+            to: path.to_path_buf(),
+        });
+        self.cleanup.push(if metadata.is_dir() {
+            Op::RemoveDir(stash)
+        } else {
+            Op::Remove(stash)
+        });
         Ok(())
     }
 }
 
+/// Adds the `completed` operations to `history` as a batch, moving the
+/// stashes they made into it where they can be. Kept stashes are made
+/// private, and put back with the mode they had when they're undone.
+///
+/// Stashes of files that `is_secret` are thrown away instead, since
+/// they can be generated again, and undoing leaves those files as
+/// they are.
+fn keep_batch(
+    history: &History,
+    description: &str,
+    completed: Vec<Applied>,
+    state: Option<State>,
+    is_secret: impl Fn(&Path) -> bool,
+) -> io::Result<()> {
+    let id = history.next_id().map_err(io::Error::other)?;
+    let dir = history.stash_dir(id);
+    fs::create_dir_all(&dir)?;
+
+    let mut undo = completed
+        .iter()
+        .rev()
+        .flat_map(|applied| applied.inverse.clone())
+        .collect::<Vec<_>>();
+    let mut stashes = vec![];
+    for (count, cleanup) in completed
+        .iter()
+        .flat_map(|applied| &applied.cleanup)
+        .enumerate()
+    {
+        let stash = match cleanup {
+            Op::Remove(stash) | Op::RemoveDir(stash) => stash,
+            _ => continue,
+        };
+        let stashed_from = undo.iter().find_map(|op| match op {
+            Op::Move { from, to } if from == stash => Some(to.clone()),
+            _ => None,
+        });
+        if let Some(secret) = stashed_from.filter(|path| is_secret(path)) {
+            let _ = fs::remove_file(stash);
+            undo.retain(|op| match op {
+                Op::Move { from, .. } => from != stash,
+                Op::RemoveUnchanged { path, .. } => *path != secret,
+                _ => true,
+            });
+            continue;
+        }
+        let kept = dir.join(format!("stash-{}", count));
+        if fs::rename(stash, &kept).is_err() {
+            stashes.push(stash.clone());
+            continue;
+        }
+        let mode = make_private(&kept);
+        for op in &mut undo {
+            if let Op::Move { from, to } = op {
+                if from == stash {
+                    *op = match mode {
+                        Some(mode) => Op::Restore {
+                            from: kept.clone(),
+                            to: to.clone(),
+                            mode,
+                        },
+                        None => Op::Move {
+                            from: kept.clone(),
+                            to: to.clone(),
+                        },
+                    };
+                }
+            }
+        }
+        stashes.push(kept);
+    }
+
+    let committed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    history.add(&Batch {
+        id,
+        description: description.to_owned(),
+        committed_at,
+        stashes,
+        undo,
+        state,
+    })
+}
+
+/// Makes the file or directory at `path` private to its owner,
+/// returning the mode it had before. Links are left alone, since
+/// changing their mode would change whatever they link to.
+fn make_private(path: &Path) -> Option<u32> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let private = match metadata.file_type() {
+        kind if kind.is_symlink() => return None,
+        kind if kind.is_dir() => PRIVATE_DIR_MODE,
+        _ => PRIVATE_FILE_MODE,
+    };
+    fs::set_permissions(path, fs::Permissions::from_mode(private)).ok()?;
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+/// Whether `path` is a symlink whose target is somewhere inside `dir`.
+/// Relative targets are resolved against the directory of the link.
+pub fn links_into(path: &Path, dir: &Path) -> bool {
+    let target = match fs::read_link(path) {
+        Ok(target) => target,
+        Err(_) => return false,
+    };
+    let target = match path.parent() {
+        Some(parent) => parent.join(target),
+        None => target,
+    };
+    normalize_path(&target).starts_with(normalize_path(dir))
+}
+
+/// Lexically resolves `.` and `..` components so that prefix checks
+/// can't be fooled by paths like `~/../../etc/passwd`.
+pub fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// The path that leads from the directory `dir` to `target`, for a
+/// link in `dir` that shouldn't break if both move together.
+pub fn relative_path(target: &Path, dir: &Path) -> PathBuf {
+    let target = normalize_path(target);
+    let dir = normalize_path(dir);
+    let shared = target
+        .components()
+        .zip(dir.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let mut relative = PathBuf::new();
+    for _ in dir.components().skip(shared) {
+        relative.push("..");
+    }
+    for component in target.components().skip(shared) {
+        relative.push(component);
+    }
+    relative
+}
+
+fn stash_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let mut stash = path.with_file_name(format!(".{}.hermit-stash", name));
+    let mut count = 1;
+    while fs::symlink_metadata(&stash).is_ok() {
+        stash = path.with_file_name(format!(".{}.hermit-stash.{}", name, count));
+        count += 1;
+    }
+    stash
+}
+
+/// The directories that creating `dir` would bring into existence,
+/// innermost first.
+fn missing_dirs(dir: &Path) -> Vec<PathBuf> {
+    dir.ancestors()
+        .take_while(|ancestor| fs::symlink_metadata(ancestor).is_err())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// Whether `path` is a real file whose contents still have `hash`.
+fn is_unchanged(path: &Path, hash: &str) -> bool {
+    let is_file = fs::symlink_metadata(path).is_ok_and(|metadata| metadata.is_file());
+    is_file && state::hash_file(path).as_deref() == Some(hash)
+}
+
+/// Whether the repository at `path` still has `head` checked out and
+/// nothing staged. When `path` holds the whole working tree rather
+/// than just `.git`, nothing in it may be modified or untracked either.
+fn is_unchanged_repo(path: &Path, head: Option<&str>) -> bool {
+    let status = match git::status(path) {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    let only_git_dir = path.file_name() == Some(OsStr::new(".git"));
+    git::head(path).as_deref() == head
+        && status.staged.is_empty()
+        && (only_git_dir || status.is_clean())
+}
+
+/// Writes a file, giving it `mode` if it has to be created.
+fn write_with_mode(path: &Path, contents: &str, mode: u32) -> Result {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(path)?;
+    file.write_all(contents.as_bytes())?;
+    Ok(())
+}
+
+/// Renames a file or directory, falling back to copying and removing
+/// the original when the rename can't be done in place (e.g. across
+/// filesystems).
+fn move_file(from: PathBuf, to: PathBuf) -> Result {
+    if fs::rename(&from, &to).is_err() {
+        if fs::symlink_metadata(&from)?.is_dir() {
+            copy_dir(&from, &to)?;
+            fs::remove_dir_all(&from)?;
+        } else {
+            fs::copy(&from, &to)?;
+            fs::remove_file(&from)?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the directory `from` and everything in it to `to`, keeping
+/// symlinks as they are.
+fn copy_dir(from: &Path, to: &Path) -> io::Result<()> {
+    for entry in WalkDir::new(from).follow_links(false) {
+        let entry = entry?;
+        let relative = entry
+            .path()
+            .strip_prefix(from)
+            .expect("walked entries are inside the directory");
+        let dest = to.join(relative);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            fs::create_dir_all(&dest)?;
+            fs::set_permissions(&dest, entry.metadata()?.permissions())?;
+        } else if file_type.is_symlink() {
+            unix::fs::symlink(fs::read_link(entry.path())?, &dest)?;
+        } else {
+            fs::copy(entry.path(), &dest)?;
+        }
+    }
+    Ok(())
+}
+
 fn git_init(dir: PathBuf, options: &git2::RepositoryInitOptions) -> Result {
     git2::Repository::init_opts(dir, options)
         .map(|_| ())
         .map_err(anyhow::Error::from)
 }
 
+fn git_clone(url: &str, dir: PathBuf) -> Result {
+    git2::Repository::clone(url, dir)
+        .map(|_| ())
+        .map_err(anyhow::Error::from)
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
         fs,
+        os::unix::{
+            self,
+            fs::{MetadataExt, PermissionsExt},
+        },
         path::{Path, PathBuf},
     };
 
-    use super::FileOperations;
-    use crate::test_helpers::filesystem::set_up;
+    use super::{copy_dir, relative_path, FileOperations, Op};
+    use crate::{
+        history::History,
+        shell::Placement,
+        state::{Record, State},
+        test_helpers::filesystem::set_up,
+    };
 
     #[test]
     fn can_link_file() {
@@ -101,17 +1051,14 @@ mod tests {
         let target_root_dir = set_up();
         let target_root = target_root_dir.path();
 
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
         let target_path = target_root.join("target_file");
         let link_path = test_root.join("link");
 
         fs::File::create(&target_path).unwrap();
 
         file_set.link("link", &target_path);
-        let results = file_set.commit();
-
-        assert_eq!(results.len(), 1);
-        results[0].as_ref().expect("Op failed");
+        file_set.commit_atomic().expect("Commit failed");
 
         match fs::symlink_metadata(&link_path) {
             Ok(val) => assert!(val.file_type().is_symlink()),
@@ -119,6 +1066,231 @@ mod tests {
         };
     }
 
+    #[test]
+    fn can_link_a_file_relatively() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::create_dir_all(test_root.join("shells/work")).unwrap();
+        fs::write(test_root.join("shells/work/.bashrc"), "contents").unwrap();
+        fs::create_dir_all(test_root.join("home")).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root.join("home"));
+        file_set.relative_link(".bashrc", test_root.join("shells/work/.bashrc"));
+        file_set.commit_atomic().expect("Commit failed");
+
+        assert_eq!(
+            fs::read_link(test_root.join("home/.bashrc")).unwrap(),
+            PathBuf::from("../shells/work/.bashrc")
+        );
+        assert_eq!(
+            fs::read_to_string(test_root.join("home/.bashrc")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn works_out_relative_paths_between_directories() {
+        assert_eq!(
+            relative_path(Path::new("/a/b/c"), Path::new("/a/d")),
+            PathBuf::from("../b/c")
+        );
+        assert_eq!(
+            relative_path(Path::new("/a/b/c"), Path::new("/a/b")),
+            PathBuf::from("c")
+        );
+        assert_eq!(
+            relative_path(Path::new("/a/b"), Path::new("/x/y/../z")),
+            PathBuf::from("../../a/b")
+        );
+    }
+
+    #[test]
+    fn can_hard_link_and_copy_files() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let source = test_root.join("source");
+        fs::write(&source, "contents").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.hard_link("hard", &source);
+        file_set.copy_file(&source, "copy");
+        file_set.commit_atomic().expect("Commit failed");
+
+        let inode = |path: &Path| fs::metadata(path).unwrap().ino();
+        assert_eq!(inode(&test_root.join("hard")), inode(&source));
+        assert_ne!(inode(&test_root.join("copy")), inode(&source));
+        assert_eq!(
+            fs::read_to_string(test_root.join("copy")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn copying_does_not_replace_what_is_there() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::write(test_root.join("source"), "new").unwrap();
+        fs::write(test_root.join("copy"), "old").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.copy_file(test_root.join("source"), "copy");
+
+        assert!(file_set.commit_atomic().is_err());
+        assert_eq!(fs::read_to_string(test_root.join("copy")).unwrap(), "old");
+    }
+
+    #[test]
+    fn commits_keep_the_state_file_up_to_date() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let state_path = test_root.join("state.toml");
+        let source = test_root.join("source");
+        fs::write(&source, "contents").unwrap();
+        let record = |path: &str| {
+            Record::new(
+                &test_root.join(path),
+                &source,
+                &Placement::Link(source.clone()),
+            )
+        };
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_state(&state_path);
+        file_set.link("old", &source);
+        file_set.record(record("old"));
+        file_set.link("kept", &source);
+        file_set.record(record("kept"));
+        file_set.commit_atomic().expect("Commit failed");
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_state(&state_path);
+        file_set.remove("old");
+        file_set.link("new", &source);
+        file_set.record(record("new"));
+        file_set.commit_atomic().expect("Commit failed");
+        assert_eq!(
+            State::load(&state_path).unwrap().records,
+            vec![record("kept"), record("new")]
+        );
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_state(&state_path);
+        file_set.remove("kept");
+        file_set.copy_file(&source, "new");
+        assert!(file_set.commit_atomic().is_err());
+        assert_eq!(
+            State::load(&state_path).unwrap().records,
+            vec![record("kept"), record("new")]
+        );
+    }
+
+    #[test]
+    fn committed_batches_are_kept_so_they_can_be_undone() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let state_path = test_root.join("state.toml");
+        let history = History::new(test_root.join("history"));
+        let source = test_root.join("source");
+        fs::write(&source, "contents").unwrap();
+        fs::write(test_root.join("file"), "original").unwrap();
+        unix::fs::symlink(&source, test_root.join("link")).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_state(&state_path);
+        file_set.keep_history(test_root.join("history"), "overwrite file");
+        file_set.write_file("file", "replaced");
+        file_set.remove("link");
+        file_set.commit_atomic().expect("Commit failed");
+
+        let batches = history.batches().unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].description, "overwrite file");
+        assert_eq!(
+            batches[0].stashes,
+            vec![history.stash_dir(1).join("stash-0")]
+        );
+        assert!(!test_root.join(".file.hermit-stash").exists());
+        assert!(!test_root.join("link").exists());
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_state(&state_path);
+        file_set.keep_history(test_root.join("history"), "undo");
+        for op in batches[0].undo.clone() {
+            file_set.push(op);
+        }
+        file_set.undoing(batches);
+        file_set.commit_atomic().expect("Undo failed");
+
+        assert_eq!(
+            fs::read_to_string(test_root.join("file")).unwrap(),
+            "original"
+        );
+        assert_eq!(fs::read_link(test_root.join("link")).unwrap(), source);
+        assert_eq!(history.batches(), Ok(vec![]));
+        assert!(!history.stash_dir(1).exists());
+        assert_eq!(State::load(&state_path), Ok(State::default()));
+    }
+
+    #[test]
+    fn kept_stashes_are_private_until_they_are_undone() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let history = History::new(test_root.join("history"));
+        let file = test_root.join("file");
+        fs::write(&file, "original").unwrap();
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_history(test_root.join("history"), "overwrite file");
+        file_set.write_file("file", "replaced");
+        file_set.commit_atomic().expect("Commit failed");
+
+        let batches = history.batches().unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&batches[0].stashes[0]), 0o600);
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_history(test_root.join("history"), "undo");
+        for op in batches[0].undo.clone() {
+            file_set.push(op);
+        }
+        file_set.undoing(batches);
+        file_set.commit_atomic().expect("Undo failed");
+
+        assert_eq!(fs::read_to_string(&file).unwrap(), "original");
+        assert_eq!(mode(&file), 0o644);
+    }
+
+    #[test]
+    fn does_not_keep_stashes_of_generated_secrets() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let history = History::new(test_root.join("history"));
+        let netrc = test_root.join(".netrc");
+        let complete = "machine example.com\npassword hunter2\n";
+        fs::write(&netrc, complete).unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_state(test_root.join("state.toml"));
+        file_set.keep_history(test_root.join("history"), "add --redact .netrc");
+        let placement = Placement::Generate(complete.to_owned());
+        file_set.push(placement.op(&netrc));
+        file_set.record(Record::new(
+            &netrc,
+            &test_root.join("shell/.netrc"),
+            &placement,
+        ));
+        file_set.commit_atomic().expect("Commit failed");
+
+        let batches = history.batches().unwrap();
+        assert_eq!(batches[0].stashes, Vec::<PathBuf>::new());
+        assert_eq!(batches[0].undo, vec![]);
+        assert!(walkdir::WalkDir::new(test_root.join("history"))
+            .into_iter()
+            .filter_map(Result::ok)
+            .all(|entry| fs::read_to_string(entry.path()).map_or(true, |c| c != complete)));
+    }
+
     #[test]
     fn does_not_link_file_without_commit() {
         let test_root = PathBuf::from("no-link");
@@ -131,19 +1303,68 @@ mod tests {
         assert!(!link_path.exists());
     }
 
+    #[test]
+    fn can_move_file() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+
+        let target_root_dir = set_up();
+        let target_root = target_root_dir.path();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        let target_path = target_root.join("moved_file");
+
+        fs::write(test_root.join("file_a"), "contents").unwrap();
+        file_set.move_file("file_a", &target_path);
+        file_set.commit_atomic().expect("Commit failed");
+        assert!(!test_root.join("file_a").exists());
+        assert_eq!(fs::read_to_string(&target_path).unwrap(), "contents");
+    }
+
+    #[test]
+    fn copies_directories_with_everything_in_them() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::create_dir_all(test_root.join("shell/.config")).unwrap();
+        fs::write(test_root.join("shell/.config/file"), "contents").unwrap();
+        unix::fs::symlink("file", test_root.join("shell/.config/link")).unwrap();
+
+        copy_dir(&test_root.join("shell"), &test_root.join("copy")).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(test_root.join("copy/.config/file")).unwrap(),
+            "contents"
+        );
+        assert_eq!(
+            fs::read_link(test_root.join("copy/.config/link")).unwrap(),
+            Path::new("file")
+        );
+    }
+
+    #[test]
+    fn does_not_move_file_without_commit() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+        let file_path = test_root.join("file_a");
+        let target_path = test_root.join("file_b");
+
+        fs::File::create(&file_path).unwrap();
+        file_set.move_file("file_a", &target_path);
+        assert!(file_path.exists());
+        assert!(!target_path.exists());
+    }
+
     #[test]
     fn can_remove_file() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
 
         // Create file to remove
         fs::File::create(test_root.join("file_a")).unwrap();
         file_set.remove("file_a");
-        let results = file_set.commit();
-
-        assert_eq!(results.len(), 1);
-        results[0].as_ref().expect("Op failed");
+        file_set.commit_atomic().expect("Commit failed");
         assert!(!test_root.join("file_a").exists());
     }
 
@@ -151,7 +1372,7 @@ mod tests {
     fn does_not_remove_file_without_commit() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
         let file_path = test_root.join("file_a");
         // Create file to remove
         fs::File::create(&file_path).unwrap();
@@ -161,18 +1382,97 @@ mod tests {
         assert!(file_path.exists());
     }
 
+    #[test]
+    fn removes_a_link_into_its_owner() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let owner = test_root.join("shell");
+        unix::fs::symlink(owner.join("file_a"), test_root.join("link")).unwrap();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.remove_link("link", &owner);
+        file_set.commit_atomic().expect("Commit failed");
+
+        assert!(fs::symlink_metadata(test_root.join("link")).is_err());
+    }
+
+    #[test]
+    fn refuses_to_remove_anything_but_a_link_into_its_owner() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let owner = test_root.join("shell");
+        unix::fs::symlink("/elsewhere", test_root.join("link")).unwrap();
+        fs::write(test_root.join("file_a"), "contents").unwrap();
+
+        for path in &["link", "file_a"] {
+            let mut file_set = FileOperations::rooted_at(test_root);
+            file_set.remove_link(path, &owner);
+            assert!(file_set.commit_atomic().is_err());
+        }
+
+        assert!(fs::symlink_metadata(test_root.join("link")).is_ok());
+        assert_eq!(
+            fs::read_to_string(test_root.join("file_a")).unwrap(),
+            "contents"
+        );
+    }
+
+    #[test]
+    fn can_remove_a_directory_and_its_contents() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+        fs::create_dir_all(test_root.join("test/inner")).unwrap();
+        fs::File::create(test_root.join("test/inner/file_a")).unwrap();
+
+        file_set.remove_dir("test");
+        assert!(test_root.join("test").is_dir());
+
+        file_set.commit_atomic().expect("Commit failed");
+        assert!(!test_root.join("test").exists());
+    }
+
+    #[test]
+    fn can_write_a_file() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.write_file("file_a", "contents");
+        file_set.write_private("secret", "hunter2");
+        file_set.push(Op::WriteFile {
+            path: test_root.join("script"),
+            contents: "#!/bin/sh\n".to_owned(),
+            mode: 0o750,
+        });
+        assert!(!test_root.join("file_a").exists());
+
+        file_set.commit_atomic().expect("Commit failed");
+        assert_eq!(
+            fs::read_to_string(test_root.join("file_a")).unwrap(),
+            "contents"
+        );
+        let mode = |name: &str| {
+            fs::metadata(test_root.join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        assert_eq!(mode("secret"), 0o600);
+        assert_eq!(mode("script"), 0o750);
+    }
+
     #[test]
     fn can_create_a_directory() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
 
         assert!(!test_root.join("test").is_dir());
         file_set.create_dir("test");
 
-        let results = file_set.commit();
-        assert_eq!(results.len(), 1);
-        results[0].as_ref().expect("Op failed");
+        file_set.commit_atomic().expect("Commit failed");
         assert!(test_root.join("test").is_dir());
     }
 
@@ -180,7 +1480,7 @@ mod tests {
     fn does_not_create_a_directory_without_commit() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
 
         assert!(!test_root.join("test").is_dir());
         file_set.create_dir("test");
@@ -191,28 +1491,105 @@ mod tests {
     fn can_create_path_of_needed_directories() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
 
         let path = Path::new("test").join("one").join("two").join("three");
         file_set.create_dir(path);
 
-        let results = file_set.commit();
-        assert_eq!(results.len(), 1);
-        results[0].as_ref().expect("Op failed");
+        file_set.commit_atomic().expect("Commit failed");
         assert!(test_root.join("test").is_dir());
     }
 
+    #[test]
+    fn undoing_removes_directories_along_with_what_was_put_in_them() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let history = History::new(test_root.join("history"));
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_history(test_root.join("history"), "write file");
+        file_set.create_dir("dir/inner");
+        file_set.write_file("dir/inner/file", "written");
+        file_set.commit_atomic().expect("Commit failed");
+
+        let batches = history.batches().unwrap();
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_history(test_root.join("history"), "undo");
+        for op in batches[0].undo.clone() {
+            file_set.push(op);
+        }
+        file_set.undoing(batches);
+        file_set.commit_atomic().expect("Undo failed");
+
+        assert!(!test_root.join("dir").exists());
+    }
+
+    #[test]
+    fn undoing_leaves_alone_what_has_changed_since() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let history = History::new(test_root.join("history"));
+        let source = test_root.join("source");
+        fs::write(&source, "contents").unwrap();
+
+        let mut file_set = FileOperations::rooted_at(test_root);
+        file_set.keep_history(test_root.join("history"), "make things");
+        file_set.create_dir("dir/inner");
+        file_set.write_file("dir/file", "written");
+        file_set.link("link", &source);
+        file_set.create_git_repo("repo");
+        file_set.commit_atomic().expect("Commit failed");
+        let undo = history.batches().unwrap()[0].undo.clone();
+        let can_undo = |undo: &[Op]| {
+            let mut cleared = vec![];
+            undo.iter()
+                .map(|op| {
+                    let can = op.can_undo(&cleared);
+                    if can {
+                        cleared.extend(op.removed_path().map(Path::to_path_buf));
+                    }
+                    can
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(can_undo(&undo), vec![true; undo.len()]);
+
+        fs::write(test_root.join("dir/file"), "edited").unwrap();
+        fs::remove_file(test_root.join("link")).unwrap();
+        unix::fs::symlink(test_root.join("elsewhere"), test_root.join("link")).unwrap();
+        let repo = git2::Repository::open(test_root.join("repo")).unwrap();
+        fs::write(test_root.join("repo/.bashrc"), "").unwrap();
+        repo.index()
+            .unwrap()
+            .add_path(Path::new(".bashrc"))
+            .unwrap();
+        repo.index().unwrap().write().unwrap();
+        fs::write(test_root.join("dir/inner/new"), "").unwrap();
+
+        assert_eq!(can_undo(&undo), vec![false; undo.len()]);
+        for op in undo {
+            let mut file_set = FileOperations::rooted_at(test_root);
+            file_set.push(op);
+            file_set
+                .commit_atomic()
+                .expect_err("Commit unexpectedly succeeded");
+        }
+        assert!(test_root.join("repo/.git").is_dir());
+        assert_eq!(
+            fs::read_to_string(test_root.join("dir/file")).unwrap(),
+            "edited"
+        );
+    }
+
     #[test]
     fn can_init_a_git_repo() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
 
         file_set.create_git_repo(".");
 
-        let results = file_set.commit();
-        assert_eq!(results.len(), 1);
-        results[0].as_ref().expect("Op failed");
+        file_set.commit_atomic().expect("Commit failed");
         assert!(test_root.join(".git").is_dir());
     }
 
@@ -220,7 +1597,7 @@ mod tests {
     fn does_not_init_without_commit() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
         let path = Path::new("test").join("repo");
         let git_dir_path = path.join(".git");
 
@@ -233,29 +1610,156 @@ mod tests {
     fn can_init_a_git_repo_at_a_nonexistent_path() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
         let path = Path::new("test").join("sub").join("repo");
 
         file_set.create_git_repo(&path);
 
-        let results = file_set.commit();
-        assert_eq!(results.len(), 1);
-        results[0].as_ref().expect("Op failed");
+        file_set.commit_atomic().expect("Commit failed");
         assert!(test_root.join(&path).join(".git").is_dir());
     }
 
+    /// Makes a bare repository in `path` with one commit pushed to it,
+    /// and returns its `file://` url.
+    fn make_remote_repo(path: &Path) -> String {
+        let bare_path = path.join("remote.git");
+        let bare = git2::Repository::init_bare(&bare_path).unwrap();
+        let url = format!("file://{}", bare_path.display());
+
+        let work_path = path.join("work");
+        let repo = git2::Repository::init(&work_path).unwrap();
+        fs::write(work_path.join(".bashrc"), "export EDITOR=vi").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(".bashrc")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Geoff", "geoff@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "Initial", &tree, &[])
+            .unwrap();
+
+        let head = repo.head().unwrap().name().unwrap().to_owned();
+        let mut remote = repo.remote("origin", &url).unwrap();
+        remote.push(&[format!("{}:{}", head, head)], None).unwrap();
+        bare.set_head(&head).unwrap();
+        url
+    }
+
+    #[test]
+    fn can_clone_a_git_repo() {
+        let remote_dir = set_up();
+        let url = make_remote_repo(remote_dir.path());
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.clone_git_repo(url, "shells/cloned");
+
+        file_set.commit_atomic().expect("Commit failed");
+        assert!(test_root.join("shells/cloned/.git").is_dir());
+        assert!(test_root.join("shells/cloned/.bashrc").is_file());
+    }
+
+    #[test]
+    fn does_not_clone_without_commit() {
+        let remote_dir = set_up();
+        let url = make_remote_repo(remote_dir.path());
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+
+        file_set.clone_git_repo(url, "cloned");
+        assert!(!test_root.join("cloned").exists());
+    }
+
     #[test]
     fn wont_re_init_an_already_existing_repository() {
         let test_root_dir = set_up();
         let test_root = test_root_dir.path();
-        let mut file_set = FileOperations::rooted_at(&test_root);
+        let mut file_set = FileOperations::rooted_at(test_root);
 
         file_set.create_git_repo(".");
         file_set.create_git_repo(".");
 
-        let results = file_set.commit();
-        assert_eq!(results.len(), 2);
-        results[0].as_ref().expect("Op failed");
-        results[1].as_ref().expect_err("Op unexpectedly succeeded");
+        file_set
+            .commit_atomic()
+            .expect_err("Commit unexpectedly succeeded");
+    }
+
+    #[test]
+    fn atomic_commit_performs_every_operation() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+        fs::write(test_root.join("file_a"), "a").unwrap();
+
+        file_set.create_dir("dir/inner");
+        file_set.remove("file_a");
+        file_set.link("link", test_root.join("dir"));
+        file_set.write_file("file_b", "b");
+
+        file_set.commit_atomic().expect("Commit failed");
+        assert!(test_root.join("dir/inner").is_dir());
+        assert!(!test_root.join("file_a").exists());
+        assert!(test_root.join("link").is_dir());
+        let mut names = fs::read_dir(test_root)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["dir", "file_b", "link"]);
+    }
+
+    #[test]
+    fn atomic_commit_rolls_back_when_an_operation_fails() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let mut file_set = FileOperations::rooted_at(test_root);
+        fs::write(test_root.join("file_a"), "a").unwrap();
+        fs::write(test_root.join("file_b"), "b").unwrap();
+        fs::write(test_root.join("file_c"), "c").unwrap();
+        unix::fs::symlink("/elsewhere", test_root.join("old_link")).unwrap();
+
+        file_set.create_dir("dir/inner");
+        file_set.link("dir/inner/link", test_root.join("file_a"));
+        file_set.remove("file_a");
+        file_set.remove("old_link");
+        file_set.write_file("file_b", "overwritten");
+        file_set.move_file("file_c", test_root.join("dir/file_c"));
+        file_set.remove("does_not_exist");
+
+        file_set
+            .commit_atomic()
+            .expect_err("Commit unexpectedly succeeded");
+        assert!(!test_root.join("dir").exists());
+        assert_eq!(fs::read_to_string(test_root.join("file_a")).unwrap(), "a");
+        assert_eq!(fs::read_to_string(test_root.join("file_b")).unwrap(), "b");
+        assert_eq!(fs::read_to_string(test_root.join("file_c")).unwrap(), "c");
+        assert_eq!(
+            fs::read_link(test_root.join("old_link")).unwrap(),
+            PathBuf::from("/elsewhere")
+        );
+        assert_eq!(fs::read_dir(test_root).unwrap().count(), 4);
+    }
+
+    #[test]
+    fn describes_operations_relative_to_home() {
+        let home = Path::new("/home/geoff");
+        let link = Op::Link {
+            path: home.join(".bashrc"),
+            target: home.join(".config/hermit/shells/work/.bashrc"),
+        };
+        let remove = Op::RemoveDir(PathBuf::from("/etc/skel"));
+
+        assert_eq!(
+            link.display_from(home).to_string(),
+            "link ~/.bashrc -> ~/.config/hermit/shells/work/.bashrc"
+        );
+        assert_eq!(
+            remove.display_from(home).to_string(),
+            "remove /etc/skel and everything in it"
+        );
+        assert_eq!(
+            link.to_string(),
+            "link /home/geoff/.bashrc -> /home/geoff/.config/hermit/shells/work/.bashrc"
+        );
     }
 }
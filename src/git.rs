@@ -0,0 +1,233 @@
+use crate::common::*;
+
+use std::convert::TryFrom;
+
+use git2::{BranchType, Repository, Status, StatusOptions};
+
+/// How the branch a shell has checked out compares to its upstream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Tracking {
+    pub upstream: String,
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+/// A summary of a shell repository's working tree.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RepoStatus {
+    pub branch: Option<String>,
+    pub tracking: Option<Tracking>,
+    /// The URL of the `origin` remote, or of the only remote if there's
+    /// just one.
+    pub remote_url: Option<String>,
+    pub last_commit: Option<SystemTime>,
+    pub staged: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+    pub untracked: Vec<PathBuf>,
+}
+
+impl RepoStatus {
+    pub fn is_clean(&self) -> bool {
+        self.staged.is_empty() && self.modified.is_empty() && self.untracked.is_empty()
+    }
+}
+
+const STAGED: Status = Status::from_bits_truncate(
+    Status::INDEX_NEW.bits()
+        | Status::INDEX_MODIFIED.bits()
+        | Status::INDEX_DELETED.bits()
+        | Status::INDEX_RENAMED.bits()
+        | Status::INDEX_TYPECHANGE.bits(),
+);
+
+const MODIFIED: Status = Status::from_bits_truncate(
+    Status::WT_MODIFIED.bits()
+        | Status::WT_DELETED.bits()
+        | Status::WT_RENAMED.bits()
+        | Status::WT_TYPECHANGE.bits(),
+);
+
+pub fn status(path: &Path) -> StdResult<RepoStatus, git2::Error> {
+    let repo = Repository::open(path)?;
+    let mut status = RepoStatus::default();
+
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    for entry in repo.statuses(Some(&mut options))?.iter() {
+        let path = match entry.path() {
+            Some(path) => PathBuf::from(path),
+            None => continue,
+        };
+        let flags = entry.status();
+        if flags.intersects(STAGED) {
+            status.staged.push(path.clone());
+        }
+        if flags.intersects(MODIFIED) {
+            status.modified.push(path.clone());
+        }
+        if flags.contains(Status::WT_NEW) {
+            status.untracked.push(path);
+        }
+    }
+
+    status.remote_url = remote_url(&repo);
+    match repo.head() {
+        Ok(head) => {
+            status.last_commit = head.peel_to_commit().ok().and_then(|commit| {
+                let seconds = u64::try_from(commit.time().seconds()).ok()?;
+                Some(UNIX_EPOCH + Duration::from_secs(seconds))
+            });
+            if head.is_branch() {
+                status.branch = head.shorthand().map(ToOwned::to_owned);
+                status.tracking = tracking(&repo, &head);
+            }
+        }
+        // A freshly created shell has no commits, but HEAD still
+        // names the branch that the first commit will go on.
+        Err(_) => status.branch = unborn_branch(&repo),
+    }
+
+    Ok(status)
+}
+
+/// The commit that the repository at `path` has checked out, if it
+/// has any commits yet.
+pub fn head(path: &Path) -> Option<String> {
+    let repo = Repository::open(path).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+fn remote_url(repo: &Repository) -> Option<String> {
+    let names = repo.remotes().ok()?;
+    let name = match names.iter().flatten().collect::<Vec<_>>()[..] {
+        [only] => only.to_owned(),
+        _ => "origin".to_owned(),
+    };
+    let remote = repo.find_remote(&name).ok()?;
+    remote.url().map(ToOwned::to_owned)
+}
+
+fn unborn_branch(repo: &Repository) -> Option<String> {
+    let head = repo.find_reference("HEAD").ok()?;
+    let target = head.symbolic_target()?;
+    target.strip_prefix("refs/heads/").map(ToOwned::to_owned)
+}
+
+fn tracking(repo: &Repository, head: &git2::Reference<'_>) -> Option<Tracking> {
+    let branch_name = head.shorthand()?;
+    let branch = repo.find_branch(branch_name, BranchType::Local).ok()?;
+    let upstream = branch.upstream().ok()?;
+    let upstream_name = upstream.name().ok()??.to_owned();
+
+    let local_oid = head.target()?;
+    let upstream_oid = upstream.get().target()?;
+    let (ahead, behind) = repo.graph_ahead_behind(local_oid, upstream_oid).ok()?;
+
+    Some(Tracking {
+        upstream: upstream_name,
+        ahead,
+        behind,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use git2::{Repository, Signature};
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn commit_file(repo: &Repository, name: &str, contents: &str) {
+        let root = repo.workdir().unwrap();
+        fs::write(root.join(name), contents).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(name)).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("Geoff", "geoff@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(Some("HEAD"), &signature, &signature, name, &tree, &parents)
+            .unwrap();
+    }
+
+    #[test]
+    fn reports_staged_modified_and_untracked_files() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = Repository::init(test_root).unwrap();
+        commit_file(&repo, ".bashrc", "one");
+        commit_file(&repo, ".vimrc", "one");
+
+        fs::write(test_root.join(".bashrc"), "two").unwrap();
+        fs::write(test_root.join(".vimrc"), "two").unwrap();
+        repo.index()
+            .and_then(|mut index| {
+                index.add_path(Path::new(".vimrc"))?;
+                index.write()
+            })
+            .unwrap();
+        fs::write(test_root.join(".profile"), "new").unwrap();
+
+        let status = status(test_root).unwrap();
+
+        assert!(!status.is_clean());
+        assert_eq!(status.staged, vec![PathBuf::from(".vimrc")]);
+        assert_eq!(status.modified, vec![PathBuf::from(".bashrc")]);
+        assert_eq!(status.untracked, vec![PathBuf::from(".profile")]);
+        assert_eq!(status.tracking, None);
+        assert_eq!(status.remote_url, None);
+        assert!(status.last_commit.is_some());
+    }
+
+    #[test]
+    fn reports_the_branch_of_a_repo_without_commits() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let repo = Repository::init(test_root).unwrap();
+        repo.set_head("refs/heads/trunk").unwrap();
+
+        let status = status(test_root).unwrap();
+
+        assert_eq!(status.branch, Some("trunk".to_owned()));
+        assert_eq!(status.last_commit, None);
+    }
+
+    #[test]
+    fn reports_commits_ahead_of_and_behind_upstream() {
+        let remote_dir = set_up();
+        let remote = Repository::init(remote_dir.path()).unwrap();
+        commit_file(&remote, ".bashrc", "one");
+        let branch = remote.head().unwrap().shorthand().unwrap().to_owned();
+
+        let local_dir = set_up();
+        let url = format!("file://{}", remote_dir.path().display());
+        let local = Repository::clone(&url, local_dir.path().join("shell")).unwrap();
+        commit_file(&local, ".vimrc", "one");
+        commit_file(&local, ".profile", "one");
+        commit_file(&remote, ".emacs", "one");
+        local
+            .find_remote("origin")
+            .unwrap()
+            .fetch(&[&branch], None, None)
+            .unwrap();
+
+        let status = status(&local_dir.path().join("shell")).unwrap();
+
+        assert!(status.is_clean());
+        assert_eq!(status.remote_url, Some(url));
+        assert_eq!(status.branch, Some(branch.clone()));
+        assert_eq!(
+            status.tracking,
+            Some(Tracking {
+                upstream: format!("origin/{}", branch),
+                ahead: 2,
+                behind: 1,
+            })
+        );
+    }
+}
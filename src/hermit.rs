@@ -1,15 +1,131 @@
 use crate::common::*;
 
+use std::collections::HashSet;
+
+use crate::{
+    config::BASES_FILE_NAME,
+    file_operations::{normalize_path, Op},
+    history::{Batch, History},
+    manifest::MANIFEST_FILE_NAME,
+    secrets::Key,
+    shell::redacted_views,
+};
+
 #[derive(Clone, Debug, Error, PartialEq, Eq)]
 pub enum Error {
-    #[error("{0} subcommand has not been implemented yet")]
-    SubcommandNotImplemented(&'static str),
+    #[error("{0:?} is not a valid shell name, because {1}")]
+    InvalidShellName(String, &'static str),
 
     #[error("That is not the name of a shell")]
     ShellDoesNotExist,
 
     #[error("No shell is active right now")]
     NoActiveShell,
+
+    #[error("Shell {shell} is based on {base}, but there is no shell named {base}")]
+    MissingBaseShell { shell: String, base: String },
+
+    #[error("Shell {0} is based on itself")]
+    ShellBasedOnItself(String),
+
+    #[error("A shell named {0} already exists")]
+    ShellAlreadyExists(String),
+
+    #[error("Shell {shell} can't be nuked while {layer} is based on it")]
+    ShellIsABase { shell: String, layer: String },
+
+    #[error("Not nuking {0} without confirmation, use --force to skip asking")]
+    NukeNotConfirmed(String),
+
+    #[error("Not undoing without confirmation, use --yes to skip asking")]
+    UndoNotConfirmed,
+
+    #[error("There is no nuked shell named {0} left to restore")]
+    NothingToRestore(String),
+
+    #[error("{} is in the way of a link", .0.display())]
+    LinkConflict(PathBuf),
+
+    #[error("Could not run git: {0}")]
+    GitCouldNotRun(String),
+
+    #[error("git error: {0}")]
+    Git(String),
+
+    #[error("git exited with status {0}")]
+    GitExited(i32),
+
+    #[error("doctor found {0} problem(s)")]
+    DoctorFoundProblems(usize),
+
+    #[error("Could not work out a shell name from {0}, please give one")]
+    NoShellNameInUrl(String),
+
+    #[error("{} does not exist", .0.display())]
+    FileDoesNotExist(PathBuf),
+
+    #[error("{} is not inside your home directory", .0.display())]
+    FileNotInHome(PathBuf),
+
+    #[error("{} is inside your hermit directory", .0.display())]
+    FileInHermitRoot(PathBuf),
+
+    #[error("{} is a symbolic link, it may already be linked", .0.display())]
+    FileAlreadyLinked(PathBuf),
+
+    #[error("{} is already in your shell", .0.display())]
+    FileAlreadyInShell(PathBuf),
+
+    #[error("{} isn't included by your shell's hermit.toml, add it there first", .0.display())]
+    NotInManifest(PathBuf),
+
+    #[error("{} is not a text file, so it can't be redacted", .0.display())]
+    CannotRedact(PathBuf),
+
+    #[error("{} is not a redacted file in your shell, so its changes are already there", .0.display())]
+    NotRedacted(PathBuf),
+
+    #[error("Could not render {}: {1}", .0.display())]
+    CannotRender(PathBuf, String),
+
+    #[error("Could not read the machine values in {}: {1}", .0.display())]
+    BadMachineValues(PathBuf, String),
+
+    #[error("Could not read the manifest in {}: {1}", .0.display())]
+    BadManifest(PathBuf, String),
+
+    #[error("Could not read what hermit has linked from {}: {1}", .0.display())]
+    BadStateFile(PathBuf, String),
+
+    #[error("Could not read the history of changes in {}: {1}", .0.display())]
+    BadHistory(PathBuf, String),
+
+    #[error("There's nothing to undo")]
+    NothingToUndo,
+
+    #[error("There's no batch {0} to undo, see `hermit undo --list`")]
+    NoSuchBatch(u64),
+
+    #[error("Could not edit the file: {0}")]
+    EditorFailed(String),
+
+    #[error("The secrets for {} don't fit it any more", .0.display())]
+    SecretsDoNotApply(PathBuf),
+
+    #[error("Secrets are locked, run `hermit secrets unlock` first")]
+    SecretsLocked,
+
+    #[error("Could not decrypt the secrets for {}, they may be for another passphrase", .0.display())]
+    CannotDecryptSecrets(PathBuf),
+
+    #[error("That passphrase doesn't unlock the secrets you already have")]
+    WrongPassphrase,
+
+    #[error("The passphrases didn't match")]
+    PassphrasesDiffer,
+
+    #[error("Could not read the passphrase: {0}")]
+    PassphraseNotRead(String),
 }
 
 impl From<io::Error> for Error {
@@ -18,8 +134,17 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<git2::Error> for Error {
+    fn from(err: git2::Error) -> Error {
+        Error::Git(err.message().to_owned())
+    }
+}
+
 pub type Result<T> = result::Result<T, Error>;
 
+/// How long a nuked shell stays in the trash before it's purged.
+pub const TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
 pub struct Hermit<T: Config> {
     config: Rc<T>,
 }
@@ -31,59 +156,583 @@ impl<T: Config> Hermit<T> {
         }
     }
 
+    pub fn config(&self) -> &T {
+        &self.config
+    }
+
     pub fn current_shell(&self) -> Result<Shell<T>> {
         self.config
             .current_shell_name()
-            .map(|shell_name| Shell::new(shell_name, self.config.clone()))
+            .map(|shell_name| Shell::new(shell_name.clone(), self.config.clone()))
             .ok_or(Error::NoActiveShell)
     }
 
-    fn set_current_shell(&mut self, name: &str) -> Result<()> {
-        match Rc::get_mut(&mut self.config) {
-            Some(config) => config.set_current_shell_name(name).map_err(Error::from),
-            None => unreachable!(message::error_str(
-                "attempted to modify config while it was being used."
-            )),
+    pub fn shell(&self, name: &ShellName) -> Result<Shell<T>> {
+        if self.config.shell_exists(name) {
+            Ok(Shell::new(name.clone(), self.config.clone()))
+        } else {
+            Err(Error::ShellDoesNotExist)
         }
     }
 
-    pub fn init_shell(&mut self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
-        self.set_current_shell(name)?;
-        let new_shell = self.current_shell()?;
+    /// Creates a new shell called `name` and makes it the current
+    /// one. If there are `bases`, the new shell is layered on top of
+    /// them, in order.
+    pub fn init_shell(
+        &self,
+        file_ops: &mut FileOperations,
+        name: &ShellName,
+        bases: &[ShellName],
+    ) -> Result<()> {
+        for base in bases {
+            if !self.config.shell_exists(base) {
+                return Err(Error::MissingBaseShell {
+                    shell: name.to_string(),
+                    base: base.to_string(),
+                });
+            }
+        }
+
+        let new_shell = Shell::new(name.clone(), self.config.clone());
         let path = new_shell.root_path();
         let parent = path.parent().expect("Shell root path was too short");
         file_ops.create_dir(parent);
         file_ops.create_git_repo(&path);
+        if !bases.is_empty() {
+            let contents = bases
+                .iter()
+                .map(|base| format!("{}\n", base))
+                .collect::<String>();
+            file_ops.write_file(path.join(BASES_FILE_NAME), contents);
+        }
+        file_ops.write_file(self.config.current_shell_file(), name.as_str());
+        Ok(())
+    }
+
+    /// Clones the repository at `url` into a new shell. When no name
+    /// is given, one is derived from the last component of the url.
+    pub fn clone_shell(
+        &self,
+        file_ops: &mut FileOperations,
+        url: &str,
+        name: Option<&ShellName>,
+    ) -> Result<()> {
+        let name = match name {
+            Some(name) => name.clone(),
+            None => shell_name_from_url(url)?,
+        };
+        if self.config.shell_exists(&name) {
+            return Err(Error::ShellAlreadyExists(name.to_string()));
+        }
+
+        let path = self.config.shell_root_path().join(&name);
+        let parent = path.parent().expect("Shell root path was too short");
+        file_ops.create_dir(parent);
+        file_ops.clone_git_repo(url, &path);
         Ok(())
     }
 
-    pub fn inhabit(&mut self, file_ops: &mut FileOperations, name: &str) -> Result<()> {
+    /// Unlinks the current shell and links the shell called `name` in
+    /// its place, returning any paths that were skipped because
+    /// something was in the way. Only hermit's own links are removed
+    /// from the old shell unless `force` is set.
+    ///
+    /// The switch of current shell is queued along with the links, so
+    /// that it only happens if they do.
+    pub fn inhabit(
+        &self,
+        file_ops: &mut FileOperations,
+        name: &ShellName,
+        policy: ConflictPolicy,
+        force: bool,
+    ) -> Result<Vec<Conflict>> {
+        let new_shell = self.shell(name)?;
+        let mut skipped = match self.current_shell() {
+            Ok(shell) => shell.unlink(file_ops, force)?,
+            Err(_) => vec![],
+        };
+        for conflict in new_shell.link(file_ops, policy)? {
+            if !skipped.iter().any(|other| other.path == conflict.path) {
+                skipped.push(conflict);
+            }
+        }
+
+        file_ops.write_file(self.config.current_shell_file(), name.as_str());
+        Ok(skipped)
+    }
+
+    /// Moves a shell into the trash, unlinking it first if it's the
+    /// shell being inhabited. A shell that another shell is based on
+    /// can't be nuked. Anything in the trash older than
+    /// `TRASH_RETENTION` is purged along the way. Returns the paths
+    /// that weren't unlinked because they aren't hermit's links.
+    pub fn nuke(
+        &self,
+        file_ops: &mut FileOperations,
+        name: &ShellName,
+        now: SystemTime,
+    ) -> Result<Vec<Conflict>> {
+        let shell = self.shell(name)?;
+        let layer = self.config.shell_names().into_iter().find(|other| {
+            other != name
+                && self
+                    .config
+                    .base_shells(other)
+                    .is_ok_and(|bases| bases.contains(name))
+        });
+        if let Some(layer) = layer {
+            return Err(Error::ShellIsABase {
+                shell: name.to_string(),
+                layer: layer.to_string(),
+            });
+        }
+        let mut skipped = vec![];
+        if self.config.current_shell_name() == Some(name) {
+            skipped = shell.unlink(file_ops, false)?;
+            file_ops.remove(self.config.current_shell_file());
+        }
+
+        self.purge_trash(file_ops, now);
+        let trashed_at = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let trash_path = (0..)
+            .map(|count| {
+                let file_name = TrashedShell::file_name(name, trashed_at.as_secs(), count);
+                self.config.trash_root_path().join(file_name)
+            })
+            .find(|path| !path.exists())
+            .expect("one of the trash names is free");
+        file_ops.create_dir(self.config.trash_root_path());
+        file_ops.move_file(shell.root_path(), trash_path);
+        Ok(skipped)
+    }
+
+    /// Brings back the most recently nuked shell called `name`, as
+    /// long as it hasn't expired.
+    pub fn restore(
+        &self,
+        file_ops: &mut FileOperations,
+        name: &ShellName,
+        now: SystemTime,
+    ) -> Result<()> {
         if self.config.shell_exists(name) {
-            if let Ok(shell) = self.current_shell() {
-                shell.unlink(file_ops)
+            return Err(Error::ShellAlreadyExists(name.to_string()));
+        }
+
+        self.purge_trash(file_ops, now);
+        let (trash_path, _) = self
+            .trashed_shells()
+            .into_iter()
+            .filter(|(_, trashed)| trashed.name == name.as_str() && !trashed.has_expired(now))
+            .max_by_key(|(_, trashed)| (trashed.trashed_at, trashed.count))
+            .ok_or_else(|| Error::NothingToRestore(name.to_string()))?;
+
+        file_ops.move_file(trash_path, self.config.shell_root_path().join(name));
+        Ok(())
+    }
+
+    /// The batches of changes that can still be undone, oldest first.
+    pub fn history(&self) -> Result<Vec<Batch>> {
+        History::new(self.config.history_path()).batches()
+    }
+
+    /// Undoes the most recent batch of changes, or every batch back to
+    /// and including the one with `id`, newest first. Anything that
+    /// has changed since a batch is left alone, and so is anything that
+    /// depends on it. Returns the batches that will be undone, and the
+    /// operations that were skipped, not counting directories that
+    /// were only left because something else is in them now.
+    pub fn undo(
+        &self,
+        file_ops: &mut FileOperations,
+        id: Option<u64>,
+    ) -> Result<(Vec<Batch>, Vec<Op>)> {
+        let mut batches = self.history()?;
+        let oldest = match (id, batches.last()) {
+            (_, None) => return Err(Error::NothingToUndo),
+            (None, Some(newest)) => newest.id,
+            (Some(id), Some(_)) if batches.iter().any(|batch| batch.id == id) => id,
+            (Some(id), Some(_)) => return Err(Error::NoSuchBatch(id)),
+        };
+
+        batches.retain(|batch| batch.id >= oldest);
+        batches.reverse();
+        let state = State::load(&self.config.state_path())?;
+        let mut cleared = vec![];
+        let mut skipped = vec![];
+        for op in batches.iter().flat_map(|batch| batch.undo.clone()) {
+            if op.can_undo(&cleared) {
+                cleared.extend(op.removed_path().map(Path::to_path_buf));
+                file_ops.push(op);
+                continue;
+            }
+            // Whatever is at a file that's left alone stays as it is
+            // now, rather than as it was before the batch.
+            let path = match op {
+                Op::Link { ref path, .. }
+                | Op::RelativeLink { ref path, .. }
+                | Op::Move { to: ref path, .. }
+                | Op::Restore { to: ref path, .. }
+                | Op::RemoveLink { ref path, .. }
+                | Op::RemoveUnchanged { ref path, .. } => Some(path),
+                _ => None,
+            };
+            match path.map(|path| (path, state.get(path))) {
+                Some((_, Some(record))) => file_ops.record(record.clone()),
+                Some((path, None)) => file_ops.forget(path),
+                None => (),
+            }
+            if !matches!(op, Op::RemoveEmptyDir(_)) {
+                skipped.push(op);
+            }
+        }
+        file_ops.undoing(batches.clone());
+        Ok((batches, skipped))
+    }
+
+    fn purge_trash(&self, file_ops: &mut FileOperations, now: SystemTime) {
+        for (path, trashed) in self.trashed_shells() {
+            if trashed.has_expired(now) {
+                file_ops.remove_dir(path);
             }
+        }
+    }
 
-            self.set_current_shell(name)?;
+    fn trashed_shells(&self) -> Vec<(PathBuf, TrashedShell)> {
+        let entries = match fs::read_dir(self.config.trash_root_path()) {
+            Ok(entries) => entries,
+            Err(_) => return vec![],
+        };
+        entries
+            .filter_map(StdResult::ok)
+            .filter_map(|entry| {
+                let file_name = entry.file_name();
+                TrashedShell::parse(&file_name.to_string_lossy()).map(|t| (entry.path(), t))
+            })
+            .collect()
+    }
 
-            if let Ok(shell) = self.current_shell() {
-                shell.link(file_ops)
+    /// Moves the files at `paths` into the current shell, replacing
+    /// them with links. Directories are added file by file.
+    ///
+    /// The links are the kind the shell's manifest asks for, unless a
+    /// different `mode` is given, in which case the manifest is updated
+    /// to keep linking them that way.
+    ///
+    /// Every path is checked before any operations are queued, so a
+    /// single bad path means nothing gets added. Links found inside
+    /// directories are skipped instead, and returned.
+    pub fn add(
+        &self,
+        file_ops: &mut FileOperations,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        mode: Option<LinkMode>,
+    ) -> Result<Vec<PathBuf>> {
+        let shell = self.current_shell()?;
+        let manifest = Manifest::load(&shell.root_path())?;
+        let mut new_modes = vec![];
+        let mut skipped = vec![];
+        for (path, source) in self.addable_paths(&shell, file_ops.root(), paths, &mut skipped)? {
+            let usual_mode = manifest_mode(manifest.as_ref(), &source);
+            let mode = mode.unwrap_or(usual_mode);
+            if mode != usual_mode {
+                new_modes.push((source.clone(), mode));
+            }
+            shell.add(file_ops, path, source, mode);
+        }
+
+        if !new_modes.is_empty() {
+            let contents = manifest::with_modes(&shell.root_path(), &new_modes);
+            file_ops.write_file(shell.root_path().join(MANIFEST_FILE_NAME), contents);
+        }
+        Ok(skipped)
+    }
+
+    /// Adds the files at `paths` to the current shell with their
+    /// secrets taken out and encrypted. `redact` is given each file's
+    /// path and complete contents, and returns the redacted contents.
+    ///
+    /// Files that come back unchanged have no secrets, and are added
+    /// just like `add` would. Links found inside directories are
+    /// skipped and returned.
+    pub fn add_redacted(
+        &self,
+        file_ops: &mut FileOperations,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        mut redact: impl FnMut(&Path, &str) -> io::Result<String>,
+    ) -> Result<Vec<PathBuf>> {
+        let shell = self.current_shell()?;
+        let key = shell.secrets_key().ok_or(Error::SecretsLocked)?;
+        let manifest = Manifest::load(&shell.root_path())?;
+        let home = file_ops.root().to_path_buf();
+        let mut skipped = vec![];
+        for (path, source) in self.addable_paths(&shell, &home, paths, &mut skipped)? {
+            let file = home.join(&path);
+            let complete =
+                fs::read_to_string(&file).map_err(|_| Error::CannotRedact(file.clone()))?;
+            let redacted =
+                redact(&file, &complete).map_err(|err| Error::EditorFailed(err.to_string()))?;
+            if redacted == complete {
+                let mode = manifest_mode(manifest.as_ref(), &source);
+                shell.add(file_ops, path, source, mode);
+            } else {
+                shell.add_redacted(file_ops, path, source, &redacted, &complete, &key);
             }
-            Ok(())
-        } else {
-            Err(Error::ShellDoesNotExist)
         }
+        Ok(skipped)
+    }
+
+    /// Folds the changes made to redacted files in the home directory
+    /// back into the current shell. Each new change is shown to
+    /// `is_secret`, and the ones it picks are kept with the file's
+    /// secrets. If the secrets themselves were changed, the whole file
+    /// is handed to `redact` to be redacted again.
+    pub fn update(
+        &self,
+        file_ops: &mut FileOperations,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        mut is_secret: impl FnMut(&Path, &str) -> bool,
+        mut redact: impl FnMut(&Path, &str) -> io::Result<String>,
+    ) -> Result<()> {
+        let shell = self.current_shell()?;
+        let key = shell.secrets_key().ok_or(Error::SecretsLocked)?;
+        let home = file_ops.root().to_path_buf();
+        for path in paths {
+            let file = normalize_path(path.as_ref());
+            let relative_path = file
+                .strip_prefix(&home)
+                .map_err(|_| Error::FileNotInHome(file.clone()))?;
+            let complete =
+                fs::read_to_string(&file).map_err(|_| Error::FileDoesNotExist(file.clone()))?;
+            let layered_file = shell
+                .files()?
+                .into_iter()
+                .find(|layered_file| layered_file.path == relative_path)
+                .ok_or_else(|| Error::NotRedacted(file.clone()))?;
+            let layer = Shell::new(layered_file.layer, self.config.clone());
+            let (redacted, secrets) =
+                redacted_views(&layer.root_path().join(&layered_file.source), Some(&key))?
+                    .ok_or_else(|| Error::NotRedacted(file.clone()))?;
+            if redact::complete(&redacted, &secrets).as_ref() == Some(&complete) {
+                continue;
+            }
+
+            let folded = redact::fold_changes(&redacted, &secrets, &complete, |change| {
+                is_secret(&file, change)
+            });
+            let redacted = match folded {
+                Some(redacted) => redacted,
+                None => {
+                    redact(&file, &complete).map_err(|err| Error::EditorFailed(err.to_string()))?
+                }
+            };
+            layer.write_redacted(file_ops, &layered_file.source, &redacted, &complete, &key);
+        }
+        Ok(())
+    }
+
+    /// The salt that the secrets of every shell have been encrypted
+    /// with so far, if any have been. Unlocking them must then use the
+    /// same passphrase.
+    pub fn secrets_salt(&self) -> Option<secrets::Salt> {
+        self.encrypted_secrets()
+            .iter()
+            .find_map(|contents| secrets::salt_of(contents))
+    }
+
+    /// Derives the key for encrypted secrets from `passphrase` and
+    /// keeps it in the hermit root until `lock_secrets`. The passphrase
+    /// is checked against the existing secrets, if there are any.
+    pub fn unlock_secrets(&self, file_ops: &mut FileOperations, passphrase: &str) -> Result<()> {
+        let existing = self.encrypted_secrets();
+        let salt = existing
+            .iter()
+            .find_map(|contents| secrets::salt_of(contents));
+        let key = Key::derive(passphrase, salt.unwrap_or_else(Key::new_salt));
+        if salt.is_some()
+            && !existing
+                .iter()
+                .any(|contents| key.decrypt(contents).is_some())
+        {
+            return Err(Error::WrongPassphrase);
+        }
+        let key_path = self.config.secrets_key_path();
+        if key_path.exists() {
+            file_ops.discard(&key_path);
+        }
+        file_ops.write_private(key_path, key.to_file_contents());
+        Ok(())
+    }
+
+    fn encrypted_secrets(&self) -> Vec<String> {
+        WalkDir::new(self.config.shell_root_path())
+            .into_iter()
+            .filter_entry(|entry| entry.file_name() != ".git")
+            .filter_map(StdResult::ok)
+            .filter(|entry| redact::is_secrets_file(entry.path()))
+            .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+            .filter(|contents| secrets::is_encrypted(contents))
+            .collect()
+    }
+
+    /// Forgets the key for encrypted secrets, without keeping a copy
+    /// that undo could bring back. Files that were already generated
+    /// from them are left as they are.
+    pub fn lock_secrets(&self, file_ops: &mut FileOperations) {
+        let key_path = self.config.secrets_key_path();
+        if key_path.exists() {
+            file_ops.discard(key_path);
+        }
+    }
+
+    /// Expands directories in `paths` and checks that every file can
+    /// be added to `shell`, returning them relative to `home` along
+    /// with where each belongs in the shell. Paths given more than once
+    /// are only added once, and links inside directories are pushed
+    /// onto `skipped` instead.
+    fn addable_paths(
+        &self,
+        shell: &Shell<T>,
+        home: &Path,
+        paths: impl IntoIterator<Item = impl AsRef<Path>>,
+        skipped: &mut Vec<PathBuf>,
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut files = vec![];
+        for path in paths {
+            let path = normalize_path(path.as_ref());
+            if path.is_dir() {
+                let entries = WalkDir::new(&path).follow_links(false).into_iter();
+                for entry in entries.filter_map(StdResult::ok) {
+                    if entry.path_is_symlink() {
+                        skipped.push(entry.into_path());
+                    } else if !entry.file_type().is_dir() {
+                        files.push(entry.into_path());
+                    }
+                }
+            } else {
+                files.push(path);
+            }
+        }
+        let mut seen = HashSet::new();
+        files.retain(|file| seen.insert(file.clone()));
+
+        let manifest = Manifest::load(&shell.root_path())?;
+        files
+            .iter()
+            .map(|file| self.addable_path(shell, manifest.as_ref(), home, file))
+            .collect()
+    }
+
+    fn addable_path(
+        &self,
+        shell: &Shell<T>,
+        manifest: Option<&Manifest>,
+        home: &Path,
+        file: &Path,
+    ) -> Result<(PathBuf, PathBuf)> {
+        let relative_path = file
+            .strip_prefix(home)
+            .map_err(|_| Error::FileNotInHome(file.to_path_buf()))?;
+        if file.starts_with(self.config.root_path()) {
+            return Err(Error::FileInHermitRoot(file.to_path_buf()));
+        }
+
+        let metadata =
+            fs::symlink_metadata(file).map_err(|_| Error::FileDoesNotExist(file.to_path_buf()))?;
+        if metadata.file_type().is_symlink() {
+            return Err(Error::FileAlreadyLinked(file.to_path_buf()));
+        }
+        let source = manifest::source_path(manifest, relative_path);
+        let shell_path = shell.root_path().join(&source);
+        if shell_path.exists() || template::template_path(&shell_path).exists() {
+            return Err(Error::FileAlreadyInShell(file.to_path_buf()));
+        }
+        if manifest.is_some_and(|manifest| !manifest.includes(&source)) {
+            return Err(Error::NotInManifest(file.to_path_buf()));
+        }
+
+        Ok((relative_path.to_path_buf(), source))
     }
 }
 
+/// How the `manifest` of a shell, if it has one, says the shell file
+/// at `path` should be put into the home directory.
+fn manifest_mode(manifest: Option<&Manifest>, path: &Path) -> LinkMode {
+    manifest
+        .map(|manifest| manifest.mode(path))
+        .unwrap_or_default()
+}
+
+/// A shell in the trash, named `<shell name>.<seconds since epoch>`.
+/// Shells with the same name that were nuked in the same second are
+/// told apart by a count, as in `<shell name>.<seconds>-<count>`.
+struct TrashedShell {
+    name: String,
+    trashed_at: SystemTime,
+    count: u32,
+}
+
+impl TrashedShell {
+    fn file_name(name: &ShellName, secs: u64, count: u32) -> String {
+        match count {
+            0 => format!("{}.{}", name, secs),
+            _ => format!("{}.{}-{}", name, secs, count),
+        }
+    }
+
+    fn parse(file_name: &str) -> Option<TrashedShell> {
+        let (name, suffix) = file_name.rsplit_once('.')?;
+        let (secs, count) = match suffix.split_once('-') {
+            Some((secs, count)) => (secs, count.parse().ok()?),
+            None => (suffix, 0),
+        };
+        let secs = secs.parse().ok()?;
+        Some(TrashedShell {
+            name: name.to_owned(),
+            trashed_at: UNIX_EPOCH + Duration::from_secs(secs),
+            count,
+        })
+    }
+
+    fn has_expired(&self, now: SystemTime) -> bool {
+        now.duration_since(self.trashed_at)
+            .map(|age| age > TRASH_RETENTION)
+            .unwrap_or(false)
+    }
+}
+
+/// Takes the last path component of a git url, e.g. `dotfiles` from
+/// both `https://example.com/me/dotfiles.git` and
+/// `git@example.com:me/dotfiles`.
+fn shell_name_from_url(url: &str) -> Result<ShellName> {
+    let name = url
+        .trim_end_matches('/')
+        .rsplit(['/', ':'])
+        .next()
+        .map(|name| name.trim_end_matches(".git"))
+        .filter(|name| !name.is_empty())
+        .ok_or_else(|| Error::NoShellNameInUrl(url.to_owned()))?;
+    ShellName::new(name)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
-    use std::{path::PathBuf, rc::Rc};
+    use std::{
+        fs,
+        os::unix,
+        path::PathBuf,
+        rc::Rc,
+        time::{Duration, SystemTime, UNIX_EPOCH},
+    };
 
     use crate::{
-        config::mock::MockConfig, config::Config, file_operations::FileOperations,
-        file_operations::Op, test_helpers::ops::*,
+        config::mock::MockConfig,
+        file_operations::{FileOperations, Op, DEFAULT_FILE_MODE, PRIVATE_FILE_MODE},
+        state::{self, Change, Record, Strategy},
+        test_helpers::filesystem::set_up,
+        test_helpers::ops::*,
+        test_helpers::shell_name,
     };
 
     fn hermit(config: &MockConfig) -> Hermit<MockConfig> {
@@ -101,28 +750,28 @@ mod tests {
     }
 
     #[test]
-    fn can_set_the_current_shell() {
-        let mut config = MockConfig::new();
-        config
-            .set_current_shell_name("current")
-            .expect("Setting shell name failed");
-        let mut hermit = hermit(&config);
+    fn returns_a_shell_by_name() {
+        let config = MockConfig::new();
+        let hermit = hermit(&config);
 
-        assert_eq!(hermit.current_shell().unwrap().name, "current");
-        hermit
-            .set_current_shell("default")
-            .expect("Setting shell name failed");
-        assert_eq!(hermit.current_shell().unwrap().name, "default");
+        assert_eq!(
+            hermit.shell(&shell_name("default")).unwrap().name,
+            "default"
+        );
+        assert_eq!(
+            hermit.shell(&shell_name("nonexistent")).err(),
+            Some(Error::ShellDoesNotExist)
+        );
     }
 
     #[test]
     fn can_initialize_a_new_shell() {
         let config = MockConfig::with_root(".hermit-config");
-        let mut hermit = hermit(&config);
+        let hermit = hermit(&config);
         let mut file_ops = FileOperations::rooted_at("/home/geoff");
 
         hermit
-            .init_shell(&mut file_ops, "new-one")
+            .init_shell(&mut file_ops, &shell_name("new-one"), &[])
             .expect("Init shell failed");
         let first_op = &file_ops.operations()[0];
         assert_eq!(
@@ -134,30 +783,1000 @@ mod tests {
             *second_op,
             Op::GitInit(PathBuf::from("/home/geoff/.hermit-config/shells/new-one"))
         );
+        let third_op = &file_ops.operations()[2];
+        assert_eq!(
+            *third_op,
+            Op::WriteFile {
+                path: PathBuf::from("/home/geoff/.hermit-config/current_shell"),
+                contents: "new-one".to_owned(),
+                mode: DEFAULT_FILE_MODE,
+            }
+        );
+    }
+
+    #[test]
+    fn can_initialize_a_shell_layered_on_others() {
+        let mut config = MockConfig::with_root(".hermit-config");
+        config.add_shell("team-base", vec![".bashrc"]);
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .init_shell(
+                &mut file_ops,
+                &shell_name("work"),
+                &[shell_name("team-base"), shell_name("default")],
+            )
+            .expect("Init shell failed");
+
+        assert_eq!(
+            file_ops.operations()[2],
+            Op::WriteFile {
+                path: PathBuf::from("/home/geoff/.hermit-config/shells/work/.hbases"),
+                contents: "team-base\ndefault\n".to_owned(),
+                mode: DEFAULT_FILE_MODE,
+            }
+        );
+
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let result = hermit.init_shell(&mut file_ops, &shell_name("work"), &[shell_name("nope")]);
+        assert_eq!(
+            result,
+            Err(Error::MissingBaseShell {
+                shell: "work".to_owned(),
+                base: "nope".to_owned(),
+            })
+        );
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn can_clone_a_new_shell() {
+        let config = MockConfig::with_root("/home/geoff/.hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .clone_shell(&mut file_ops, "file:///srv/git/dotfiles.git", None)
+            .expect("Clone shell failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::MkDir(PathBuf::from("/home/geoff/.hermit-config/shells")),
+                Op::GitClone {
+                    url: "file:///srv/git/dotfiles.git".to_owned(),
+                    path: PathBuf::from("/home/geoff/.hermit-config/shells/dotfiles"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn can_clone_a_shell_with_a_given_name() {
+        let config = MockConfig::with_root("/home/geoff/.hermit-config");
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        hermit
+            .clone_shell(
+                &mut file_ops,
+                "git@example.com:me/dotfiles",
+                Some(&shell_name("work")),
+            )
+            .expect("Clone shell failed");
+
+        assert_eq!(
+            file_ops.operations()[1],
+            Op::GitClone {
+                url: "git@example.com:me/dotfiles".to_owned(),
+                path: PathBuf::from("/home/geoff/.hermit-config/shells/work"),
+            }
+        );
+    }
+
+    #[test]
+    fn cannot_clone_over_an_existing_shell() {
+        let config = MockConfig::new();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        let result = hermit.clone_shell(&mut file_ops, "https://example.com/default/", None);
+
+        assert_eq!(result, Err(Error::ShellAlreadyExists("default".to_owned())));
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn derives_shell_names_from_urls() {
+        assert_eq!(
+            shell_name_from_url("https://example.com/me/dotfiles.git"),
+            Ok(shell_name("dotfiles"))
+        );
+        assert_eq!(
+            shell_name_from_url("git@example.com:dotfiles"),
+            Ok(shell_name("dotfiles"))
+        );
+        assert_eq!(
+            shell_name_from_url("file:///srv/git/work/"),
+            Ok(shell_name("work"))
+        );
+        assert_eq!(
+            shell_name_from_url("/"),
+            Err(Error::NoShellNameInUrl("/".to_owned()))
+        );
     }
 
     #[test]
     fn can_inhabit_and_change_shells() {
-        let hermit_root = PathBuf::from(".hermit-config");
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
         let mut config = MockConfig::with_root(&hermit_root);
         config.set_paths(vec![".bashrc", ".boot/profile.boot"]);
-        let mut hermit = hermit(&config);
-        let op_root_path = PathBuf::from("/home/geoff");
-        let mut file_ops = FileOperations::rooted_at(&op_root_path);
+        let hermit = hermit(&config);
+        let shell_root = hermit_root.join("shells/default");
+        unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
 
-        hermit
-            .inhabit(&mut file_ops, "default")
+        let skipped = hermit
+            .inhabit(
+                &mut file_ops,
+                &shell_name("default"),
+                ConflictPolicy::Abort,
+                false,
+            )
             .expect("Inhabit failed");
 
-        let new_shell_root = hermit_root.join("shells/default");
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: shell_root.clone(),
+                },
+                link_op_for(&shell_root, home, ".bashrc"),
+                Op::MkDir(home.join(".boot")),
+                link_op_for(&shell_root, home, ".boot/profile.boot"),
+                Op::WriteFile {
+                    path: hermit_root.join("current_shell"),
+                    contents: "default".to_owned(),
+                    mode: DEFAULT_FILE_MODE,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn inhabiting_leaves_real_files_of_the_old_shell_alone() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit-config"));
+        config.set_paths(vec![".bashrc"]);
+        let hermit = hermit(&config);
+        fs::write(home.join(".bashrc"), "mine").unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = hermit
+            .inhabit(
+                &mut file_ops,
+                &shell_name("default"),
+                ConflictPolicy::Skip,
+                false,
+            )
+            .expect("Inhabit failed");
+
+        assert_eq!(
+            skipped,
+            vec![Conflict {
+                path: PathBuf::from(".bashrc"),
+                state: LinkState::Blocked,
+            }]
+        );
+        assert!(!file_ops.will_remove(".bashrc"));
+    }
+
+    #[test]
+    fn can_add_files_to_the_current_shell() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::write(home.join(".bashrc"), "").unwrap();
+
+        hermit
+            .add(&mut file_ops, vec![home.join("somewhere/../.bashrc")], None)
+            .expect("Add failed");
+
+        let shell_root = hermit_root.join("shells/default");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::MkDir(shell_root.clone()),
+                Op::Move {
+                    from: home.join(".bashrc"),
+                    to: shell_root.join(".bashrc"),
+                },
+                link_op_for(&shell_root, home, ".bashrc"),
+            ]
+        );
+    }
+
+    #[test]
+    fn undoing_reverts_batches_back_to_the_one_asked_for() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let history_path = hermit_root.join("history");
+        for file in &[".bashrc", ".vimrc"] {
+            fs::write(home.join(file), *file).unwrap();
+            let mut file_ops = FileOperations::rooted_at(home);
+            file_ops.keep_history(&history_path, format!("add {}", file));
+            hermit
+                .add(&mut file_ops, vec![home.join(file)], None)
+                .expect("Add failed");
+            file_ops.commit_atomic().expect("Commit failed");
+        }
+        let mut file_ops = FileOperations::rooted_at(home);
+        assert_eq!(
+            hermit.undo(&mut file_ops, Some(3)),
+            Err(Error::NoSuchBatch(3))
+        );
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_history(&history_path, "undo 1");
+        let (undone, skipped) = hermit.undo(&mut file_ops, Some(1)).expect("Undo failed");
+        assert_eq!(
+            undone.iter().map(|batch| batch.id).collect::<Vec<_>>(),
+            vec![2, 1]
+        );
+        assert_eq!(skipped, vec![]);
+        file_ops.commit_atomic().expect("Commit failed");
+
+        for file in &[".bashrc", ".vimrc"] {
+            assert_eq!(fs::read_to_string(home.join(file)).unwrap(), *file);
+        }
+        assert!(!hermit_root.join("shells/default").exists());
+        let mut file_ops = FileOperations::rooted_at(home);
+        assert_eq!(hermit.undo(&mut file_ops, None), Err(Error::NothingToUndo));
+    }
+
+    #[test]
+    fn undoing_leaves_shells_with_new_commits_alone() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let history_path = hermit_root.join("history");
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_history(&history_path, "init work");
+        hermit
+            .init_shell(&mut file_ops, &shell_name("work"), &[])
+            .expect("Init shell failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        let shell_root = hermit_root.join("shells/work");
+        let repo = git2::Repository::open(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "").unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new(".bashrc")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("Geoff", "geoff@example.com").unwrap();
+        repo.commit(Some("HEAD"), &signature, &signature, "Add", &tree, &[])
+            .unwrap();
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_history(&history_path, "undo");
+        let (_, skipped) = hermit.undo(&mut file_ops, None).expect("Undo failed");
+        assert_eq!(
+            skipped,
+            vec![Op::RemoveRepo {
+                path: shell_root.clone(),
+                head: None,
+            }]
+        );
+        file_ops.commit_atomic().expect("Commit failed");
+
+        assert!(shell_root.join(".git").is_dir());
+        assert!(shell_root.join(".bashrc").is_file());
+        assert!(!hermit_root.join("current_shell").exists());
+    }
+
+    #[test]
+    fn adding_a_directory_adds_each_file_in_it() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit = hermit(&MockConfig::with_root(home.join(".hermit-config")));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::create_dir_all(home.join(".boot/inner")).unwrap();
+        fs::write(home.join(".boot/inner/profile.boot"), "").unwrap();
+
+        hermit
+            .add(&mut file_ops, vec![home.join(".boot")], None)
+            .expect("Add failed");
+
+        assert_eq!(file_ops.operations().len(), 3);
+        assert_eq!(
+            file_ops.operations()[1],
+            Op::Move {
+                from: home.join(".boot/inner/profile.boot"),
+                to: home.join(".hermit-config/shells/default/.boot/inner/profile.boot"),
+            }
+        );
+    }
+
+    #[test]
+    fn adding_skips_links_inside_directories_and_repeated_paths() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit = hermit(&MockConfig::with_root(home.join(".hermit-config")));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::create_dir_all(home.join(".boot")).unwrap();
+        fs::write(home.join(".boot/profile.boot"), "").unwrap();
+        unix::fs::symlink("/somewhere", home.join(".boot/link")).unwrap();
+
+        let skipped = hermit
+            .add(
+                &mut file_ops,
+                vec![home.join(".boot"), home.join(".boot/profile.boot")],
+                None,
+            )
+            .expect("Add failed");
+
+        assert_eq!(skipped, vec![home.join(".boot/link")]);
+        let moves = file_ops
+            .operations()
+            .iter()
+            .filter(|op| matches!(op, Op::Move { .. }))
+            .count();
+        assert_eq!(moves, 1);
+    }
+
+    #[test]
+    fn cannot_add_files_outside_of_home() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit = hermit(&MockConfig::with_root(home.join(".hermit-config")));
+        let mut file_ops = FileOperations::rooted_at(home);
+        let outside = home.join("../outside");
+
+        let result = hermit.add(&mut file_ops, vec![&outside], None);
+
+        assert_eq!(
+            result,
+            Err(Error::FileNotInHome(home.parent().unwrap().join("outside")))
+        );
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn cannot_add_files_that_are_already_linked() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit = hermit(&MockConfig::with_root(home.join(".hermit-config")));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::write(home.join(".vimrc"), "").unwrap();
+        std::os::unix::fs::symlink(home.join(".vimrc"), home.join(".bashrc")).unwrap();
+
+        let result = hermit.add(
+            &mut file_ops,
+            vec![home.join(".vimrc"), home.join(".bashrc")],
+            None,
+        );
+
+        assert_eq!(result, Err(Error::FileAlreadyLinked(home.join(".bashrc"))));
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn cannot_add_files_that_do_not_exist() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit = hermit(&MockConfig::with_root(home.join(".hermit-config")));
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let result = hermit.add(&mut file_ops, vec![home.join(".missing")], None);
+
+        assert_eq!(result, Err(Error::FileDoesNotExist(home.join(".missing"))));
+    }
+
+    #[test]
+    fn cannot_add_files_that_the_manifest_leaves_out() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::create_dir_all(hermit_root.join("shells/default")).unwrap();
+        fs::write(
+            hermit_root.join("shells/default/hermit.toml"),
+            "include = [\".bashrc\"]\n",
+        )
+        .unwrap();
+        fs::write(home.join(".bashrc"), "").unwrap();
+        fs::write(home.join(".vimrc"), "").unwrap();
+
+        let result = hermit.add(
+            &mut file_ops,
+            vec![home.join(".bashrc"), home.join(".vimrc")],
+            None,
+        );
+
+        assert_eq!(result, Err(Error::NotInManifest(home.join(".vimrc"))));
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn adding_files_another_way_records_it_in_the_manifest() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::write(home.join(".vimrc"), "").unwrap();
+        let shell_root = hermit_root.join("shells/default");
+
+        hermit
+            .add(
+                &mut file_ops,
+                vec![home.join(".vimrc")],
+                Some(LinkMode::Copy),
+            )
+            .expect("Add failed");
+
+        let operations = file_ops.operations();
+        assert_eq!(
+            operations[2],
+            Op::Copy {
+                from: shell_root.join(".vimrc"),
+                to: home.join(".vimrc"),
+            }
+        );
+        match &operations[3] {
+            Op::WriteFile { path, contents, .. } => {
+                assert_eq!(*path, shell_root.join("hermit.toml"));
+                let manifest = Manifest::parse(path, contents).unwrap();
+                assert_eq!(manifest.mode(Path::new(".vimrc")), LinkMode::Copy);
+                assert!(manifest.includes(Path::new(".bashrc")));
+            }
+            other => panic!("Expected the manifest to be written, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn adding_files_puts_them_where_the_manifest_maps_them() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let mut file_ops = FileOperations::rooted_at(home);
+        let shell_root = hermit_root.join("shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "dot_prefix = true\n\
+             include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \"vscode\"\n\
+             target = \".config/Code/User\"\n",
+        )
+        .unwrap();
+        fs::create_dir_all(home.join(".config/Code/User")).unwrap();
+        fs::write(home.join(".config/Code/User/settings.json"), "{}").unwrap();
+        fs::write(home.join(".vimrc"), "").unwrap();
+
+        hermit
+            .add(
+                &mut file_ops,
+                vec![
+                    home.join(".config/Code/User/settings.json"),
+                    home.join(".vimrc"),
+                ],
+                None,
+            )
+            .expect("Add failed");
+
+        let operations = file_ops.operations();
+        assert_eq!(
+            operations[1],
+            Op::Move {
+                from: home.join(".config/Code/User/settings.json"),
+                to: shell_root.join("vscode/settings.json"),
+            }
+        );
+        assert_eq!(
+            operations[5],
+            Op::Link {
+                path: home.join(".vimrc"),
+                target: shell_root.join("dot_vimrc"),
+            }
+        );
+    }
+
+    fn unlocked(hermit_root: &Path) -> Key {
+        let key = Key::derive("correct horse", Key::new_salt());
+        fs::create_dir_all(hermit_root).unwrap();
+        fs::write(hermit_root.join("secrets.key"), key.to_file_contents()).unwrap();
+        key
+    }
+
+    #[test]
+    fn can_add_a_file_with_its_secrets_redacted() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let key = unlocked(&hermit_root);
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::write(
+            home.join(".netrc"),
+            "machine example.com\npassword hunter2\n",
+        )
+        .unwrap();
+
+        hermit
+            .add_redacted(&mut file_ops, vec![home.join(".netrc")], |_, complete| {
+                Ok(complete.replace("password hunter2\n", ""))
+            })
+            .expect("Add failed");
+
+        let shell_root = hermit_root.join("shells/default");
+        let operations = file_ops.operations();
+        assert_eq!(operations.len(), 4);
+        assert_eq!(operations[0], Op::MkDir(shell_root.clone()));
+        match &operations[1] {
+            Op::WriteFile { path, contents, .. } => {
+                assert_eq!(*path, shell_root.join(".netrc.hermit-secrets"));
+                assert!(!contents.contains("hunter2"));
+                assert_eq!(
+                    key.decrypt(contents),
+                    Some(redact::secrets(
+                        "machine example.com\n",
+                        "machine example.com\npassword hunter2\n",
+                    ))
+                );
+            }
+            other => panic!("Expected the secrets to be written, not {:?}", other),
+        }
+        assert_eq!(
+            operations[2],
+            Op::WriteFile {
+                path: shell_root.join(".netrc"),
+                contents: "machine example.com\n".to_owned(),
+                mode: DEFAULT_FILE_MODE,
+            }
+        );
+        let complete = "machine example.com\npassword hunter2\n";
+        assert_eq!(
+            operations[3],
+            Op::WriteFile {
+                path: home.join(".netrc"),
+                contents: complete.to_owned(),
+                mode: PRIVATE_FILE_MODE,
+            }
+        );
+        assert_eq!(
+            file_ops.changes().collect::<Vec<_>>(),
+            vec![&Change::Record(Record {
+                path: home.join(".netrc"),
+                source: shell_root.join(".netrc"),
+                strategy: Strategy::Generated,
+                hash: Some(state::hash(complete.as_bytes())),
+            })]
+        );
+    }
+
+    #[test]
+    fn adding_a_file_without_secrets_links_it() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        unlocked(&hermit_root);
+        let hermit = hermit(&MockConfig::with_root(&hermit_root));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::write(home.join(".bashrc"), "alias ll='ls -l'\n").unwrap();
+
+        hermit
+            .add_redacted(&mut file_ops, vec![home.join(".bashrc")], |_, complete| {
+                Ok(complete.to_owned())
+            })
+            .expect("Add failed");
+
+        let mut plain_ops = FileOperations::rooted_at(home);
+        hermit
+            .add(&mut plain_ops, vec![home.join(".bashrc")], None)
+            .expect("Add failed");
+        assert_eq!(file_ops.operations(), plain_ops.operations());
+    }
+
+    #[test]
+    fn cannot_redact_while_secrets_are_locked() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit = hermit(&MockConfig::with_root(home.join(".hermit-config")));
+        let mut file_ops = FileOperations::rooted_at(home);
+        fs::write(home.join(".netrc"), "password hunter2\n").unwrap();
+
+        let result = hermit.add_redacted(&mut file_ops, vec![home.join(".netrc")], |_, _| {
+            panic!("The editor shouldn't be opened")
+        });
+
+        assert_eq!(result, Err(Error::SecretsLocked));
+    }
+
+    fn redacted_netrc(hermit_root: &Path, home: &Path, key: &Key) {
+        let shell_root = hermit_root.join("shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        let redacted = "machine example.com\n";
+        let complete = "machine example.com\npassword hunter2\n";
+        fs::write(shell_root.join(".netrc"), redacted).unwrap();
+        fs::write(
+            shell_root.join(".netrc.hermit-secrets"),
+            key.encrypt(&redact::secrets(redacted, complete)),
+        )
+        .unwrap();
+        fs::write(home.join(".netrc"), complete).unwrap();
+    }
+
+    #[test]
+    fn updating_folds_changes_back_into_the_shell() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let key = unlocked(&hermit_root);
+        redacted_netrc(&hermit_root, home, &key);
+        let complete = "machine example.com\npassword hunter2\n\
+                        machine example.org\npassword swordfish\n";
+        fs::write(home.join(".netrc"), complete).unwrap();
+        let mut config = MockConfig::with_root(&hermit_root);
+        config.set_paths(vec![".netrc", ".bashrc"]);
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        hermit
+            .update(
+                &mut file_ops,
+                vec![home.join(".netrc")],
+                |_, change| change.contains("swordfish"),
+                |_, _| panic!("The editor shouldn't be opened"),
+            )
+            .expect("Update failed");
+
+        let shell_root = hermit_root.join("shells/default");
+        let operations = file_ops.operations();
+        assert_eq!(operations.len(), 2);
+        match &operations[0] {
+            Op::WriteFile { path, contents, .. } => {
+                assert_eq!(*path, shell_root.join(".netrc.hermit-secrets"));
+                let secrets = key.decrypt(contents).unwrap();
+                assert_eq!(
+                    redact::complete("machine example.com\n", &secrets).as_deref(),
+                    Some(complete)
+                );
+            }
+            other => panic!("Expected the secrets to be written, not {:?}", other),
+        }
+        assert_eq!(
+            operations[1],
+            Op::WriteFile {
+                path: shell_root.join(".netrc"),
+                contents: "machine example.com\n".to_owned(),
+                mode: DEFAULT_FILE_MODE,
+            }
+        );
+    }
+
+    #[test]
+    fn updating_re_redacts_files_whose_secrets_changed() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let key = unlocked(&hermit_root);
+        redacted_netrc(&hermit_root, home, &key);
+        fs::write(
+            home.join(".netrc"),
+            "machine example.com\npassword hunter3\n",
+        )
+        .unwrap();
+        let mut config = MockConfig::with_root(&hermit_root);
+        config.set_paths(vec![".netrc", ".bashrc"]);
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        hermit
+            .update(
+                &mut file_ops,
+                vec![home.join(".netrc")],
+                |_, _| panic!("Nothing should be asked about"),
+                |_, complete| Ok(complete.replace("password hunter3\n", "")),
+            )
+            .expect("Update failed");
+
+        assert_eq!(
+            file_ops.operations()[1],
+            Op::WriteFile {
+                path: hermit_root.join("shells/default/.netrc"),
+                contents: "machine example.com\n".to_owned(),
+                mode: DEFAULT_FILE_MODE,
+            }
+        );
+    }
+
+    #[test]
+    fn updating_leaves_unchanged_and_unredacted_files_alone() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit-config");
+        let key = unlocked(&hermit_root);
+        redacted_netrc(&hermit_root, home, &key);
+        fs::write(hermit_root.join("shells/default/.bashrc"), "").unwrap();
+        fs::write(home.join(".bashrc"), "").unwrap();
+        let mut config = MockConfig::with_root(&hermit_root);
+        config.set_paths(vec![".netrc", ".bashrc"]);
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let never_secret = |_: &Path, _: &str| false;
+        let no_editor = |_: &Path, _: &str| -> io::Result<String> { unreachable!() };
+        hermit
+            .update(
+                &mut file_ops,
+                vec![home.join(".netrc")],
+                never_secret,
+                no_editor,
+            )
+            .expect("Update failed");
+        let result = hermit.update(
+            &mut file_ops,
+            vec![home.join(".bashrc")],
+            never_secret,
+            no_editor,
+        );
+
+        assert!(file_ops.operations().is_empty());
+        assert_eq!(result, Err(Error::NotRedacted(home.join(".bashrc"))));
+    }
+
+    #[test]
+    fn unlocking_checks_the_passphrase_against_existing_secrets() {
+        let test_root_dir = set_up();
+        let hermit_root = test_root_dir.path();
+        let shell_root = hermit_root.join("shells/default");
+        fs::create_dir_all(&shell_root).unwrap();
+        let key = Key::derive("correct horse", Key::new_salt());
+        fs::write(shell_root.join(".netrc.hermit-secrets"), key.encrypt("")).unwrap();
+        let hermit = hermit(&MockConfig::with_root(hermit_root));
+        let mut file_ops = FileOperations::rooted_at(hermit_root);
+
+        assert_eq!(
+            hermit.unlock_secrets(&mut file_ops, "battery staple"),
+            Err(Error::WrongPassphrase)
+        );
+        hermit
+            .unlock_secrets(&mut file_ops, "correct horse")
+            .expect("Unlock failed");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::WriteFile {
+                path: hermit_root.join("secrets.key"),
+                contents: key.to_file_contents(),
+                mode: PRIVATE_FILE_MODE,
+            }]
+        );
+    }
+
+    #[test]
+    fn locking_forgets_the_key() {
+        let test_root_dir = set_up();
+        let hermit_root = test_root_dir.path();
+        unlocked(hermit_root);
+        let hermit = hermit(&MockConfig::with_root(hermit_root));
+        let mut file_ops = FileOperations::rooted_at(hermit_root);
+
+        hermit.lock_secrets(&mut file_ops);
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Discard(hermit_root.join("secrets.key"))]
+        );
+    }
+
+    #[test]
+    fn locking_leaves_no_copy_of_the_key_to_undo() {
+        let test_root_dir = set_up();
+        let hermit_root = test_root_dir.path();
+        let hermit = hermit(&MockConfig::with_root(hermit_root));
+        let history_path = hermit_root.join("history");
+        let mut keys = vec![];
+        for _ in 0..2 {
+            let mut file_ops = FileOperations::rooted_at(hermit_root);
+            file_ops.keep_history(&history_path, "secrets unlock");
+            hermit
+                .unlock_secrets(&mut file_ops, "correct horse")
+                .expect("Unlock failed");
+            file_ops.commit_atomic().expect("Commit failed");
+            keys.push(fs::read_to_string(hermit_root.join("secrets.key")).unwrap());
+        }
+        let mut file_ops = FileOperations::rooted_at(hermit_root);
+        file_ops.keep_history(&history_path, "secrets lock");
+
+        hermit.lock_secrets(&mut file_ops);
+        file_ops.commit_atomic().expect("Commit failed");
+
+        let copies = || {
+            WalkDir::new(hermit_root)
+                .into_iter()
+                .filter_map(StdResult::ok)
+                .filter_map(|entry| fs::read_to_string(entry.path()).ok())
+                .filter(|contents| keys.contains(contents))
+                .count()
+        };
+        assert_eq!(copies(), 0);
+        loop {
+            let mut file_ops = FileOperations::rooted_at(hermit_root);
+            file_ops.keep_history(&history_path, "undo");
+            match hermit.undo(&mut file_ops, None) {
+                Ok((batches, _)) => assert_ne!(batches[0].description, "secrets lock"),
+                Err(err) => {
+                    assert_eq!(err, Error::NothingToUndo);
+                    break;
+                }
+            }
+            file_ops.commit_atomic().expect("Undo failed");
+            assert_eq!(copies(), 0);
+        }
+    }
+
+    #[test]
+    fn nuking_the_current_shell_unlinks_it_and_moves_it_to_the_trash() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let hermit_root = home.join(".hermit");
+        let mut config = MockConfig::with_root(&hermit_root);
+        config.set_paths(vec![".bashrc"]);
+        let hermit = hermit(&config);
+        let shell_root = hermit_root.join("shells/default");
+        unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+
+        hermit
+            .nuke(&mut file_ops, &shell_name("default"), now)
+            .expect("Nuke failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: shell_root.clone(),
+                },
+                Op::Remove(hermit_root.join("current_shell")),
+                Op::MkDir(hermit_root.join("trash")),
+                Op::Move {
+                    from: shell_root,
+                    to: hermit_root.join("trash/default.1000"),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn nuking_a_shell_twice_in_a_second_keeps_both_in_the_trash() {
+        let test_root_dir = set_up();
+        let hermit_root = test_root_dir.path();
+        fs::create_dir_all(hermit_root.join("trash/default.1000")).unwrap();
+        fs::create_dir_all(hermit_root.join("shells/default")).unwrap();
+        let hermit = hermit(&MockConfig::with_root(hermit_root));
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+
+        hermit
+            .nuke(&mut file_ops, &shell_name("default"), now)
+            .expect("Nuke failed");
+
+        assert_eq!(
+            file_ops.operations().last(),
+            Some(&Op::Move {
+                from: hermit_root.join("shells/default"),
+                to: hermit_root.join("trash/default.1000-1"),
+            })
+        );
+    }
+
+    #[test]
+    fn cannot_nuke_a_shell_that_another_shell_is_based_on() {
+        let test_root_dir = set_up();
+        let hermit_root = test_root_dir.path();
+        let mut config = MockConfig::with_root(hermit_root);
+        config.add_shell("team-base", vec![".bashrc"]);
+        fs::create_dir_all(hermit_root.join("shells/default")).unwrap();
+        fs::write(hermit_root.join("shells/default/.hbases"), "team-base\n").unwrap();
+        let hermit = hermit(&config);
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        let result = hermit.nuke(&mut file_ops, &shell_name("team-base"), SystemTime::now());
+
+        assert_eq!(
+            result,
+            Err(Error::ShellIsABase {
+                shell: "team-base".to_owned(),
+                layer: "default".to_owned(),
+            })
+        );
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn cannot_nuke_a_shell_that_does_not_exist() {
+        let hermit = hermit(&MockConfig::new());
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        let result = hermit.nuke(&mut file_ops, &shell_name("other"), SystemTime::now());
+
+        assert_eq!(result, Err(Error::ShellDoesNotExist));
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn restores_the_newest_unexpired_nuked_shell() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        let trash = test_root.join("trash");
+        for entry in &[
+            "work.100",
+            "work.200",
+            "work.200-1",
+            "work.1",
+            "default.150",
+        ] {
+            fs::create_dir_all(trash.join(entry)).unwrap();
+        }
+        let hermit = hermit(&MockConfig::with_root(test_root));
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let now = UNIX_EPOCH + TRASH_RETENTION + Duration::from_secs(50);
+
+        hermit
+            .restore(&mut file_ops, &shell_name("work"), now)
+            .expect("Restore failed");
+
         assert_eq!(
             file_ops.operations(),
             &vec![
-                Op::Remove(op_root_path.join(".bashrc")),
-                Op::Remove(op_root_path.join(".boot/profile.boot")),
-                link_op_for(&new_shell_root, &op_root_path, ".bashrc"),
-                link_op_for(&new_shell_root, &op_root_path, ".boot/profile.boot"),
+                Op::RemoveDir(trash.join("work.1")),
+                Op::Move {
+                    from: trash.join("work.200-1"),
+                    to: test_root.join("shells/work"),
+                },
             ]
         );
     }
+
+    #[test]
+    fn cannot_restore_an_expired_shell() {
+        let test_root_dir = set_up();
+        let test_root = test_root_dir.path();
+        fs::create_dir_all(test_root.join("trash/work.1")).unwrap();
+        let hermit = hermit(&MockConfig::with_root(test_root));
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+        let now = UNIX_EPOCH + TRASH_RETENTION + Duration::from_secs(2);
+
+        let result = hermit.restore(&mut file_ops, &shell_name("work"), now);
+
+        assert_eq!(result, Err(Error::NothingToRestore("work".to_owned())));
+    }
+
+    #[test]
+    fn cannot_restore_over_an_existing_shell() {
+        let hermit = hermit(&MockConfig::new());
+        let mut file_ops = FileOperations::rooted_at("/home/geoff");
+
+        let result = hermit.restore(&mut file_ops, &shell_name("default"), SystemTime::now());
+
+        assert_eq!(result, Err(Error::ShellAlreadyExists("default".to_owned())));
+    }
 }
@@ -0,0 +1,152 @@
+use crate::{common::*, config::BASES_FILE_NAME};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+/// The name of the per-directory files listing shell paths that
+/// should never be linked.
+pub const IGNORE_FILE_NAME: &str = ".hignore";
+
+/// Files that almost every shell repository has, but that nobody
+/// wants linked into their home directory, and hermit's own manifest.
+/// These only apply at the top of a shell, and can be re-included with
+/// a `!` pattern in the top-level `.hignore`.
+const DEFAULT_IGNORES: &[&str] = &["/README*", "/LICENSE*", "/COPYING*", "/hermit.toml"];
+
+/// The combined ignore rules for a single shell.
+///
+/// Each `.hignore` file uses gitignore syntax, and its patterns are
+/// relative to the directory that holds it. As in git, the rules in
+/// deeper directories take precedence over shallower ones.
+pub struct IgnoreRules {
+    // Sorted deepest first, so the first matcher with an opinion wins.
+    matchers: Vec<Gitignore>,
+}
+
+impl IgnoreRules {
+    pub fn load(shell_root: &Path) -> IgnoreRules {
+        let walker = WalkDir::new(shell_root)
+            .follow_links(false)
+            .into_iter()
+            .filter_entry(|entry| !is_git_dir(entry.path(), entry.file_type().is_dir()));
+
+        let mut matchers = vec![];
+        for entry in walker.filter_map(StdResult::ok) {
+            if entry.file_name() != IGNORE_FILE_NAME || entry.file_type().is_dir() {
+                continue;
+            }
+            let dir = entry.path().parent().unwrap_or(shell_root);
+            let mut builder = GitignoreBuilder::new(dir);
+            builder.add(entry.path());
+            if let Ok(matcher) = builder.build() {
+                matchers.push(matcher);
+            }
+        }
+        matchers.push(default_matcher(shell_root));
+        matchers.sort_by_key(|matcher| std::cmp::Reverse(matcher.path().components().count()));
+
+        IgnoreRules { matchers }
+    }
+
+    /// Whether `path`, which must be inside the shell root, should be
+    /// left out of the shell's files.
+    pub fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        if is_git_dir(path, is_dir)
+            || path.file_name() == Some(IGNORE_FILE_NAME.as_ref())
+            || path.file_name() == Some(BASES_FILE_NAME.as_ref())
+            || redact::is_secrets_file(path)
+        {
+            return true;
+        }
+
+        for matcher in &self.matchers {
+            if !path.starts_with(matcher.path()) {
+                continue;
+            }
+            let matched = matcher.matched(path, is_dir);
+            if matched.is_ignore() {
+                return true;
+            }
+            if matched.is_whitelist() {
+                return false;
+            }
+        }
+        false
+    }
+}
+
+fn default_matcher(shell_root: &Path) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(shell_root);
+    for pattern in DEFAULT_IGNORES {
+        builder
+            .add_line(None, pattern)
+            .expect("Default ignore patterns are valid");
+    }
+    builder.build().expect("Default ignore patterns are valid")
+}
+
+fn is_git_dir(path: &Path, is_dir: bool) -> bool {
+    is_dir && path.file_name() == Some(".git".as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn always_ignores_git_directories_and_hermit_files() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let rules = IgnoreRules::load(root);
+
+        assert!(rules.is_ignored(&root.join(".git"), true));
+        assert!(rules.is_ignored(&root.join(".hignore"), false));
+        assert!(rules.is_ignored(&root.join("sub/.hignore"), false));
+        assert!(rules.is_ignored(&root.join(".hbases"), false));
+        assert!(rules.is_ignored(&root.join(".netrc.hermit-secrets"), false));
+        assert!(!rules.is_ignored(&root.join(".gitconfig"), false));
+    }
+
+    #[test]
+    fn ignores_readme_and_license_only_at_the_top() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let rules = IgnoreRules::load(root);
+
+        assert!(rules.is_ignored(&root.join("README.md"), false));
+        assert!(rules.is_ignored(&root.join("LICENSE"), false));
+        assert!(rules.is_ignored(&root.join("hermit.toml"), false));
+        assert!(!rules.is_ignored(&root.join(".config/tool/README"), false));
+    }
+
+    #[test]
+    fn scopes_patterns_to_the_directory_of_their_ignore_file() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        fs::create_dir_all(root.join(".config")).unwrap();
+        fs::write(root.join(".config/.hignore"), "*.log\n/cache\n").unwrap();
+        let rules = IgnoreRules::load(root);
+
+        assert!(rules.is_ignored(&root.join(".config/app/debug.log"), false));
+        assert!(rules.is_ignored(&root.join(".config/cache"), true));
+        assert!(!rules.is_ignored(&root.join(".config/app/cache"), true));
+        assert!(!rules.is_ignored(&root.join("debug.log"), false));
+    }
+
+    #[test]
+    fn deeper_ignore_files_take_precedence() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        fs::create_dir_all(root.join(".config")).unwrap();
+        fs::write(root.join(".hignore"), "*.log\n!README.md\n").unwrap();
+        fs::write(root.join(".config/.hignore"), "!keep.log\n").unwrap();
+        let rules = IgnoreRules::load(root);
+
+        assert!(rules.is_ignored(&root.join(".config/other.log"), false));
+        assert!(!rules.is_ignored(&root.join(".config/keep.log"), false));
+        assert!(!rules.is_ignored(&root.join("README.md"), false));
+    }
+}
@@ -0,0 +1,155 @@
+use crate::common::*;
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_operations::Op;
+
+/// How many batches are kept before the oldest are forgotten.
+pub const HISTORY_LIMIT: usize = 50;
+
+const BATCH_FILE_NAME: &str = "batch.toml";
+
+/// The batches of operations that hermit has committed, newest last,
+/// kept in a directory under the hermit root so they can be undone.
+///
+/// Each batch has a directory named after its ID, holding a
+/// description of the batch and whatever the batch replaced.
+pub struct History {
+    dir: PathBuf,
+}
+
+/// One committed batch of operations, and how to undo it.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    pub id: u64,
+    /// The command that made the batch, like `inhabit work`.
+    pub description: String,
+    /// When the batch was committed, in seconds since the epoch.
+    pub committed_at: u64,
+    /// Where the things the batch replaced or removed are kept.
+    #[serde(default)]
+    pub stashes: Vec<PathBuf>,
+    /// The operations that undo the batch, in the order they're done.
+    #[serde(default)]
+    pub undo: Vec<Op>,
+    /// What the state file said before the batch, if it was kept.
+    pub state: Option<State>,
+}
+
+impl History {
+    pub fn new(dir: impl AsRef<Path>) -> History {
+        History {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Every batch that can still be undone, oldest first.
+    pub fn batches(&self) -> Result<Vec<Batch>> {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(Error::BadHistory(self.dir.clone(), err.to_string())),
+        };
+        let mut batches = vec![];
+        for entry in entries.filter_map(StdResult::ok) {
+            let is_batch = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|name| name.parse::<u64>().is_ok());
+            if !is_batch {
+                continue;
+            }
+            let path = entry.path().join(BATCH_FILE_NAME);
+            let contents = fs::read_to_string(&path)
+                .map_err(|err| Error::BadHistory(path.clone(), err.to_string()))?;
+            let batch: Batch = toml::from_str(&contents).map_err(|err| {
+                Error::BadHistory(path.clone(), message::toml_error(&contents, &err))
+            })?;
+            batches.push(batch);
+        }
+        batches.sort_by_key(|batch| batch.id);
+        Ok(batches)
+    }
+
+    /// The ID that the next batch gets.
+    pub fn next_id(&self) -> Result<u64> {
+        Ok(self.batches()?.last().map_or(1, |batch| batch.id + 1))
+    }
+
+    /// The directory that the batch with `id` keeps its stashes in.
+    pub fn stash_dir(&self, id: u64) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+
+    /// Keeps `batch`, forgetting the oldest batches beyond the limit.
+    pub fn add(&self, batch: &Batch) -> io::Result<()> {
+        let dir = self.stash_dir(batch.id);
+        fs::create_dir_all(&dir)?;
+        let contents = toml::to_string(batch).map_err(io::Error::other)?;
+        fs::write(dir.join(BATCH_FILE_NAME), contents)?;
+
+        let batches = self.batches().map_err(io::Error::other)?;
+        let excess = batches.len().saturating_sub(HISTORY_LIMIT);
+        for old in &batches[..excess] {
+            self.remove(old)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets `batch`, along with anything it stashed.
+    pub fn remove(&self, batch: &Batch) -> io::Result<()> {
+        for stash in &batch.stashes {
+            match fs::symlink_metadata(stash) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(stash)?,
+                Ok(_) => fs::remove_file(stash)?,
+                Err(_) => (),
+            }
+        }
+        let dir = self.stash_dir(batch.id);
+        if dir.exists() {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+}
+
+impl Batch {
+    pub fn committed_at(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.committed_at)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn batch(id: u64) -> Batch {
+        Batch {
+            id,
+            description: format!("add .file{}", id),
+            committed_at: 1_000_000,
+            stashes: vec![],
+            undo: vec![Op::Remove(PathBuf::from(format!("/home/.file{}", id)))],
+            state: None,
+        }
+    }
+
+    #[test]
+    fn keeps_a_limited_number_of_batches_in_order() {
+        let test_root_dir = set_up();
+        let history = History::new(test_root_dir.path().join("history"));
+        assert_eq!(history.batches(), Ok(vec![]));
+        assert_eq!(history.next_id(), Ok(1));
+
+        for id in 1..=(HISTORY_LIMIT as u64 + 2) {
+            history.add(&batch(id)).unwrap();
+        }
+
+        let batches = history.batches().unwrap();
+        assert_eq!(batches.len(), HISTORY_LIMIT);
+        assert_eq!(batches[0], batch(3));
+        assert_eq!(history.next_id(), Ok(HISTORY_LIMIT as u64 + 3));
+    }
+}
@@ -1,25 +1,55 @@
 use crate::common::*;
 
+use std::{io::IsTerminal, os::unix::fs::OpenOptionsExt};
+
 pub mod common;
 pub mod config;
+pub mod doctor;
 pub mod env;
 pub mod file_operations;
+pub mod git;
 pub mod hermit;
+pub mod hignore;
+pub mod history;
+pub mod manifest;
 pub mod message;
+pub mod redact;
+pub mod secrets;
 pub mod shell;
+pub mod shell_name;
+pub mod state;
+pub mod template;
 
 #[cfg(test)]
 mod test_helpers;
 
 const SHELL_NAME_ARG: &str = "SHELL_NAME";
+const PATHS_ARG: &str = "PATHS";
+const URL_ARG: &str = "URL";
+const FIX_ARG: &str = "fix";
+const YES_ARG: &str = "yes";
+const SHELL_ARG: &str = "shell";
+const GIT_ARGS_ARG: &str = "GIT_ARGS";
+const FORCE_ARG: &str = "force";
+const RESTORE_ARG: &str = "restore";
+const ON_CONFLICT_ARG: &str = "on-conflict";
+const DRY_RUN_ARG: &str = "dry-run";
+const REDACT_ARG: &str = "redact";
+const MODE_ARG: &str = "mode";
+const FROM_ARG: &str = "from";
+const BATCH_ID_ARG: &str = "BATCH_ID";
+const LIST_ARG: &str = "list";
 
 fn main() {
     match run() {
         Ok(()) => (),
-        Err(err) => {
-            eprintln!("{}: {}", env::get_program_name(), err);
-            process::exit(1)
-        }
+        Err(err) => match err.downcast_ref::<Error>() {
+            Some(Error::GitExited(code)) => process::exit(*code),
+            _ => {
+                eprintln!("{}: {}", env::get_program_name(), err);
+                process::exit(1)
+            }
+        },
     }
 }
 
@@ -30,10 +60,14 @@ fn run() -> anyhow::Result<()>{
 
     let hermit_root = env::get_hermit_dir().expect("Could not determine hermit root location.");
     let fs_config = FsConfig::new(hermit_root)?;
+    let state_path = fs_config.state_path();
+    let history_path = fs_config.history_path();
     let mut hermit = Hermit::new(fs_config);
 
     let home_dir = env::home_dir().expect("Could not determine home directory.");
     let mut file_operations = FileOperations::rooted_at(home_dir);
+    file_operations.keep_state(state_path);
+    file_operations.keep_history(history_path, env::command_line());
 
     match app_matches.subcommand() {
         ("add",     Some(matches)) => handle_add     (matches, &mut hermit, &mut file_operations),
@@ -41,27 +75,26 @@ fn run() -> anyhow::Result<()>{
         ("doctor",  Some(matches)) => handle_doctor  (matches, &mut hermit, &mut file_operations),
         ("git",     Some(matches)) => handle_git     (matches, &mut hermit, &mut file_operations),
         ("init",    Some(matches)) => handle_init    (matches, &mut hermit, &mut file_operations),
+        ("list",    Some(matches)) => handle_list    (matches, &mut hermit, &mut file_operations),
         ("nuke",    Some(matches)) => handle_nuke    (matches, &mut hermit, &mut file_operations),
+        ("secrets", Some(matches)) => handle_secrets (matches, &mut hermit, &mut file_operations),
         ("shell",   Some(matches)) => handle_shell   (matches, &mut hermit, &mut file_operations),
         ("status",  Some(matches)) => handle_status  (matches, &mut hermit, &mut file_operations),
+        ("undo",    Some(matches)) => handle_undo    (matches, &mut hermit, &mut file_operations),
+        ("update",  Some(matches)) => handle_update  (matches, &mut hermit, &mut file_operations),
         ("inhabit", Some(matches)) => handle_inhabit (matches, &mut hermit, &mut file_operations),
-        _ => unreachable!(message::error_str("unknown subcommand passed"))
+        _ => unreachable!("{}", message::error_str("unknown subcommand passed"))
     }?;
 
-    report_errors(file_operations.commit());
+    if app_matches.is_present(DRY_RUN_ARG) {
+        print_plan(&file_operations);
+    } else {
+        file_operations.commit_atomic()?;
+    }
 
     Ok(())
 }
 
-fn report_errors(results: Vec<file_operations::Result>) {
-    for result in results {
-        match result {
-            Ok(()) => (),
-            Err(e) => println!("{}", message::error(e)),
-        }
-    }
-}
-
 #[allow(clippy::let_and_return)]
 fn make_app_config<'a, 'b>() -> App<'a, 'b> {
     let app = App::new("hermit")
@@ -69,16 +102,27 @@ fn make_app_config<'a, 'b>() -> App<'a, 'b> {
         .author("A product of the Bike Barn <https://github.com/bike-barn/hermit>")
         .about("A home directory configuration management assistant.")
         .setting(AppSettings::SubcommandRequiredElseHelp)
-        .setting(AppSettings::VersionlessSubcommands);
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name(DRY_RUN_ARG)
+                .long("dry-run")
+                .short("n")
+                .global(true)
+                .help("Show what would be changed without changing anything."),
+        );
 
     let app = add_add_subcommand(app);
     let app = add_clone_subcommand(app);
     let app = add_doctor_subcommand(app);
     let app = add_git_subcommand(app);
     let app = add_init_subcommand(app);
+    let app = add_list_subcommand(app);
     let app = add_nuke_subcommand(app);
+    let app = add_secrets_subcommand(app);
     let app = add_shell_subcommand(app);
     let app = add_status_subcommand(app);
+    let app = add_undo_subcommand(app);
+    let app = add_update_subcommand(app);
     let app = add_inhabit_subcommand(app);
 
     app
@@ -109,57 +153,194 @@ macro_rules! subcommand {
 subcommand! {
   fn add_add_subcommand("add") {
       about("Add files to your hermit shell")
+      arg(Arg::with_name(PATHS_ARG)
+          .required(true)
+          .multiple(true)
+          .help("The files to move into your shell and replace with links."))
+      arg(Arg::with_name(REDACT_ARG)
+          .long("redact")
+          .short("r")
+          .help("Open each file in your editor to remove its secrets before \
+                 adding it. The secrets are kept out of the shell's history."))
+      arg(Arg::with_name(MODE_ARG)
+          .long("mode")
+          .takes_value(true)
+          .possible_values(&["symlink", "relative", "copy", "hardlink"])
+          .conflicts_with(REDACT_ARG)
+          .help("How to put the files back in place, when a symlink won't \
+                 do. The shell's hermit.toml remembers it."))
   }
 }
 
 fn handle_add<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("add")
+    let current_dir = env::current_dir().expect("Could not determine current directory.");
+    let paths = matches
+        .values_of(PATHS_ARG)
+        .unwrap()
+        .map(|path| current_dir.join(path));
+    let mode = matches
+        .value_of(MODE_ARG)
+        .map(|mode| mode.parse().expect("clap only allows known link modes"));
+    let skipped = if matches.is_present(REDACT_ARG) {
+        hermit.add_redacted(file_operations, paths, edit)?
+    } else {
+        hermit.add(file_operations, paths, mode)?
+    };
+    for link in skipped {
+        println!("skipped: {} is a symbolic link", link.display());
+    }
+    Ok(())
 }
 
 subcommand! {
   fn add_clone_subcommand("clone") {
-    about("Create a local shell from an existing remote shell")
+    about("Create a local shell from an existing remote shell. If no shell \
+           name is given, it is taken from the last part of the URL.")
+    arg(Arg::with_name(URL_ARG)
+        .required(true)
+        .help("The git URL of the shell to clone."))
+    arg(Arg::with_name(SHELL_NAME_ARG)
+        .help("The name of the shell to be created."))
   }
 }
 
 fn handle_clone<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("clone")
+    let url = matches.value_of(URL_ARG).unwrap();
+    let shell_name = matches
+        .value_of(SHELL_NAME_ARG)
+        .map(ShellName::new)
+        .transpose()?;
+    hermit.clone_shell(file_operations, url, shell_name.as_ref())?;
+    Ok(())
 }
 
 subcommand! {
   fn add_doctor_subcommand("doctor") {
     about("Make sure your hermit setup is sane")
+    arg(Arg::with_name(FIX_ARG)
+        .long("fix")
+        .help("Repair the problems that hermit knows how to fix."))
+    arg(Arg::with_name(YES_ARG)
+        .long("yes")
+        .short("y")
+        .requires(FIX_ARG)
+        .help("Apply fixes without asking first."))
   }
 }
 
 fn handle_doctor<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("doctor")
+    let context = doctor::Context {
+        config: hermit.config(),
+        home: file_operations.root(),
+    };
+    let diagnoses = doctor::diagnose(&doctor::checks(), &context);
+
+    let mut failures = 0;
+    let mut fixes = vec![];
+    for diagnosis in diagnoses {
+        let finding = diagnosis.finding;
+        println!(
+            "{:<4}  {}: {}",
+            finding.status, diagnosis.check, finding.explanation
+        );
+        if finding.fix.is_empty() {
+            if finding.status == doctor::Status::Fail {
+                failures += 1;
+            }
+        } else {
+            fixes.push((finding.status, finding.fix));
+        }
+    }
+
+    if matches.is_present(FIX_ARG) && !fixes.is_empty() {
+        println!();
+        println!("hermit can fix some of these problems by doing the following:");
+        for op in fixes.iter().flat_map(|(_, fix)| fix) {
+            println!("  {}", op.display_from(file_operations.root()));
+        }
+        let dry_run = matches.is_present(DRY_RUN_ARG);
+        let skip_confirmation = matches.is_present(YES_ARG) || dry_run;
+        if skip_confirmation || confirm("Apply these fixes?") {
+            for op in fixes.iter().flat_map(|(_, fix)| fix) {
+                file_operations.push(op.clone());
+            }
+            // A dry run doesn't fix anything, so its problems still count.
+            if !dry_run {
+                fixes.clear();
+            }
+        }
+    }
+    failures += fixes
+        .iter()
+        .filter(|(status, _)| *status == doctor::Status::Fail)
+        .count();
+
+    if failures > 0 {
+        Err(Error::DoctorFoundProblems(failures))
+    } else {
+        Ok(())
+    }
 }
 
 subcommand! {
   fn add_git_subcommand("git") {
     about("Run git operations on the current shell")
+    setting(AppSettings::TrailingVarArg)
+    setting(AppSettings::AllowLeadingHyphen)
+    arg(Arg::with_name(SHELL_ARG)
+        .long("shell")
+        .takes_value(true)
+        .value_name(SHELL_NAME_ARG)
+        .help("Run git in this shell instead of the current one."))
+    arg(Arg::with_name(GIT_ARGS_ARG)
+        .multiple(true)
+        .help("The arguments to pass along to git."))
   }
 }
 
 fn handle_git<C: Config>(
-    _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
     _file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("git")
+    let shell = match matches.value_of(SHELL_ARG) {
+        Some(name) => hermit.shell(&ShellName::new(name)?)?,
+        None => hermit.current_shell()?,
+    };
+    let args = matches.values_of_os(GIT_ARGS_ARG).into_iter().flatten();
+
+    if matches.is_present(DRY_RUN_ARG) {
+        let args = args.map(|arg| arg.to_string_lossy()).collect::<Vec<_>>();
+        println!(
+            "hermit would run git {} in {}",
+            args.join(" "),
+            shell.root_path().display()
+        );
+        return Ok(());
+    }
+
+    let status = process::Command::new("git")
+        .args(args)
+        .current_dir(shell.root_path())
+        .status()
+        .map_err(|err| Error::GitCouldNotRun(err.to_string()))?;
+
+    match status.code() {
+        Some(0) => Ok(()),
+        code => Err(Error::GitExited(code.unwrap_or(1))),
+    }
 }
 
 subcommand! {
@@ -167,6 +348,15 @@ subcommand! {
     about("Create a new hermit shell called SHELL_NAME. If no shell name \
            is given, \"default\" is used.")
     arg(shell_name_arg("The name of the shell to be created."))
+    arg(Arg::with_name(FROM_ARG)
+        .long("from")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .value_name("BASE")
+        .help("A shell to layer the new shell on top of. Files in the new \
+               shell override the ones in its bases. Can be given more \
+               than once, lowest layer first."))
   }
 }
 
@@ -175,23 +365,174 @@ fn handle_init<C: Config>(
     hermit: &mut Hermit<C>,
     file_operations: &mut FileOperations,
 ) -> Result<()> {
-    let shell_name = matches.value_of(SHELL_NAME_ARG).unwrap();
-    hermit.init_shell(file_operations, shell_name)?;
+    let shell_name = ShellName::new(matches.value_of(SHELL_NAME_ARG).unwrap())?;
+    let bases = matches
+        .values_of(FROM_ARG)
+        .into_iter()
+        .flatten()
+        .map(ShellName::new)
+        .collect::<Result<Vec<_>>>()?;
+    hermit.init_shell(file_operations, &shell_name, &bases)?;
     Ok(())
 }
 
 subcommand! {
-  fn add_nuke_subcommand("nuke") {
-    about("Permanently remove a hermit shell")
+  fn add_list_subcommand("list") {
+    about("List your hermit shells, marking the current one with a *")
   }
 }
 
-fn handle_nuke<C: Config>(
+fn handle_list<C: Config>(
     _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
+    hermit: &mut Hermit<C>,
     _file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("nuke")
+    let now = SystemTime::now();
+    for name in hermit.config().shell_names() {
+        let shell = hermit.shell(&name)?;
+        let marker = if hermit.config().current_shell_name() == Some(&name) {
+            '*'
+        } else {
+            ' '
+        };
+        match shell.description() {
+            Ok(Some(description)) => println!("{} {} - {}", marker, name, description),
+            Ok(None) => println!("{} {}", marker, name),
+            Err(err) => {
+                println!("{} {}", marker, name);
+                println!("    {}", err);
+                continue;
+            }
+        }
+
+        let file_count = match hermit.config().shell_files(&name) {
+            Ok(files) => files.into_iter().count(),
+            Err(err) => {
+                println!("    {}", err);
+                continue;
+            }
+        };
+        let files = format!(
+            "{} file{}",
+            file_count,
+            if file_count == 1 { "" } else { "s" }
+        );
+        let status = match git::status(&shell.root_path()) {
+            Ok(status) => status,
+            Err(_) => {
+                println!("    {}, not a git repository", files);
+                continue;
+            }
+        };
+
+        let mut state = vec![];
+        if let Some(tracking) = &status.tracking {
+            if tracking.ahead > 0 {
+                state.push(format!("{} ahead", tracking.ahead));
+            }
+            if tracking.behind > 0 {
+                state.push(format!("{} behind", tracking.behind));
+            }
+        }
+        if !status.is_clean() {
+            state.push("uncommitted changes".to_owned());
+        }
+        let branch = status.branch.as_deref().unwrap_or("no branch");
+        if state.is_empty() {
+            println!("    {}, on {}", files, branch);
+        } else {
+            println!("    {}, on {} ({})", files, branch, state.join(", "));
+        }
+
+        let remote = status.remote_url.as_deref().unwrap_or("no remote");
+        match status.last_commit {
+            Some(time) => println!("    {}, last commit {}", remote, message::ago(time, now)),
+            None => println!("    {}, no commits yet", remote),
+        }
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_nuke_subcommand("nuke") {
+    about("Remove a hermit shell. Nuked shells are kept in the trash for \
+           30 days, and can be brought back with --restore.")
+    arg(Arg::with_name(SHELL_NAME_ARG)
+        .required(true)
+        .help("The name of the shell to be removed."))
+    arg(Arg::with_name(FORCE_ARG)
+        .long("force")
+        .short("f")
+        .help("Don't ask for confirmation first."))
+    arg(Arg::with_name(RESTORE_ARG)
+        .long("restore")
+        .conflicts_with(FORCE_ARG)
+        .help("Bring back a shell that was nuked."))
+  }
+}
+
+fn handle_nuke<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let shell_name = ShellName::new(matches.value_of(SHELL_NAME_ARG).unwrap())?;
+    let now = SystemTime::now();
+    if matches.is_present(RESTORE_ARG) {
+        return hermit.restore(file_operations, &shell_name, now);
+    }
+
+    let question = format!("Really nuke the shell {}?", shell_name);
+    let skip_confirmation = matches.is_present(FORCE_ARG) || matches.is_present(DRY_RUN_ARG);
+    if !skip_confirmation && !confirm(&question) {
+        return Err(Error::NukeNotConfirmed(shell_name.to_string()));
+    }
+    for skipped in hermit.nuke(file_operations, &shell_name, now)? {
+        println!("skipped: {}", skipped);
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_secrets_subcommand("secrets") {
+    about("Unlock or lock the encrypted secrets of redacted files")
+    setting(AppSettings::SubcommandRequiredElseHelp)
+    subcommand(SubCommand::with_name("unlock")
+        .about("Ask for your passphrase, and keep the key it gives until \
+                the secrets are locked again"))
+    subcommand(SubCommand::with_name("lock")
+        .about("Forget the key, so that secrets can't be read until they \
+                are unlocked again"))
+  }
+}
+
+fn handle_secrets<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    match matches.subcommand_name() {
+        Some("unlock") => {
+            let passphrase = read_passphrase("Passphrase: ")?;
+            if hermit.secrets_salt().is_none() {
+                println!(
+                    "There are no secrets yet, so this passphrase will be used for all of them."
+                );
+                if read_passphrase("Passphrase again: ")? != passphrase {
+                    return Err(Error::PassphrasesDiffer);
+                }
+            }
+            hermit.unlock_secrets(file_operations, &passphrase)
+        }
+        Some("lock") => {
+            hermit.lock_secrets(file_operations);
+            Ok(())
+        }
+        _ => unreachable!(
+            "{}",
+            message::error_str("unknown secrets subcommand passed")
+        ),
+    }
 }
 
 subcommand! {
@@ -218,15 +559,201 @@ subcommand! {
 
 fn handle_status<C: Config>(
     _matches: &ArgMatches<'_>,
-    _hermit: &mut Hermit<C>,
-    _file_operations: &mut FileOperations,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
 ) -> Result<()> {
-    not_implemented("status")
+    let shell = hermit.current_shell()?;
+    let shell_root = shell.root_path();
+    println!("Inhabiting shell {}", shell.name);
+    println!("Located at {}", shell_root.display());
+    let layers = shell.layers()?;
+    if layers.len() > 1 {
+        println!(
+            "Layered on {}",
+            layers[..layers.len() - 1]
+                .iter()
+                .map(ShellName::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    println!();
+    println!("Links:");
+    for (file, state) in shell.link_states(file_operations.root())? {
+        let mut path = file.path.display().to_string();
+        if file.folded {
+            path.push('/');
+        }
+        if file.layer != shell.name {
+            path = format!("{} (from {})", path, file.layer);
+        }
+        match state {
+            Ok(LinkState::Linked) => println!("  linked     {}", path),
+            Ok(LinkState::Missing) => println!("  missing    {}", path),
+            Ok(LinkState::LinkedElsewhere(target)) => {
+                println!("  elsewhere  {} -> {}", path, target.display())
+            }
+            Ok(LinkState::Blocked) => println!("  blocked    {} (a real file is in the way)", path),
+            Ok(LinkState::Changed) => {
+                println!("  changed    {} (edited since it was generated)", path)
+            }
+            Ok(LinkState::Drifted) => {
+                println!("  drifted    {} (out of sync with the shell file)", path)
+            }
+            Ok(LinkState::Stale) => {
+                println!(
+                    "  stale      {} (its template renders differently now)",
+                    path
+                )
+            }
+            Err(err) => println!("  unknown    {} ({})", path, err),
+        }
+    }
+    for record in shell.orphans(file_operations.root())? {
+        if record.state() != LinkState::Missing {
+            let path = record
+                .path
+                .strip_prefix(file_operations.root())
+                .unwrap_or(&record.path);
+            println!("  orphaned   {} (no longer in the shell)", path.display());
+        }
+    }
+
+    let status = git::status(&shell_root)?;
+    println!();
+    match (&status.branch, &status.tracking) {
+        (Some(branch), Some(tracking)) => println!(
+            "Git: on {}, {} ahead and {} behind {}",
+            branch, tracking.ahead, tracking.behind, tracking.upstream
+        ),
+        (Some(branch), None) => println!("Git: on {}, with no upstream", branch),
+        (None, _) => println!("Git: not on a branch"),
+    }
+    for path in &status.staged {
+        println!("  staged     {}", path.display());
+    }
+    for path in &status.modified {
+        println!("  modified   {}", path.display());
+    }
+    for path in &status.untracked {
+        println!("  untracked  {}", path.display());
+    }
+    if status.is_clean() {
+        println!("  nothing to commit");
+    }
+
+    Ok(())
+}
+
+subcommand! {
+  fn add_undo_subcommand("undo") {
+    about("Undo the last change hermit made to your files")
+    arg(Arg::with_name(BATCH_ID_ARG)
+        .validator(|id| id.parse::<u64>().map(|_| ()).map_err(|err| err.to_string()))
+        .help("Undo every change back to and including this one, instead \
+               of just the last."))
+    arg(Arg::with_name(LIST_ARG)
+        .long("list")
+        .short("l")
+        .conflicts_with(BATCH_ID_ARG)
+        .help("List the changes that can be undone, newest first."))
+    arg(Arg::with_name(YES_ARG)
+        .long("yes")
+        .short("y")
+        .conflicts_with(LIST_ARG)
+        .help("Undo without asking first."))
+  }
+}
+
+fn handle_undo<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let now = SystemTime::now();
+    if matches.is_present(LIST_ARG) {
+        let batches = hermit.history()?;
+        if batches.is_empty() {
+            println!("There's nothing to undo.");
+        }
+        for batch in batches.iter().rev() {
+            let changes = batch.undo.len();
+            println!(
+                "{:>4}  {}, {} change{}, {}",
+                batch.id,
+                batch.description,
+                changes,
+                if changes == 1 { "" } else { "s" },
+                message::ago(batch.committed_at(), now)
+            );
+        }
+        return Ok(());
+    }
+
+    let id = matches
+        .value_of(BATCH_ID_ARG)
+        .map(|id| id.parse().expect("clap only allows numeric batch IDs"));
+    let (batches, skipped) = hermit.undo(file_operations, id)?;
+    for batch in &batches {
+        println!("undoing {}: {}", batch.id, batch.description);
+    }
+    for op in &skipped {
+        println!(
+            "skipped: {}, it has changed since",
+            op.display_from(file_operations.root())
+        );
+    }
+    let skip_confirmation = matches.is_present(YES_ARG) || matches.is_present(DRY_RUN_ARG);
+    if !skip_confirmation && !confirm("Really undo these changes?") {
+        return Err(Error::UndoNotConfirmed);
+    }
+    Ok(())
+}
+
+subcommand! {
+  fn add_update_subcommand("update") {
+    about("Fold the changes you made to redacted files back into your shell")
+    arg(Arg::with_name(PATHS_ARG)
+        .required(true)
+        .multiple(true)
+        .help("The redacted files in your home directory to update from."))
+  }
+}
+
+fn handle_update<C: Config>(
+    matches: &ArgMatches<'_>,
+    hermit: &mut Hermit<C>,
+    file_operations: &mut FileOperations,
+) -> Result<()> {
+    let current_dir = env::current_dir().expect("Could not determine current directory.");
+    let paths = matches
+        .values_of(PATHS_ARG)
+        .unwrap()
+        .map(|path| current_dir.join(path));
+    let is_secret = |path: &Path, change: &str| {
+        println!("{}:", path.display());
+        print!("{}", change);
+        confirm("Is this a secret?")
+    };
+    hermit.update(file_operations, paths, is_secret, edit)
 }
 
 subcommand! {
   fn add_inhabit_subcommand("inhabit") {
     about("Switch to using a different hermit shell")
+    arg(shell_name_arg("The name of the shell to switch to."))
+    arg(Arg::with_name(ON_CONFLICT_ARG)
+        .long("on-conflict")
+        .takes_value(true)
+        .possible_values(&["skip", "backup", "overwrite", "abort"])
+        .default_value("skip")
+        .help("What to do when a file is already in the way of a link."))
+    arg(Arg::with_name(FORCE_ARG)
+        .long("force")
+        .short("f")
+        .help("Remove the current shell's files from your home directory \
+               even if they aren't links that hermit made."))
   }
 }
 
@@ -235,8 +762,16 @@ fn handle_inhabit<C: Config>(
     hermit: &mut Hermit<C>,
     file_operations: &mut FileOperations,
 ) -> Result<()> {
-    let shell_name = matches.value_of(SHELL_NAME_ARG).unwrap();
-    hermit.inhabit(file_operations, shell_name)?;
+    let shell_name = ShellName::new(matches.value_of(SHELL_NAME_ARG).unwrap())?;
+    let policy = matches
+        .value_of(ON_CONFLICT_ARG)
+        .unwrap()
+        .parse()
+        .expect("clap only allows known conflict policies");
+    let force = matches.is_present(FORCE_ARG);
+    for conflict in hermit.inhabit(file_operations, &shell_name, policy, force)? {
+        println!("skipped: {}", conflict);
+    }
     Ok(())
 }
 
@@ -244,12 +779,79 @@ fn handle_inhabit<C: Config>(
 // Utility functions
 // **************************************************
 
+fn print_plan(file_operations: &FileOperations) {
+    let operations = file_operations.operations();
+    if operations.is_empty() {
+        println!("No files would be changed.");
+        return;
+    }
+
+    println!("hermit would:");
+    for op in operations {
+        println!("  {}", op.display_from(file_operations.root()));
+    }
+}
+
 fn shell_name_arg<'a, 'b>(message: &'static str) -> Arg<'a, 'b> {
     Arg::with_name(SHELL_NAME_ARG)
         .default_value("default")
         .help(message)
 }
 
-fn not_implemented(name: &'static str) -> Result<()> {
-    Err(Error::SubcommandNotImplemented(name))
+/// Lets the user edit `contents` in their editor, in a private copy of
+/// the file at `path`, and returns what they saved.
+fn edit(path: &Path, contents: &str) -> io::Result<String> {
+    let editor = env::editor();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let copy = env::temp_dir().join(format!("hermit-{}-{}", process::id(), file_name));
+
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&copy)?
+        .write_all(contents.as_bytes())?;
+    let status = process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", editor))
+        .arg(&editor)
+        .arg(&copy)
+        .status();
+    let edited = fs::read_to_string(&copy);
+    fs::remove_file(&copy)?;
+
+    match status? {
+        status if status.success() => edited,
+        status => Err(io::Error::other(format!(
+            "{} exited with {}",
+            editor, status
+        ))),
+    }
+}
+
+/// Asks for a passphrase without echoing it, or reads one line from
+/// standard input when that isn't a terminal.
+fn read_passphrase(prompt: &str) -> Result<String> {
+    let passphrase = if io::stdin().is_terminal() {
+        rpassword::prompt_password(prompt)
+    } else {
+        let mut line = String::new();
+        io::stdin().read_line(&mut line).map(|_| line)
+    };
+    passphrase
+        .map(|passphrase| passphrase.trim_end_matches(&['\n', '\r'][..]).to_owned())
+        .map_err(|err| Error::PassphraseNotRead(err.to_string()))
+}
+
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    if io::stdout().flush().is_err() {
+        return false;
+    }
+
+    let mut answer = String::new();
+    match io::stdin().read_line(&mut answer) {
+        Ok(_) => matches!(answer.trim(), "y" | "Y" | "yes"),
+        Err(_) => false,
+    }
 }
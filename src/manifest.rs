@@ -0,0 +1,717 @@
+use crate::common::*;
+
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+use toml::Spanned;
+
+/// The name of the file at the top of a shell that describes it.
+pub const MANIFEST_FILE_NAME: &str = "hermit.toml";
+
+/// What a shell's `hermit.toml` says about it.
+///
+/// A shell without a manifest manages every file in it that isn't
+/// ignored, and so does one whose manifest has no `include` list and
+/// no `[[files]]`, like one that only has a description. Otherwise the
+/// shell only manages the files that its entries include. Every
+/// pattern is a glob relative to the shell root, where `*` stays
+/// within a directory and `**` crosses them. A pattern that matches a
+/// directory matches everything in it.
+///
+/// Files go to the same path in the home directory as they have in the
+/// shell, unless an entry gives them a `target`, or `dot_prefix` is
+/// set and their names start with `dot_` in place of a dot.
+#[derive(Clone, Debug)]
+pub struct Manifest {
+    pub description: Option<String>,
+    dot_prefix: bool,
+    /// Whether only the files that the entries include are managed,
+    /// rather than every file.
+    restricts: bool,
+    entries: Vec<Entry>,
+    exclude: Vec<GlobMatcher>,
+}
+
+/// One entry in a manifest, and the options for the files it includes.
+///
+/// Entries come from the `include` list, which only has patterns, and
+/// from `[[files]]` tables, which can also have options.
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub pattern: String,
+    /// How the files are put into the home directory, if not the
+    /// usual way.
+    pub mode: Option<LinkMode>,
+    /// Whether the directories it matches are always linked whole,
+    /// rather than file by file.
+    pub fold: bool,
+    /// Where the path goes in the home directory, relative to it, if
+    /// not the same place. It's only absolute when the entry allows
+    /// it to be outside the home directory.
+    pub target: Option<PathBuf>,
+    include: GlobMatcher,
+    exclude: Vec<GlobMatcher>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawManifest {
+    description: Option<String>,
+    #[serde(default)]
+    dot_prefix: bool,
+    include: Option<Vec<Spanned<String>>>,
+    #[serde(default)]
+    exclude: Vec<Spanned<String>>,
+    #[serde(default)]
+    files: Vec<RawEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawEntry {
+    path: Spanned<String>,
+    #[serde(default)]
+    exclude: Vec<Spanned<String>>,
+    mode: Option<LinkMode>,
+    #[serde(default)]
+    fold: bool,
+    target: Option<Spanned<String>>,
+    #[serde(default)]
+    outside_home: bool,
+}
+
+impl Manifest {
+    /// Reads the manifest of the shell at `shell_root`, if it has one.
+    pub fn load(shell_root: &Path) -> Result<Option<Manifest>> {
+        let path = shell_root.join(MANIFEST_FILE_NAME);
+        match fs::read_to_string(&path) {
+            Ok(contents) => Manifest::parse(&path, &contents).map(Some),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(Error::BadManifest(path, err.to_string())),
+        }
+    }
+
+    /// Parses the `contents` of the manifest at `path`. Problems are
+    /// reported by the line they're on.
+    pub fn parse(path: &Path, contents: &str) -> Result<Manifest> {
+        let bad_manifest = |problem| Error::BadManifest(path.to_path_buf(), problem);
+        let raw: RawManifest = toml::from_str(contents)
+            .map_err(|err| bad_manifest(message::toml_error(contents, &err)))?;
+        let glob = |pattern: &Spanned<String>| {
+            glob(pattern.get_ref())
+                .map_err(|err| bad_manifest(message::at_line(contents, pattern.span().start, err)))
+        };
+
+        let mut entries = vec![];
+        for pattern in raw.include.iter().flatten() {
+            entries.push(Entry {
+                pattern: pattern.get_ref().clone(),
+                mode: None,
+                fold: false,
+                target: None,
+                include: glob(pattern)?,
+                exclude: vec![],
+            });
+        }
+        for entry in &raw.files {
+            let at_path = |problem| {
+                bad_manifest(message::at_line(contents, entry.path.span().start, problem))
+            };
+            if entry.fold && !entry.mode.unwrap_or_default().is_symlink() {
+                return Err(at_path(
+                    "only symlinked directories can be folded".to_owned(),
+                ));
+            }
+            let target = match entry.target {
+                Some(_) if is_glob(entry.path.get_ref()) => {
+                    return Err(at_path(format!(
+                        "{} has a target, so it can't be a glob",
+                        entry.path.get_ref()
+                    )))
+                }
+                Some(ref target) => Some(
+                    expand_target(target.get_ref(), entry.outside_home).map_err(|err| {
+                        bad_manifest(message::at_line(contents, target.span().start, err))
+                    })?,
+                ),
+                None => None,
+            };
+            entries.push(Entry {
+                pattern: entry.path.get_ref().clone(),
+                mode: entry.mode,
+                fold: entry.fold,
+                target,
+                include: glob(&entry.path)?,
+                exclude: entry.exclude.iter().map(glob).collect::<Result<_>>()?,
+            });
+        }
+        let description = raw
+            .description
+            .map(|description| description.trim().to_owned())
+            .filter(|description| !description.is_empty());
+
+        Ok(Manifest {
+            description,
+            dot_prefix: raw.dot_prefix,
+            restricts: raw.include.is_some() || !raw.files.is_empty(),
+            entries,
+            exclude: raw.exclude.iter().map(glob).collect::<Result<_>>()?,
+        })
+    }
+
+    /// The entry that includes the shell file at `path`, relative to
+    /// the shell root. When more than one does, the last one wins, so
+    /// specific entries can follow general ones.
+    pub fn entry(&self, path: &Path) -> Option<&Entry> {
+        if matches_any(&self.exclude, path) {
+            return None;
+        }
+        self.entries.iter().rev().find(|entry| entry.includes(path))
+    }
+
+    pub fn includes(&self, path: &Path) -> bool {
+        if !self.restricts {
+            return !matches_any(&self.exclude, path);
+        }
+        self.entry(path).is_some()
+    }
+
+    /// How the shell file at `path` is put into the home directory.
+    pub fn mode(&self, path: &Path) -> LinkMode {
+        self.entry(path)
+            .and_then(|entry| entry.mode)
+            .unwrap_or_default()
+    }
+
+    /// Where the shell file at `source`, relative to the shell root,
+    /// goes in the home directory. The last entry with a target that
+    /// includes it decides, and `dot_` prefixes below that target are
+    /// turned into dots.
+    pub fn target(&self, source: &Path) -> PathBuf {
+        let mapped = self.entries.iter().rev().find_map(|entry| {
+            let target = entry.target.as_ref()?;
+            let rest = source.strip_prefix(entry.path()).ok()?;
+            Some((target, rest)).filter(|_| !matches_any(&entry.exclude, source))
+        });
+        match mapped {
+            Some((target, rest)) => join(target, &self.dotted(rest)),
+            None => self.dotted(source),
+        }
+    }
+
+    /// Where a file at the home-relative `path` belongs in the shell,
+    /// undoing what `target` does.
+    pub fn source(&self, path: &Path) -> PathBuf {
+        let mapped = self.entries.iter().rev().find_map(|entry| {
+            let rest = path.strip_prefix(entry.target.as_ref()?).ok()?;
+            Some((entry.path(), rest))
+        });
+        match mapped {
+            Some((source, rest)) => join(&source, &self.undotted(rest)),
+            None => self.undotted(path),
+        }
+    }
+
+    /// `path` with any `dot_` prefixes turned into dots, when this
+    /// shell names its files that way.
+    fn dotted(&self, path: &Path) -> PathBuf {
+        self.rename(path, "dot_", ".")
+    }
+
+    fn undotted(&self, path: &Path) -> PathBuf {
+        self.rename(path, ".", "dot_")
+    }
+
+    fn rename(&self, path: &Path, from: &str, to: &str) -> PathBuf {
+        if !self.dot_prefix {
+            return path.to_path_buf();
+        }
+        path.components()
+            .map(|component| match component {
+                Component::Normal(name) => {
+                    match name.to_str().and_then(|name| name.strip_prefix(from)) {
+                        Some(rest) if !rest.is_empty() => format!("{}{}", to, rest).into(),
+                        _ => name.to_os_string(),
+                    }
+                }
+                other => other.as_os_str().to_os_string(),
+            })
+            .collect()
+    }
+
+    /// How the directory at `path` is linked when an entry says it's
+    /// always folded, or `None` when it's left to hermit to decide.
+    pub fn fold_mode(&self, path: &Path) -> Option<LinkMode> {
+        if matches_any(&self.exclude, path) {
+            return None;
+        }
+        self.entries
+            .iter()
+            .rev()
+            .find(|entry| {
+                entry.fold && entry.include.is_match(path) && !matches_any(&entry.exclude, path)
+            })
+            .map(|entry| entry.mode.unwrap_or_default())
+    }
+}
+
+impl Entry {
+    /// The path this entry is for, which is only a plain path when it
+    /// has a target.
+    fn path(&self) -> PathBuf {
+        PathBuf::from(self.pattern.trim_matches('/'))
+    }
+
+    fn includes(&self, path: &Path) -> bool {
+        matches_any(std::slice::from_ref(&self.include), path) && !matches_any(&self.exclude, path)
+    }
+}
+
+/// The description in the manifest of the shell at `shell_root`, if
+/// it has one. A manifest that can't be read is an error, rather than
+/// no description.
+pub fn description(shell_root: &Path) -> Result<Option<String>> {
+    Ok(Manifest::load(shell_root)?.and_then(|manifest| manifest.description))
+}
+
+/// The contents of the manifest of the shell at `shell_root`, with an
+/// entry added for each of the shell `paths` that puts it in place the
+/// way its `LinkMode` says. A shell whose manifest didn't restrict it
+/// to its entries, or that has no manifest, gets one that still
+/// includes all of its files.
+pub fn with_modes(shell_root: &Path, paths: &[(PathBuf, LinkMode)]) -> String {
+    let path = shell_root.join(MANIFEST_FILE_NAME);
+    let mut contents = fs::read_to_string(&path).unwrap_or_default();
+    if !Manifest::parse(&path, &contents).is_ok_and(|manifest| manifest.restricts) {
+        contents.insert_str(0, "include = [\"**\"]\n");
+    }
+    for (path, mode) in paths {
+        let pattern = globset::escape(&path.to_string_lossy());
+        contents.push_str(&format!(
+            "\n[[files]]\npath = {}\nmode = \"{}\"\n",
+            toml::Value::String(pattern),
+            mode
+        ));
+    }
+    contents
+}
+
+/// Where the shell file at `source` goes in the home directory, if it
+/// comes from a shell with this `manifest`. Templates lose their
+/// extension on the way.
+pub fn target_path(manifest: Option<&Manifest>, source: &Path) -> PathBuf {
+    let path = match manifest {
+        Some(manifest) => manifest.target(source),
+        None => source.to_path_buf(),
+    };
+    template::target_path(&path)
+}
+
+/// Where a file at the home-relative `path` belongs in a shell with
+/// this `manifest`.
+pub fn source_path(manifest: Option<&Manifest>, path: &Path) -> PathBuf {
+    match manifest {
+        Some(manifest) => manifest.source(path),
+        None => path.to_path_buf(),
+    }
+}
+
+/// Expands a leading `~` and environment variables, written `$NAME` or
+/// `${NAME}`, in a `target`. An unset `$XDG_CONFIG_HOME` means
+/// `.config`, like the XDG spec says. Targets in the home directory
+/// are made relative to it, and ones outside it, whether absolute or
+/// climbing out with `..`, are a mistake unless `outside_home` allows
+/// them, in which case they're made absolute.
+fn expand_target(target: &str, outside_home: bool) -> StdResult<PathBuf, String> {
+    let mut rest = match target.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => rest.trim_start_matches('/'),
+        _ => target,
+    };
+    let mut expanded = String::new();
+    while let Some(start) = rest.find('$') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let (name, remaining) = match after.strip_prefix('{') {
+            Some(braced) => {
+                let end = braced
+                    .find('}')
+                    .ok_or_else(|| format!("{} has a ${{ that isn't closed", target))?;
+                (&braced[..end], &braced[end + 1..])
+            }
+            None => {
+                let end = after
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(after.len());
+                after.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            return Err(format!("{} has a $ without a variable name", target));
+        }
+        match std::env::var(name) {
+            Ok(value) => expanded.push_str(&value),
+            Err(_) if name == "XDG_CONFIG_HOME" => expanded.push_str(".config"),
+            Err(_) => return Err(format!("${} isn't set", name)),
+        }
+        rest = remaining;
+    }
+    expanded.push_str(rest);
+
+    let home = env::home_dir();
+    let path = normalize(&PathBuf::from(expanded));
+    let inside = match home {
+        Some(ref home) if path.is_absolute() => path.strip_prefix(home).ok(),
+        _ if path.is_absolute() => None,
+        _ => Some(path.as_path()).filter(|path| !path.starts_with("..")),
+    };
+    match inside {
+        Some(inside) if inside.as_os_str().is_empty() => {
+            Err(format!("{} is the home directory itself", target))
+        }
+        Some(inside) => Ok(inside.to_path_buf()),
+        None if outside_home => Ok(match home {
+            Some(home) if path.is_relative() => normalize(&home.join(&path)),
+            _ => path,
+        }),
+        None => Err(format!(
+            "{} is outside the home directory, set outside_home = true if that's intended",
+            target
+        )),
+    }
+}
+
+/// `path` with its `.` components dropped and its `..` components
+/// taking out the directory before them, without looking at the
+/// filesystem. A relative path keeps the `..` that climb out of it.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => match normalized.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    normalized.pop();
+                }
+                Some(Component::RootDir) => (),
+                _ => normalized.push(".."),
+            },
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// Whether `pattern` matches more than the one path it spells out.
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', ']', '{', '}'])
+}
+
+/// `path` joined onto `base`, without a trailing slash when there's
+/// nothing to join.
+fn join(base: &Path, path: &Path) -> PathBuf {
+    if path.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(path)
+    }
+}
+
+fn glob(pattern: &str) -> StdResult<GlobMatcher, globset::Error> {
+    GlobBuilder::new(pattern.trim_start_matches('/'))
+        .literal_separator(true)
+        .build()
+        .map(|glob| glob.compile_matcher())
+}
+
+/// Whether any of `globs` matches `path` or one of the directories
+/// it's in.
+fn matches_any(globs: &[GlobMatcher], path: &Path) -> bool {
+    path.ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| globs.iter().any(|glob| glob.is_match(ancestor)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn manifest(contents: &str) -> Manifest {
+        Manifest::parse(Path::new(MANIFEST_FILE_NAME), contents).unwrap()
+    }
+
+    #[test]
+    fn reads_the_description_of_a_shell() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+
+        assert_eq!(description(shell_root), Ok(None));
+
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "description = \"My work setup\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            description(shell_root),
+            Ok(Some("My work setup".to_owned()))
+        );
+
+        fs::write(shell_root.join("hermit.toml"), "description = 12\n").unwrap();
+        assert!(matches!(
+            description(shell_root),
+            Err(Error::BadManifest(_, problem)) if problem.starts_with("line 1: ")
+        ));
+    }
+
+    #[test]
+    fn includes_files_and_directories_that_match_an_entry() {
+        let manifest = manifest(
+            "include = [\".bashrc\", \".config/nvim\", \".local/bin/*\"]\n\
+             exclude = [\"**/*.swp\"]\n",
+        );
+
+        assert!(manifest.includes(Path::new(".bashrc")));
+        assert!(manifest.includes(Path::new(".config/nvim/init.lua")));
+        assert!(manifest.includes(Path::new(".config/nvim/lua/plugins.lua")));
+        assert!(manifest.includes(Path::new(".local/bin/backup")));
+        assert!(!manifest.includes(Path::new(".config/nvim/.init.lua.swp")));
+        assert!(!manifest.includes(Path::new(".config/git/config")));
+        assert!(!manifest.includes(Path::new(".profile")));
+    }
+
+    #[test]
+    fn includes_every_file_without_an_include_list_or_files() {
+        let manifest = manifest(
+            "description = \"My work setup\"\n\
+             exclude = [\"**/*.swp\"]\n",
+        );
+
+        assert!(manifest.includes(Path::new(".bashrc")));
+        assert!(manifest.includes(Path::new(".config/git/config")));
+        assert!(!manifest.includes(Path::new(".config/nvim/.init.lua.swp")));
+    }
+
+    #[test]
+    fn entries_can_leave_out_some_of_what_they_include() {
+        let manifest = manifest(
+            "[[files]]\n\
+             path = \".config\"\n\
+             exclude = [\".config/*/cache\"]\n\
+             \n\
+             [[files]]\n\
+             path = \".config/nvim/cache/keep\"\n",
+        );
+
+        assert!(manifest.includes(Path::new(".config/git/config")));
+        assert!(!manifest.includes(Path::new(".config/nvim/cache/swap")));
+        assert_eq!(
+            manifest
+                .entry(Path::new(".config/nvim/cache/keep"))
+                .map(|entry| entry.pattern.as_str()),
+            Some(".config/nvim/cache/keep")
+        );
+    }
+
+    #[test]
+    fn entries_can_say_how_their_files_are_linked() {
+        let manifest = manifest(
+            "include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \".config/Code\"\n\
+             mode = \"copy\"\n",
+        );
+
+        assert_eq!(manifest.mode(Path::new(".bashrc")), LinkMode::Symlink);
+        assert_eq!(
+            manifest.mode(Path::new(".config/Code/settings.json")),
+            LinkMode::Copy
+        );
+        assert!(matches!(
+            Manifest::parse(Path::new(MANIFEST_FILE_NAME), "[[files]]\npath = \"a\"\nmode = \"move\"\n"),
+            Err(Error::BadManifest(_, problem)) if problem.starts_with("line 3: ")
+        ));
+    }
+
+    #[test]
+    fn entries_can_always_fold_directories() {
+        let manifest = manifest(
+            "include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \".config/*\"\n\
+             exclude = [\".config/git\"]\n\
+             fold = true\n\
+             \n\
+             [[files]]\n\
+             path = \".vim\"\n\
+             mode = \"relative\"\n\
+             fold = true\n",
+        );
+
+        assert_eq!(
+            manifest.fold_mode(Path::new(".config/nvim")),
+            Some(LinkMode::Symlink)
+        );
+        assert_eq!(
+            manifest.fold_mode(Path::new(".vim")),
+            Some(LinkMode::Relative)
+        );
+        assert_eq!(manifest.fold_mode(Path::new(".config")), None);
+        assert_eq!(manifest.fold_mode(Path::new(".config/git")), None);
+        assert!(matches!(
+            Manifest::parse(Path::new(MANIFEST_FILE_NAME), "[[files]]\npath = \"a\"\nmode = \"copy\"\nfold = true\n"),
+            Err(Error::BadManifest(_, problem)) if problem.starts_with("line 2: ")
+        ));
+    }
+
+    #[test]
+    fn maps_shell_files_to_their_targets_and_back() {
+        let manifest = manifest(
+            "dot_prefix = true\n\
+             include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \"vscode\"\n\
+             target = \".config/Code/User\"\n\
+             \n\
+             [[files]]\n\
+             path = \"gitconfig\"\n\
+             target = \"~/.gitconfig\"\n",
+        );
+        let maps = |source: &str, path: &str| {
+            assert_eq!(manifest.target(Path::new(source)), PathBuf::from(path));
+            assert_eq!(manifest.source(Path::new(path)), PathBuf::from(source));
+        };
+
+        maps("vscode/settings.json", ".config/Code/User/settings.json");
+        maps(
+            "vscode/dot_snippets/rust.json",
+            ".config/Code/User/.snippets/rust.json",
+        );
+        maps("gitconfig", ".gitconfig");
+        maps("dot_config/nvim/init.lua", ".config/nvim/init.lua");
+        maps("README.md", "README.md");
+        assert_eq!(
+            target_path(Some(&manifest), Path::new("dot_bashrc.tmpl")),
+            PathBuf::from(".bashrc")
+        );
+    }
+
+    #[test]
+    fn expands_variables_in_targets() {
+        std::env::set_var("HERMIT_TEST_APPS", "/opt/apps");
+        let manifest = manifest(
+            "[[files]]\n\
+             path = \"tool\"\n\
+             target = \"${HERMIT_TEST_APPS}/tool\"\n\
+             outside_home = true\n",
+        );
+        assert_eq!(
+            manifest.target(Path::new("tool/config")),
+            PathBuf::from("/opt/apps/tool/config")
+        );
+
+        let problem =
+            |contents: &str| match Manifest::parse(Path::new(MANIFEST_FILE_NAME), contents) {
+                Err(Error::BadManifest(_, problem)) => problem,
+                other => panic!("Expected a bad manifest, not {:?}", other),
+            };
+        assert_eq!(
+            problem("[[files]]\npath = \"a\"\ntarget = \"$HERMIT_TEST_UNSET/a\"\n"),
+            "line 3: $HERMIT_TEST_UNSET isn't set"
+        );
+        assert!(problem("[[files]]\npath = \"a/*\"\ntarget = \"b\"\n").starts_with("line 2: "));
+    }
+
+    #[test]
+    fn keeps_targets_inside_the_home_directory() {
+        let problem =
+            |contents: &str| match Manifest::parse(Path::new(MANIFEST_FILE_NAME), contents) {
+                Err(Error::BadManifest(_, problem)) => problem,
+                other => panic!("Expected a bad manifest, not {:?}", other),
+            };
+        for target in &["/etc/hosts", "../elsewhere", ".config/../../elsewhere", "~"] {
+            let contents = format!("[[files]]\npath = \"a\"\ntarget = \"{}\"\n", target);
+            assert!(
+                problem(&contents).starts_with("line 3: "),
+                "{} should be refused",
+                target
+            );
+        }
+
+        let inside = manifest(
+            "[[files]]\n\
+             path = \"vscode\"\n\
+             target = \"./.config//Code/../Code/User/\"\n",
+        );
+        assert_eq!(
+            inside.target(Path::new("vscode/settings.json")),
+            PathBuf::from(".config/Code/User/settings.json")
+        );
+        let outside = manifest(
+            "[[files]]\n\
+             path = \"hosts\"\n\
+             target = \"/etc/./hosts\"\n\
+             outside_home = true\n",
+        );
+        assert_eq!(
+            outside.target(Path::new("hosts")),
+            PathBuf::from("/etc/hosts")
+        );
+    }
+
+    #[test]
+    fn records_link_modes_without_leaving_out_other_files() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+        let modes = vec![(PathBuf::from(".config/a[1].json"), LinkMode::Hardlink)];
+
+        let everything = manifest(&with_modes(shell_root, &modes));
+        assert!(everything.includes(Path::new(".bashrc")));
+        assert_eq!(
+            everything.mode(Path::new(".config/a[1].json")),
+            LinkMode::Hardlink
+        );
+
+        fs::write(
+            shell_root.join(MANIFEST_FILE_NAME),
+            "include = [\".vimrc\"]\n",
+        )
+        .unwrap();
+        let listed = manifest(&with_modes(shell_root, &modes));
+        assert!(!listed.includes(Path::new(".bashrc")));
+        assert!(listed.includes(Path::new(".vimrc")));
+
+        fs::write(
+            shell_root.join(MANIFEST_FILE_NAME),
+            "description = \"My work setup\"\n",
+        )
+        .unwrap();
+        let described = manifest(&with_modes(shell_root, &modes));
+        assert!(described.includes(Path::new(".bashrc")));
+        assert_eq!(described.description, Some("My work setup".to_owned()));
+    }
+
+    #[test]
+    fn reports_problems_by_file_and_line() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+        let path = shell_root.join(MANIFEST_FILE_NAME);
+        let problem = |contents: &str| {
+            fs::write(&path, contents).unwrap();
+            match Manifest::load(shell_root) {
+                Err(Error::BadManifest(bad_path, problem)) => {
+                    assert_eq!(bad_path, path);
+                    problem
+                }
+                other => panic!("Expected a bad manifest, not {:?}", other),
+            }
+        };
+
+        assert!(problem("include = [\".bashrc\" \".vimrc\"]\n").starts_with("line 1: "));
+        assert!(problem("description = \"\"\n\ninclde = []\n").starts_with("line 3: "));
+        assert!(problem("[[files]]\npath = \"a\"\nexclude = [\"[z-a\"]\n").starts_with("line 3: "));
+    }
+}
@@ -7,3 +7,70 @@ pub fn error_str<T: 'static + Into<String>>(details: T) -> String {
 pub fn error(failure: impl Display) -> String {
     format!("{}: error: {}", env::get_program_name(), failure)
 }
+
+/// Describes how long before `now` something happened at `then`, in
+/// the largest whole unit that fits.
+pub fn ago(then: SystemTime, now: SystemTime) -> String {
+    const UNITS: &[(&str, u64)] = &[
+        ("year", 365 * 24 * 60 * 60),
+        ("month", 30 * 24 * 60 * 60),
+        ("week", 7 * 24 * 60 * 60),
+        ("day", 24 * 60 * 60),
+        ("hour", 60 * 60),
+        ("minute", 60),
+    ];
+
+    let seconds = now.duration_since(then).unwrap_or_default().as_secs();
+    for (unit, length) in UNITS {
+        let count = seconds / length;
+        if count > 0 {
+            let plural = if count == 1 { "" } else { "s" };
+            return format!("{} {}{} ago", count, unit, plural);
+        }
+    }
+    "just now".to_owned()
+}
+
+/// Points at the line of `contents` that `offset` is on, to say what's
+/// wrong with it.
+pub fn at_line(contents: &str, offset: usize, problem: impl Display) -> String {
+    let line = contents[..offset.min(contents.len())].matches('\n').count() + 1;
+    format!("line {}: {}", line, problem)
+}
+
+/// Describes what's wrong with the TOML in `contents`, by line.
+pub fn toml_error(contents: &str, err: &toml::de::Error) -> String {
+    let offset = err.span().map_or(0, |span| span.start);
+    let message = err.message().lines().collect::<Vec<_>>().join(", ");
+    at_line(contents, offset, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_times_in_the_largest_unit_that_fits() {
+        let now = UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let before = |seconds| now - Duration::from_secs(seconds);
+
+        assert_eq!(ago(before(30), now), "just now");
+        assert_eq!(ago(before(60), now), "1 minute ago");
+        assert_eq!(ago(before(3 * 60 * 60 + 5), now), "3 hours ago");
+        assert_eq!(ago(before(10 * 24 * 60 * 60), now), "1 week ago");
+        assert_eq!(ago(before(400 * 24 * 60 * 60), now), "1 year ago");
+        assert_eq!(ago(now + Duration::from_secs(5), now), "just now");
+    }
+
+    #[test]
+    fn points_at_the_line_with_the_problem() {
+        let contents = "one = 1\ntwo = \nthree = 3\n";
+
+        assert_eq!(at_line(contents, 0, "oops"), "line 1: oops");
+        assert_eq!(at_line(contents, 8, "oops"), "line 2: oops");
+        assert_eq!(at_line(contents, 100, "oops"), "line 4: oops");
+
+        let err = contents.parse::<toml::Table>().unwrap_err();
+        assert!(toml_error(contents, &err).starts_with("line 2: "));
+    }
+}
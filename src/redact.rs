@@ -0,0 +1,194 @@
+use crate::common::*;
+
+use diffy::{DiffOptions, Hunk, Line, Patch};
+
+/// The suffix of the file that holds the secrets of a redacted file.
+/// It sits next to the redacted view of the file in the shell.
+pub const SECRETS_SUFFIX: &str = ".hermit-secrets";
+
+/// Where the secrets for the shell file at `path` are kept.
+pub fn secrets_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(SECRETS_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+pub fn is_secrets_file(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(SECRETS_SUFFIX))
+}
+
+/// Works out the secrets that were taken out of `complete` to leave
+/// `redacted`, as a unified diff from the redacted to the complete view.
+pub fn secrets(redacted: &str, complete: &str) -> String {
+    diffy::create_patch(redacted, complete).to_string()
+}
+
+/// Puts the `secrets` back into `redacted`. Returns `None` if the
+/// secrets don't fit, which happens when the redacted view has changed
+/// in a way that conflicts with them.
+pub fn complete(redacted: &str, secrets: &str) -> Option<String> {
+    let patch = Patch::from_str(secrets).ok()?;
+    diffy::apply(redacted, &patch).ok()
+}
+
+/// Takes the `secrets` back out of `complete`.
+pub fn redacted(complete: &str, secrets: &str) -> Option<String> {
+    let patch = Patch::from_str(secrets).ok()?;
+    diffy::apply(complete, &patch.reverse()).ok()
+}
+
+/// Folds the edits made to the `complete` view of a file back into its
+/// `redacted` view, leaving out the ones that `is_secret` picks.
+///
+/// `is_secret` is shown each change that adds lines, as `+`/`-`
+/// prefixed lines. Returns `None` if the old `secrets` can't be told
+/// apart from the edits, because the edits touched them.
+pub fn fold_changes(
+    redacted: &str,
+    secrets: &str,
+    complete: &str,
+    mut is_secret: impl FnMut(&str) -> bool,
+) -> Option<String> {
+    let edited = self::redacted(complete, secrets)?;
+    let patch = DiffOptions::new()
+        .set_context_len(0)
+        .create_patch(redacted, &edited);
+
+    let lines = redacted.split_inclusive('\n').collect::<Vec<_>>();
+    let mut folded = String::new();
+    let mut next = 0;
+    for hunk in patch.hunks() {
+        let range = hunk.old_range();
+        // Like unified diffs, an empty range starts at the line before it.
+        let start = if range.is_empty() {
+            range.start()
+        } else {
+            range.start() - 1
+        };
+        folded.extend(lines[next..start].iter().copied());
+        next = start + range.len();
+
+        let adds_lines = hunk
+            .lines()
+            .iter()
+            .any(|line| matches!(line, Line::Insert(_)));
+        if adds_lines && is_secret(&describe(hunk)) {
+            folded.extend(lines[start..next].iter().copied());
+        } else {
+            for line in hunk.lines() {
+                if let Line::Insert(inserted) = line {
+                    folded.push_str(inserted);
+                }
+            }
+        }
+    }
+    folded.extend(lines[next..].iter().copied());
+    Some(folded)
+}
+
+fn describe(hunk: &Hunk<'_, str>) -> String {
+    let mut description = String::new();
+    for line in hunk.lines() {
+        let (prefix, text) = match line {
+            Line::Context(text) => (' ', text),
+            Line::Delete(text) => ('-', text),
+            Line::Insert(text) => ('+', text),
+        };
+        description.push(prefix);
+        description.push_str(text.trim_end_matches('\n'));
+        description.push('\n');
+    }
+    description
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMPLETE: &str = "export PATH=~/bin:$PATH\n\
+                            export GITHUB_TOKEN=hunter2\n\
+                            alias ll='ls -l'\n";
+    const REDACTED: &str = "export PATH=~/bin:$PATH\n\
+                            alias ll='ls -l'\n";
+
+    #[test]
+    fn keeps_secrets_next_to_the_redacted_file() {
+        let path = secrets_path(Path::new("shells/work/.config/hub"));
+        assert_eq!(
+            path,
+            PathBuf::from("shells/work/.config/hub.hermit-secrets")
+        );
+        assert!(is_secrets_file(&path));
+        assert!(!is_secrets_file(Path::new("shells/work/.config/hub")));
+    }
+
+    #[test]
+    fn any_two_views_rebuild_the_third() {
+        let secrets = secrets(REDACTED, COMPLETE);
+
+        assert!(secrets.contains("+export GITHUB_TOKEN=hunter2"));
+        assert_eq!(complete(REDACTED, &secrets).as_deref(), Some(COMPLETE));
+        assert_eq!(redacted(COMPLETE, &secrets).as_deref(), Some(REDACTED));
+    }
+
+    #[test]
+    fn secrets_survive_unrelated_changes_to_the_redacted_view() {
+        let secrets = secrets(REDACTED, COMPLETE);
+        let edited = format!("# bash settings\n{}", REDACTED);
+
+        assert_eq!(
+            complete(&edited, &secrets),
+            Some(format!("# bash settings\n{}", COMPLETE))
+        );
+    }
+
+    #[test]
+    fn refuses_secrets_that_do_not_fit() {
+        let secrets = secrets(REDACTED, COMPLETE);
+
+        assert_eq!(complete("something else entirely\n", &secrets), None);
+        assert_eq!(redacted("something else entirely\n", &secrets), None);
+    }
+
+    #[test]
+    fn folds_changes_that_are_not_secret_into_the_redacted_view() {
+        let secrets = secrets(REDACTED, COMPLETE);
+        let edited = format!("{}export EDITOR=vim\nexport NPM_TOKEN=abc\n", COMPLETE);
+        let mut asked = vec![];
+
+        let folded = fold_changes(REDACTED, &secrets, &edited, |change| {
+            asked.push(change.to_owned());
+            false
+        });
+
+        assert_eq!(
+            folded,
+            Some(format!(
+                "{}export EDITOR=vim\nexport NPM_TOKEN=abc\n",
+                REDACTED
+            ))
+        );
+        assert_eq!(asked, vec!["+export EDITOR=vim\n+export NPM_TOKEN=abc\n"]);
+    }
+
+    #[test]
+    fn keeps_secret_changes_out_of_the_redacted_view() {
+        let secrets = secrets(REDACTED, COMPLETE);
+        let edited = format!("# settings\n{}export NPM_TOKEN=abc\n", COMPLETE);
+
+        let folded = fold_changes(REDACTED, &secrets, &edited, |change| {
+            change.contains("TOKEN")
+        });
+
+        assert_eq!(folded, Some(format!("# settings\n{}", REDACTED)));
+    }
+
+    #[test]
+    fn cannot_fold_changes_to_the_secrets_themselves() {
+        let secrets = secrets(REDACTED, COMPLETE);
+        let edited = COMPLETE.replace("hunter2", "hunter3");
+
+        assert_eq!(fold_changes(REDACTED, &secrets, &edited, |_| false), None);
+    }
+}
@@ -0,0 +1,174 @@
+use crate::common::*;
+
+use std::convert::TryInto;
+
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    XChaCha20Poly1305, XNonce,
+};
+
+/// The first word of every encrypted secrets file.
+const MAGIC: &str = "hermit-secrets-v1";
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+
+pub type Salt = [u8; SALT_LEN];
+
+/// The key that secrets files are encrypted with.
+///
+/// It's derived from a passphrase, so that every machine with the
+/// same passphrase can read the same secrets. The salt it was derived
+/// with is written into every file it encrypts, which is how another
+/// machine finds out what salt to use.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Key {
+    salt: Salt,
+    key: [u8; KEY_LEN],
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Key").field("salt", &self.salt).finish()
+    }
+}
+
+impl Key {
+    pub fn derive(passphrase: &str, salt: Salt) -> Key {
+        let mut key = [0; KEY_LEN];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .expect("Salt and key lengths are valid for argon2");
+        Key { salt, key }
+    }
+
+    pub fn new_salt() -> Salt {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    /// Reads a key saved by `to_file_contents`, if there is one.
+    pub fn load(path: &Path) -> Option<Key> {
+        let contents = fs::read_to_string(path).ok()?;
+        let (salt, key) = contents.trim().split_once(' ')?;
+        Some(Key {
+            salt: decode(salt)?,
+            key: decode(key)?,
+        })
+    }
+
+    pub fn to_file_contents(&self) -> String {
+        format!("{} {}\n", hex::encode(self.salt), hex::encode(self.key))
+    }
+
+    pub fn encrypt(&self, plaintext: &str) -> String {
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_bytes())
+            .expect("Encrypting into memory can't fail");
+        format!(
+            "{} {}\n{}{}\n",
+            MAGIC,
+            hex::encode(self.salt),
+            hex::encode(nonce),
+            hex::encode(ciphertext)
+        )
+    }
+
+    /// Decrypts the contents of a file written by `encrypt`. Returns
+    /// `None` if it was encrypted with a different key, or has been
+    /// tampered with.
+    pub fn decrypt(&self, contents: &str) -> Option<String> {
+        if salt_of(contents)? != self.salt {
+            return None;
+        }
+        let payload = hex::decode(contents.lines().nth(1)?).ok()?;
+        if payload.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, ciphertext) = payload.split_at(NONCE_LEN);
+        let cipher = XChaCha20Poly1305::new(&self.key.into());
+        let plaintext = cipher.decrypt(XNonce::from_slice(nonce), ciphertext).ok()?;
+        String::from_utf8(plaintext).ok()
+    }
+}
+
+pub fn is_encrypted(contents: &str) -> bool {
+    contents.starts_with(MAGIC)
+}
+
+/// The salt of the key that `contents` was encrypted with.
+pub fn salt_of(contents: &str) -> Option<Salt> {
+    let header = contents.lines().next()?;
+    let salt = header.strip_prefix(MAGIC)?.trim();
+    decode(salt)
+}
+
+fn decode<const N: usize>(text: &str) -> Option<[u8; N]> {
+    hex::decode(text).ok()?.try_into().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn can_decrypt_what_it_encrypted() {
+        let key = Key::derive("correct horse", Key::new_salt());
+        let encrypted = key.encrypt("password hunter2\n");
+
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("hunter2"));
+        assert_eq!(
+            key.decrypt(&encrypted).as_deref(),
+            Some("password hunter2\n")
+        );
+    }
+
+    #[test]
+    fn the_same_passphrase_and_salt_give_the_same_key() {
+        let salt = Key::new_salt();
+        let encrypted = Key::derive("correct horse", salt).encrypt("secret");
+
+        assert_eq!(salt_of(&encrypted), Some(salt));
+        assert_eq!(
+            Key::derive("correct horse", salt)
+                .decrypt(&encrypted)
+                .as_deref(),
+            Some("secret")
+        );
+        assert_eq!(
+            Key::derive("battery staple", salt).decrypt(&encrypted),
+            None
+        );
+    }
+
+    #[test]
+    fn refuses_tampered_secrets() {
+        let key = Key::derive("correct horse", Key::new_salt());
+        let encrypted = key.encrypt("secret");
+        let mut tampered = encrypted.trim_end().to_owned();
+        let last = tampered.pop().unwrap();
+        tampered.push(if last == '0' { '1' } else { '0' });
+
+        assert_eq!(key.decrypt(&tampered), None);
+    }
+
+    #[test]
+    fn can_save_and_load_a_key() {
+        let test_root_dir = set_up();
+        let key_path = test_root_dir.path().join("secrets.key");
+        let key = Key::derive("correct horse", Key::new_salt());
+
+        fs::write(&key_path, key.to_file_contents()).unwrap();
+
+        assert_eq!(Key::load(&key_path), Some(key));
+        assert_eq!(Key::load(&test_root_dir.path().join("missing")), None);
+    }
+}
@@ -1,13 +1,395 @@
 use crate::common::*;
 
+use std::{
+    collections::{BTreeSet, HashMap},
+    convert::TryFrom,
+    os::unix::fs::MetadataExt,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    file_operations::{links_into, relative_path, Op, DEFAULT_FILE_MODE, PRIVATE_FILE_MODE},
+    redact,
+    secrets::{self, Key},
+    state::Record,
+    template::{self, Variables},
+};
+
+/// What currently sits at a home path that a shell file should be
+/// linked to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// The path is a link to the shell file.
+    Linked,
+    /// Nothing is there.
+    Missing,
+    /// The path is a link, but to somewhere else.
+    LinkedElsewhere(PathBuf),
+    /// A real file or directory is in the way.
+    Blocked,
+    /// A real file is there, but it isn't what hermit would generate.
+    Changed,
+    /// A real file is there, but its template renders differently now.
+    Stale,
+    /// A real file is there, but it's out of sync with the shell file
+    /// it should be a copy or hard link of.
+    Drifted,
+}
+
+impl LinkState {
+    pub fn of(path: &Path, target: &Path) -> LinkState {
+        match fs::symlink_metadata(path) {
+            Err(_) => LinkState::Missing,
+            Ok(metadata) if !metadata.file_type().is_symlink() => LinkState::Blocked,
+            Ok(_) => match fs::read_link(path) {
+                Ok(ref link_target) if link_target == target => LinkState::Linked,
+                Ok(link_target) => LinkState::LinkedElsewhere(link_target),
+                Err(_) => LinkState::Blocked,
+            },
+        }
+    }
+
+    /// Like `of`, but for a path that should hold a copy of `source`,
+    /// or a hard link to it when `hard_link` is set.
+    pub fn of_copy(path: &Path, source: &Path, hard_link: bool) -> LinkState {
+        match fs::symlink_metadata(path) {
+            Err(_) => LinkState::Missing,
+            Ok(metadata) if metadata.file_type().is_symlink() => match fs::read_link(path) {
+                Ok(link_target) => LinkState::LinkedElsewhere(link_target),
+                Err(_) => LinkState::Blocked,
+            },
+            Ok(metadata) if metadata.is_dir() => LinkState::Blocked,
+            Ok(metadata) => {
+                let in_sync = if hard_link {
+                    fs::metadata(source).is_ok_and(|source| {
+                        source.dev() == metadata.dev() && source.ino() == metadata.ino()
+                    })
+                } else {
+                    matches!((fs::read(path), fs::read(source)), (Ok(a), Ok(b)) if a == b)
+                };
+                if in_sync {
+                    LinkState::Linked
+                } else {
+                    LinkState::Drifted
+                }
+            }
+        }
+    }
+
+    /// Like `of`, but for a path that should hold a generated file
+    /// with the given `contents`. When the contents can't be worked
+    /// out, any real file at the path counts as changed.
+    pub fn of_generated(path: &Path, contents: Option<&str>) -> LinkState {
+        match fs::symlink_metadata(path) {
+            Err(_) => LinkState::Missing,
+            Ok(metadata) if metadata.file_type().is_symlink() => match fs::read_link(path) {
+                Ok(link_target) => LinkState::LinkedElsewhere(link_target),
+                Err(_) => LinkState::Blocked,
+            },
+            Ok(metadata) if metadata.is_dir() => LinkState::Blocked,
+            Ok(_) => match (fs::read_to_string(path), contents) {
+                (Ok(ref current), Some(contents)) if current == contents => LinkState::Linked,
+                _ => LinkState::Changed,
+            },
+        }
+    }
+}
+
+/// Everything besides the shell itself that generated files are made
+/// from.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Inputs {
+    pub key: Option<Key>,
+    pub variables: Variables,
+}
+
+impl Inputs {
+    pub fn load(config: &impl Config) -> Result<Inputs> {
+        Ok(Inputs {
+            key: Key::load(&config.secrets_key_path()),
+            variables: Variables::gather(&config.machine_values_path())?,
+        })
+    }
+}
+
+/// How a shell file that hermit doesn't generate is put into the
+/// home directory.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum LinkMode {
+    /// As a symlink to the absolute path of the shell file.
+    #[default]
+    Symlink,
+    /// As a symlink to the shell file, relative to the link.
+    Relative,
+    /// As a copy of the shell file, for apps that replace symlinks
+    /// when they save.
+    Copy,
+    /// As a hard link to the shell file, for apps that won't follow
+    /// symlinks.
+    Hardlink,
+}
+
+impl std::str::FromStr for LinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<LinkMode, String> {
+        match s {
+            "symlink" => Ok(LinkMode::Symlink),
+            "relative" => Ok(LinkMode::Relative),
+            "copy" => Ok(LinkMode::Copy),
+            "hardlink" => Ok(LinkMode::Hardlink),
+            other => Err(format!("{} is not a link mode", other)),
+        }
+    }
+}
+
+impl LinkMode {
+    /// Whether files are put in place as symlinks, which are the only
+    /// way a whole directory can be.
+    pub fn is_symlink(self) -> bool {
+        matches!(self, LinkMode::Symlink | LinkMode::Relative)
+    }
+}
+
+impl TryFrom<String> for LinkMode {
+    type Error = String;
+
+    fn try_from(s: String) -> StdResult<LinkMode, String> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for LinkMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LinkMode::Symlink => "symlink",
+            LinkMode::Relative => "relative",
+            LinkMode::Copy => "copy",
+            LinkMode::Hardlink => "hardlink",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How a shell file is put into the home directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// As a link to the file in the shell.
+    Link(PathBuf),
+    /// As a link to the file in the shell, relative to the link.
+    RelativeLink(PathBuf),
+    /// As a copy of the file in the shell.
+    Copy(PathBuf),
+    /// As a hard link to the file in the shell.
+    HardLink(PathBuf),
+    /// As a real file with these contents, for redacted files.
+    Generate(String),
+    /// As a real file with these contents, for templates, with the
+    /// same permissions as the template.
+    Render(String, u32),
+}
+
+impl Placement {
+    /// Works out how the file at `path`, relative to `shell_root`,
+    /// gets into the home directory. Encrypted secrets need the key
+    /// in `inputs`, and templates its variables. Files that are
+    /// neither are placed according to `mode`.
+    pub fn of(
+        shell_root: &Path,
+        path: &Path,
+        mode: LinkMode,
+        inputs: &Inputs,
+    ) -> Result<Placement> {
+        let shell_file = shell_root.join(path);
+        if template::is_template(path) {
+            let permissions = fs::metadata(&shell_file)
+                .map_or(DEFAULT_FILE_MODE, |metadata| metadata.mode() & 0o777);
+            return template::render(shell_root, path, &inputs.variables)
+                .map(|contents| Placement::Render(contents, permissions));
+        }
+        match redacted_views(&shell_file, inputs.key.as_ref())? {
+            None => Ok(Placement::linked(shell_file, mode)),
+            Some((redacted, secrets)) => redact::complete(&redacted, &secrets)
+                .map(Placement::Generate)
+                .ok_or(Error::SecretsDoNotApply(shell_file)),
+        }
+    }
+
+    /// Places the `shell_file` according to `mode`.
+    pub fn linked(shell_file: PathBuf, mode: LinkMode) -> Placement {
+        match mode {
+            LinkMode::Symlink => Placement::Link(shell_file),
+            LinkMode::Relative => Placement::RelativeLink(shell_file),
+            LinkMode::Copy => Placement::Copy(shell_file),
+            LinkMode::Hardlink => Placement::HardLink(shell_file),
+        }
+    }
+
+    /// Classifies `path` against this placement. A render that no
+    /// longer matches its template is only stale if it's still what
+    /// hermit `recorded` rendering there last time, otherwise it has
+    /// been edited by hand.
+    pub fn state(&self, path: &Path, recorded: Option<&Record>) -> LinkState {
+        match self {
+            Placement::Link(target) => LinkState::of(path, target),
+            Placement::RelativeLink(target) => {
+                let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+                LinkState::of(path, &relative_path(target, dir))
+            }
+            Placement::Copy(source) => LinkState::of_copy(path, source, false),
+            Placement::HardLink(target) => LinkState::of_copy(path, target, true),
+            Placement::Generate(contents) => LinkState::of_generated(path, Some(contents)),
+            Placement::Render(contents, _) => match LinkState::of_generated(path, Some(contents)) {
+                LinkState::Changed
+                    if recorded.is_some_and(|record| record.state() == LinkState::Linked) =>
+                {
+                    LinkState::Stale
+                }
+                state => state,
+            },
+        }
+    }
+
+    /// The operation that puts this file at the home-relative `path`.
+    pub fn op(&self, path: &Path) -> Op {
+        match self {
+            Placement::Link(target) => Op::Link {
+                path: path.to_path_buf(),
+                target: target.clone(),
+            },
+            Placement::RelativeLink(target) => Op::RelativeLink {
+                path: path.to_path_buf(),
+                target: target.clone(),
+            },
+            Placement::Copy(source) => Op::Copy {
+                from: source.clone(),
+                to: path.to_path_buf(),
+            },
+            Placement::HardLink(target) => Op::HardLink {
+                path: path.to_path_buf(),
+                target: target.clone(),
+            },
+            Placement::Generate(contents) => Op::WriteFile {
+                path: path.to_path_buf(),
+                contents: contents.clone(),
+                mode: PRIVATE_FILE_MODE,
+            },
+            Placement::Render(contents, mode) => Op::WriteFile {
+                path: path.to_path_buf(),
+                contents: contents.clone(),
+                mode: *mode,
+            },
+        }
+    }
+}
+
+/// What to do when something is already in the way of a link.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Leave whatever is there alone, and don't link the file.
+    #[default]
+    Skip,
+    /// Move whatever is there aside, then link the file.
+    Backup,
+    /// Remove whatever is there, then link the file.
+    Overwrite,
+    /// Give up without linking anything.
+    Abort,
+}
+
+impl std::str::FromStr for ConflictPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> StdResult<ConflictPolicy, String> {
+        match s {
+            "skip" => Ok(ConflictPolicy::Skip),
+            "backup" => Ok(ConflictPolicy::Backup),
+            "overwrite" => Ok(ConflictPolicy::Overwrite),
+            "abort" => Ok(ConflictPolicy::Abort),
+            other => Err(format!("{} is not a conflict policy", other)),
+        }
+    }
+}
+
+/// A home path that was left alone because something was in the way.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    pub path: PathBuf,
+    pub state: LinkState,
+}
+
+impl fmt::Display for Conflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.state {
+            LinkState::LinkedElsewhere(ref target) => write!(
+                f,
+                "{} already links to {}",
+                self.path.display(),
+                target.display()
+            ),
+            LinkState::Changed => write!(
+                f,
+                "{} has changes that aren't in the shell yet",
+                self.path.display()
+            ),
+            LinkState::Stale => write!(
+                f,
+                "{} is out of date with its template",
+                self.path.display()
+            ),
+            LinkState::Drifted => write!(
+                f,
+                "{} is out of sync with the shell file",
+                self.path.display()
+            ),
+            _ => write!(f, "{} is a real file or directory", self.path.display()),
+        }
+    }
+}
+
+/// A file that one of the layers of a shell puts into the home
+/// directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LayeredFile {
+    /// The name of the shell the file comes from.
+    pub layer: ShellName,
+    /// Where the file is, relative to the root of that shell.
+    pub source: PathBuf,
+    /// Where the file goes, relative to the home directory.
+    pub path: PathBuf,
+    /// How the file goes there, unless it's generated.
+    pub mode: LinkMode,
+    /// Whether this is a whole directory of files, linked as one.
+    pub folded: bool,
+}
+
+/// The files of a shell as they're put into a home directory, with
+/// directories folded into single links wherever they can be.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Folding {
+    pub files: Vec<LayeredFile>,
+    /// Home-relative directories that were folded into a layer, by the
+    /// root of that layer, but that have to be unfolded now.
+    pub unfold: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Folding {
+    /// Whether the home-relative `path` is inside a directory that has
+    /// to be unfolded.
+    pub fn is_unfolding(&self, path: &Path) -> bool {
+        self.unfold.iter().any(|(dir, _)| path.starts_with(dir))
+    }
+}
+
 pub struct Shell<T: Config> {
-    pub name: String,
+    pub name: ShellName,
     pub config: Rc<T>,
 }
 
 impl<T: Config> Shell<T> {
-    pub fn new(name: impl Into<String>, config: Rc<T>) -> Shell<T> {
-        let name = name.into();
+    pub fn new(name: ShellName, config: Rc<T>) -> Shell<T> {
         Shell { name, config }
     }
 
@@ -20,18 +402,628 @@ impl<T: Config> Shell<T> {
         self.root_path().join(filename)
     }
 
-    pub fn link(&self, file_operations: &mut FileOperations) {
-        let shell_root = self.root_path();
-        for path in self.config.shell_files(&self.name) {
-            file_operations.link(&path, shell_root.join(&path))
+    /// Links every file in this shell into the file operations root,
+    /// folding directories that nothing else shares into one link, and
+    /// unfolding those that have come to be shared.
+    ///
+    /// Paths that are already linked correctly are left alone, and
+    /// anything else in the way is handled according to `policy`.
+    /// The conflicts that were skipped are returned.
+    pub fn link(
+        &self,
+        file_operations: &mut FileOperations,
+        policy: ConflictPolicy,
+    ) -> Result<Vec<Conflict>> {
+        let inputs = Inputs::load(&*self.config)?;
+        let recorded = State::load(&self.config.state_path())?;
+        let home = file_operations.root().to_path_buf();
+        let folding = fold(&*self.config, &self.name, &home, |path| {
+            file_operations.will_remove(path)
+        })?;
+        for (dir, owner) in &folding.unfold {
+            file_operations.remove_link(dir, owner);
+        }
+        let mut conflicts = vec![];
+        for file in folding.files {
+            let path = file.path;
+            let home_path = file_operations.root().join(&path);
+            let layer_root = self.config.shell_root_path().join(&file.layer);
+            let placement = Placement::of(&layer_root, &file.source, file.mode, &inputs)?;
+            let record = Record::new(&home_path, &layer_root.join(&file.source), &placement);
+            let state = if file_operations.will_remove(&home_path) {
+                LinkState::Missing
+            } else {
+                placement.state(&home_path, recorded.get(&home_path))
+            };
+
+            match (state, policy) {
+                (LinkState::Linked, _) => {
+                    file_operations.record(record);
+                    continue;
+                }
+                (LinkState::Missing, _) => (),
+                (state, ConflictPolicy::Skip) => {
+                    conflicts.push(Conflict { path, state });
+                    continue;
+                }
+                (_, ConflictPolicy::Abort) => return Err(Error::LinkConflict(home_path)),
+                (_, ConflictPolicy::Backup) => {
+                    file_operations.move_file(&path, backup_path(&home_path))
+                }
+                (LinkState::Blocked, ConflictPolicy::Overwrite) if home_path.is_dir() => {
+                    file_operations.remove_dir(&path)
+                }
+                (_, ConflictPolicy::Overwrite) => file_operations.remove(&path),
+            }
+            let parent = path
+                .parent()
+                .filter(|parent| !parent.as_os_str().is_empty());
+            if let Some(parent) = parent {
+                let home_parent = file_operations.root().join(parent);
+                let exists = home_parent.is_dir() && !file_operations.will_remove(parent);
+                if !exists && !file_operations.will_create_dir(parent) {
+                    file_operations.create_dir(parent);
+                }
+            }
+            file_operations.push(placement.op(&home_path));
+            file_operations.record(record);
+        }
+        Ok(conflicts)
+    }
+
+    /// Moves a home-relative `path` to `source` in this shell and
+    /// leaves a link to its new location behind, of the kind `mode`
+    /// asks for.
+    pub fn add(
+        &self,
+        file_operations: &mut FileOperations,
+        path: impl AsRef<Path>,
+        source: impl AsRef<Path>,
+        mode: LinkMode,
+    ) {
+        let path = path.as_ref();
+        let shell_path = self.root_path().join(source);
+        if let Some(parent) = shell_path.parent() {
+            file_operations.create_dir(parent);
         }
+        file_operations.move_file(path, &shell_path);
+        let home_path = file_operations.root().join(path);
+        let placement = Placement::linked(shell_path.clone(), mode);
+        file_operations.push(placement.op(&home_path));
+        file_operations.record(Record::moved(&home_path, &shell_path, &placement));
     }
 
-    pub fn unlink(&self, file_operations: &mut FileOperations) {
-        for path in self.config.shell_files(&self.name) {
-            file_operations.remove(&path)
+    /// Moves the secrets out of the home file at `path`, storing its
+    /// `redacted` view and the secrets, encrypted with `key`, at
+    /// `source` in this shell. The home file is then rewritten as the
+    /// `complete` view that linking would generate, which only its
+    /// owner can read, and recorded like one.
+    pub fn add_redacted(
+        &self,
+        file_operations: &mut FileOperations,
+        path: impl AsRef<Path>,
+        source: impl AsRef<Path>,
+        redacted: &str,
+        complete: &str,
+        key: &Key,
+    ) {
+        let shell_path = self.root_path().join(&source);
+        if let Some(parent) = shell_path.parent() {
+            file_operations.create_dir(parent);
         }
+        self.write_redacted(file_operations, source, redacted, complete, key);
+        let home_path = file_operations.root().join(path);
+        let placement = Placement::Generate(complete.to_owned());
+        file_operations.push(placement.op(&home_path));
+        file_operations.record(Record::new(&home_path, &shell_path, &placement));
     }
+
+    /// Replaces the redacted view and secrets of the redacted shell
+    /// file at `path` with the ones for the new `complete` view.
+    pub fn write_redacted(
+        &self,
+        file_operations: &mut FileOperations,
+        path: impl AsRef<Path>,
+        redacted: &str,
+        complete: &str,
+        key: &Key,
+    ) {
+        let shell_path = self.root_path().join(path);
+        let secrets = key.encrypt(&redact::secrets(redacted, complete));
+        file_operations.write_file(redact::secrets_path(&shell_path), secrets);
+        file_operations.write_file(shell_path, redacted);
+    }
+
+    /// What this shell's manifest says it's for, if anything.
+    pub fn description(&self) -> Result<Option<String>> {
+        manifest::description(&self.root_path())
+    }
+
+    /// The key for this shell's encrypted secrets, if they're unlocked.
+    pub fn secrets_key(&self) -> Option<Key> {
+        Key::load(&self.config.secrets_key_path())
+    }
+
+    /// The names of the shells this one is layered from, starting
+    /// with the bottom layer and ending with this shell.
+    pub fn layers(&self) -> Result<Vec<ShellName>> {
+        layers(&*self.config, &self.name)
+    }
+
+    /// The files that this shell's layers put into the home directory.
+    pub fn files(&self) -> Result<Vec<LayeredFile>> {
+        layered_files(&*self.config, &self.name)
+    }
+
+    /// Classifies the home path of every file in this shell, and of
+    /// every directory it folds, where `home` is the directory the
+    /// shell gets linked into. Files whose secrets can't be read, or
+    /// whose templates can't be rendered, can't be classified, and
+    /// files in a directory that has to be unfolded are missing.
+    pub fn link_states(&self, home: &Path) -> Result<Vec<(LayeredFile, Result<LinkState>)>> {
+        let inputs = Inputs::load(&*self.config);
+        let recorded = State::load(&self.config.state_path())?;
+        let folding = fold(&*self.config, &self.name, home, |_| false)?;
+        let states = folding
+            .files
+            .iter()
+            .cloned()
+            .map(|file| {
+                if folding.is_unfolding(&file.path) {
+                    return (file, Ok(LinkState::Missing));
+                }
+                let layer_root = self.config.shell_root_path().join(&file.layer);
+                let state = inputs
+                    .clone()
+                    .and_then(|inputs| Placement::of(&layer_root, &file.source, file.mode, &inputs))
+                    .map(|placement| {
+                        let home_path = home.join(&file.path);
+                        placement.state(&home_path, recorded.get(&home_path))
+                    });
+                (file, state)
+            })
+            .collect();
+        Ok(states)
+    }
+
+    /// Removes what hermit recorded putting into the file operations
+    /// root from this shell, then the links into the shell that it
+    /// didn't record, including links to whole directories of it.
+    ///
+    /// Only files that are still as hermit recorded leaving them,
+    /// symlinks that point inside one of the shell's layers, and
+    /// copies and hard links that are still in sync, are removed,
+    /// unless `force` is set, in which case whatever is at each path
+    /// goes. When the shell's layers or files can't be worked out any
+    /// more, what was recorded is still removed. The paths that were
+    /// left alone are returned.
+    pub fn unlink(
+        &self,
+        file_operations: &mut FileOperations,
+        force: bool,
+    ) -> Result<Vec<Conflict>> {
+        let shell_root_path = self.config.shell_root_path();
+        let state = State::load(&self.config.state_path())?;
+        let home = file_operations.root().to_path_buf();
+        let tree = self.layers().and_then(|layers| {
+            let folding = fold(&*self.config, &self.name, &home, |_| false)?;
+            Ok((layers, folding))
+        });
+        let (layer_roots, folding) = match tree {
+            Ok((layers, folding)) => (
+                layers
+                    .iter()
+                    .map(|layer| shell_root_path.join(layer))
+                    .collect::<Vec<_>>(),
+                folding,
+            ),
+            Err(_) => (vec![shell_root_path.clone()], Folding::default()),
+        };
+        let current = folding
+            .files
+            .iter()
+            .map(|file| home.join(&file.path))
+            .collect::<Vec<_>>();
+
+        let mut skipped = vec![];
+        let mut handled = vec![];
+        let recorded = state.records.iter().filter(|record| {
+            record.path.starts_with(&home)
+                && layer_roots
+                    .iter()
+                    .any(|root| record.source.starts_with(root))
+        });
+        for record in recorded {
+            let in_tree = current
+                .iter()
+                .any(|path| path.starts_with(&record.path) || record.path.starts_with(path));
+            let path = record
+                .path
+                .strip_prefix(&home)
+                .unwrap_or(&record.path)
+                .to_path_buf();
+            match record.state() {
+                LinkState::Missing => file_operations.forget(&record.path),
+                LinkState::Linked => remove_recorded(file_operations, record),
+                _ if in_tree => continue,
+                LinkState::Blocked if force && record.path.is_dir() => {
+                    file_operations.remove_dir(&record.path)
+                }
+                _ if force => file_operations.remove(&record.path),
+                state => skipped.push(Conflict { path, state }),
+            }
+            handled.push(record.path.clone());
+        }
+        let handled = |path: &Path| handled.iter().any(|done| path.starts_with(done));
+
+        let inputs = Inputs::load(&*self.config).unwrap_or_default();
+        for (dir, owner) in &folding.unfold {
+            if !handled(&home.join(dir)) {
+                file_operations.remove_link(dir, owner);
+            }
+        }
+        for file in folding.files.iter().cloned() {
+            let home_path = home.join(&file.path);
+            if folding.is_unfolding(&file.path) || handled(&home_path) {
+                continue;
+            }
+            let path = file.path;
+            let layer_root = shell_root_path.join(&file.layer);
+            let placement = Placement::of(&layer_root, &file.source, file.mode, &inputs).ok();
+            let owner = layer_roots.iter().find(|root| links_into(&home_path, root));
+            let recorded = state.get(&home_path);
+            match (
+                state_of(&home_path, placement.as_ref(), recorded),
+                placement,
+            ) {
+                (LinkState::Missing, _) => (),
+                (LinkState::Linked, Some(Placement::Generate(_)))
+                | (LinkState::Linked, Some(Placement::Render(..)))
+                | (LinkState::Linked, Some(Placement::Copy(_)))
+                | (LinkState::Linked, Some(Placement::HardLink(_))) => {
+                    file_operations.remove(&path)
+                }
+                (LinkState::Linked, _) => file_operations.remove_link(&path, &layer_root),
+                _ if owner.is_some() => {
+                    file_operations.remove_link(&path, owner.expect("Checked just above"))
+                }
+                (LinkState::Blocked, _) if force && home_path.is_dir() => {
+                    file_operations.remove_dir(&path)
+                }
+                _ if force => file_operations.remove(&path),
+                (state, _) => skipped.push(Conflict { path, state }),
+            }
+        }
+        Ok(skipped)
+    }
+
+    /// What hermit recorded putting into `home` from this shell's
+    /// layers that the shell no longer has, like files that a pull
+    /// deleted from it.
+    pub fn orphans(&self, home: &Path) -> Result<Vec<Record>> {
+        let state = State::load(&self.config.state_path())?;
+        let shell_root_path = self.config.shell_root_path();
+        let layer_roots = self
+            .layers()?
+            .iter()
+            .map(|layer| shell_root_path.join(layer))
+            .collect::<Vec<_>>();
+        let current = fold(&*self.config, &self.name, home, |_| false)?
+            .files
+            .iter()
+            .map(|file| home.join(&file.path))
+            .collect::<Vec<_>>();
+        let orphans = state
+            .records
+            .into_iter()
+            .filter(|record| record.path.starts_with(home))
+            .filter(|record| {
+                layer_roots
+                    .iter()
+                    .any(|root| record.source.starts_with(root))
+            })
+            .filter(|record| {
+                !current
+                    .iter()
+                    .any(|path| path.starts_with(&record.path) || record.path.starts_with(path))
+            })
+            .collect();
+        Ok(orphans)
+    }
+}
+
+/// The names of the shells that the shell called `name` is layered
+/// from, starting with the bottom layer and ending with `name`. A base
+/// shared by several layers only appears once, as low as it can.
+pub fn layers(config: &impl Config, name: &ShellName) -> Result<Vec<ShellName>> {
+    let mut layers = vec![];
+    add_layers(config, name, &mut vec![], &mut layers)?;
+    Ok(layers)
+}
+
+fn add_layers(
+    config: &impl Config,
+    name: &ShellName,
+    above: &mut Vec<ShellName>,
+    layers: &mut Vec<ShellName>,
+) -> Result<()> {
+    if above.contains(name) {
+        return Err(Error::ShellBasedOnItself(name.to_string()));
+    }
+    if layers.contains(name) {
+        return Ok(());
+    }
+
+    above.push(name.clone());
+    for base in config.base_shells(name)? {
+        if !config.shell_exists(&base) {
+            return Err(Error::MissingBaseShell {
+                shell: name.to_string(),
+                base: base.to_string(),
+            });
+        }
+        add_layers(config, &base, above, layers)?;
+    }
+    above.pop();
+    layers.push(name.clone());
+    Ok(())
+}
+
+/// The files that the layers of the shell called `name` put into the
+/// home directory. Where two layers have a file for the same path, the
+/// higher layer's file wins.
+pub fn layered_files(config: &impl Config, name: &ShellName) -> Result<Vec<LayeredFile>> {
+    let mut files = vec![];
+    let mut indices = HashMap::new();
+    for layer in layers(config, name)? {
+        let manifest = Manifest::load(&config.shell_root_path().join(&layer))?;
+        for source in config.shell_files(&layer)? {
+            let path = manifest::target_path(manifest.as_ref(), &source);
+            let mode = manifest
+                .as_ref()
+                .map(|manifest| manifest.mode(&source))
+                .unwrap_or_default();
+            let file = LayeredFile {
+                layer: layer.clone(),
+                source,
+                path: path.clone(),
+                mode,
+                folded: false,
+            };
+            match indices.get(&path) {
+                Some(&index) => files[index] = file,
+                None => {
+                    indices.insert(path, files.len());
+                    files.push(file);
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Folds the files of the shell called `name` into whole directories,
+/// for linking into `home`, where `removed` says which home paths are
+/// about to be removed.
+///
+/// A directory is folded when all of its files come from one layer and
+/// are symlinked as they are, when that layer has nothing else in the
+/// directory, when there's no real directory in the way at home, and
+/// when it sits in a real directory there already. So directories at
+/// the top of home, and ones whose parents hermit would have to make,
+/// are never folded on their own. A manifest can also say a directory
+/// is always folded, in which case it hides whatever lower layers have
+/// in it. Directories that are already folded at home, but shouldn't
+/// be any more, are unfolded.
+pub fn fold(
+    config: &impl Config,
+    name: &ShellName,
+    home: &Path,
+    removed: impl Fn(&Path) -> bool,
+) -> Result<Folding> {
+    let files = layered_files(config, name)?;
+    let mut layers = vec![];
+    for layer in self::layers(config, name)? {
+        let root = config.shell_root_path().join(&layer);
+        let manifest = Manifest::load(&root)?;
+        layers.push((layer, root, manifest));
+    }
+
+    let mut dirs = files
+        .iter()
+        .flat_map(|file| file.path.ancestors().skip(1))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .map(Path::to_path_buf)
+        .collect::<Vec<_>>();
+    dirs.sort_by_key(|dir| (dir.components().count(), dir.clone()));
+    dirs.dedup();
+
+    let mut folding = Folding::default();
+    let mut folded: Vec<LayeredFile> = vec![];
+    for dir in dirs {
+        if folded.iter().any(|file| dir.starts_with(&file.path)) {
+            continue;
+        }
+        let home_dir = home.join(&dir);
+        let replaced = removed(&home_dir) || folding.is_unfolding(&dir);
+        let is_link = !replaced
+            && fs::symlink_metadata(&home_dir)
+                .is_ok_and(|metadata| metadata.file_type().is_symlink());
+        let is_dir = !replaced && !is_link && home_dir.is_dir();
+        let in_real_dir = dir
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .is_some_and(|parent| {
+                let home_parent = home.join(parent);
+                folding.is_unfolding(parent)
+                    || !removed(&home_parent)
+                        && fs::symlink_metadata(&home_parent)
+                            .is_ok_and(|metadata| metadata.is_dir())
+            });
+
+        let always = layers.iter().rev().find_map(|(layer, root, manifest)| {
+            let source = files
+                .iter()
+                .filter(|file| file.layer == *layer)
+                .find_map(|file| source_dir(&dir, file))?;
+            let mode = manifest.as_ref()?.fold_mode(&source)?;
+            Some(LayeredFile {
+                layer: layer.clone(),
+                source: source.clone(),
+                path: dir.clone(),
+                mode,
+                folded: true,
+            })
+            .filter(|_| root.join(&source).is_dir())
+        });
+        let fold = match always {
+            Some(file) => Some(file),
+            None if is_dir || !in_real_dir => None,
+            None => foldable(&dir, &files, &layers),
+        };
+        match fold {
+            Some(file) => folded.push(file),
+            None if is_link => {
+                let owner = layers
+                    .iter()
+                    .find(|(_, root, _)| links_into(&home_dir, root));
+                if let Some((_, root, _)) = owner {
+                    folding.unfold.push((dir, root.clone()));
+                }
+            }
+            None => (),
+        }
+    }
+
+    for file in files {
+        match folded
+            .iter()
+            .position(|dir| file.path.starts_with(&dir.path))
+        {
+            Some(index) => {
+                if !folding.files.contains(&folded[index]) {
+                    folding.files.push(folded[index].clone());
+                }
+            }
+            None => folding.files.push(file),
+        }
+    }
+    Ok(folding)
+}
+
+/// The home-relative `dir` folded into a single link, if every file in
+/// it comes from one layer, is symlinked, and is laid out the same way
+/// in the layer, and the layer has nothing else there that would show
+/// through.
+fn foldable(
+    dir: &Path,
+    files: &[LayeredFile],
+    layers: &[(ShellName, PathBuf, Option<Manifest>)],
+) -> Option<LayeredFile> {
+    let inside = files
+        .iter()
+        .filter(|file| file.path.starts_with(dir))
+        .collect::<Vec<_>>();
+    let first = inside.first()?;
+    let source = source_dir(dir, first)?;
+    let plain = inside.iter().all(|file| {
+        file.layer == first.layer
+            && file.mode == first.mode
+            && source_dir(dir, file).as_ref() == Some(&source)
+    });
+    if !plain || !first.mode.is_symlink() {
+        return None;
+    }
+
+    let (_, root, _) = layers.iter().find(|(layer, _, _)| *layer == first.layer)?;
+    let on_disk = WalkDir::new(root.join(&source))
+        .follow_links(false)
+        .into_iter()
+        .filter_map(StdResult::ok)
+        .filter(|entry| !entry.file_type().is_dir())
+        .filter_map(|entry| entry.path().strip_prefix(root).ok().map(Path::to_path_buf))
+        .collect::<BTreeSet<_>>();
+    let included = inside
+        .iter()
+        .map(|file| file.source.clone())
+        .collect::<BTreeSet<_>>();
+    let file = LayeredFile {
+        layer: first.layer.clone(),
+        source,
+        path: dir.to_path_buf(),
+        mode: first.mode,
+        folded: true,
+    };
+    Some(file).filter(|_| on_disk == included)
+}
+
+/// The directory in its layer that `file` comes from which matches the
+/// home-relative `dir` it goes into, if everything below the two is
+/// named the same.
+fn source_dir(dir: &Path, file: &LayeredFile) -> Option<PathBuf> {
+    let below = file.path.strip_prefix(dir).ok()?;
+    let mut source = file.source.clone();
+    for name in below.iter().rev() {
+        if source.file_name() != Some(name) {
+            return None;
+        }
+        source.pop();
+    }
+    Some(source).filter(|source| !source.as_os_str().is_empty())
+}
+
+/// Reads the redacted view and the decrypted secrets of `shell_file`,
+/// or `None` if it isn't redacted.
+pub fn redacted_views(shell_file: &Path, key: Option<&Key>) -> Result<Option<(String, String)>> {
+    let secrets_file = redact::secrets_path(shell_file);
+    if !secrets_file.exists() {
+        return Ok(None);
+    }
+
+    let unreadable = || Error::SecretsDoNotApply(shell_file.to_path_buf());
+    let redacted = fs::read_to_string(shell_file).map_err(|_| unreadable())?;
+    let mut secrets = fs::read_to_string(&secrets_file).map_err(|_| unreadable())?;
+    if secrets::is_encrypted(&secrets) {
+        secrets = key
+            .ok_or(Error::SecretsLocked)?
+            .decrypt(&secrets)
+            .ok_or_else(|| Error::CannotDecryptSecrets(shell_file.to_path_buf()))?;
+    }
+    Ok(Some((redacted, secrets)))
+}
+
+/// Classifies `path` against its `placement`, if that could be worked
+/// out. Without one, only its absence can be relied on.
+fn state_of(path: &Path, placement: Option<&Placement>, recorded: Option<&Record>) -> LinkState {
+    match placement {
+        Some(placement) => placement.state(path, recorded),
+        None => LinkState::of_generated(path, None),
+    }
+}
+
+/// Removes what hermit recorded putting at the path of `record`.
+fn remove_recorded(file_operations: &mut FileOperations, record: &Record) {
+    if record.is_link() {
+        file_operations.remove_link(&record.path, &record.source);
+    } else {
+        file_operations.remove(&record.path);
+    }
+}
+
+/// Finds a free name next to `path` to move its current contents to.
+fn backup_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".hermit-backup");
+
+    let mut backup = path.with_file_name(&file_name);
+    let mut count = 1;
+    while fs::symlink_metadata(&backup).is_ok() {
+        let mut numbered = file_name.clone();
+        numbered.push(format!(".{}", count));
+        backup = path.with_file_name(numbered);
+        count += 1;
+    }
+    backup
 }
 
 #[cfg(test)]
@@ -39,11 +1031,16 @@ mod tests {
     use super::*;
 
     use std::{
+        fs,
+        os::unix::{self, fs::PermissionsExt},
         path::{Path, PathBuf},
         rc::Rc,
     };
 
-    use crate::{config::mock::MockConfig, file_operations::Op, test_helpers::ops::*};
+    use crate::{
+        config::mock::MockConfig, file_operations::Op, test_helpers::filesystem::set_up,
+        test_helpers::ops::*, test_helpers::shell_name,
+    };
 
     fn root_path(path_str: &str) -> PathBuf {
         PathBuf::from(path_str)
@@ -56,14 +1053,17 @@ mod tests {
     #[test]
     fn has_a_name() {
         let config = mock_config("/");
-        let s = Shell::new("my_shell", config);
+        let s = Shell::new(shell_name("my_shell"), config);
         assert_eq!(s.name, "my_shell");
     }
 
     #[test]
     fn has_a_string_name() {
         let config = mock_config("/");
-        let s = Shell::new(String::from("my_shell"), config);
+        let name = String::from("my_shell")
+            .parse()
+            .expect("Invalid shell name");
+        let s = Shell::new(name, config);
         assert_eq!(s.name, "my_shell");
     }
 
@@ -71,7 +1071,7 @@ mod tests {
     fn can_resolve_its_path() {
         let root_path = root_path("/some/random/path");
         let config = mock_config(root_path.clone());
-        let s = Shell::new("default", config);
+        let s = Shell::new(shell_name("default"), config);
 
         let expected_path = root_path.join("shells").join("default");
         assert_eq!(s.root_path(), expected_path);
@@ -81,7 +1081,7 @@ mod tests {
     fn resolves_empty_string_to_root() {
         let root_path = root_path("/Users/geoff/.config/hermit");
         let config = mock_config(root_path.clone());
-        let s = Shell::new("default", config);
+        let s = Shell::new(shell_name("default"), config);
 
         let expected_path = root_path.join("shells").join("default");
         assert_eq!(s.path_for(""), expected_path);
@@ -91,7 +1091,7 @@ mod tests {
     fn can_resolve_paths() {
         let root_path = root_path("/Users/geoff/.config/hermit");
         let config = mock_config(root_path.clone());
-        let s = Shell::new("default", config);
+        let s = Shell::new(shell_name("default"), config);
 
         let expected_path = root_path.join("shells").join("default").join(".bashrc");
         assert_eq!(s.path_for(".bashrc"), expected_path);
@@ -102,39 +1102,1106 @@ mod tests {
         let root_path = root_path("/Users/geoff/.config/hermit");
         let mut config = MockConfig::with_root(&root_path);
         config.set_paths(vec![".bashrc", ".boot/profile.boot"]);
-        let s = Shell::new("default", Rc::new(config));
+        let s = Shell::new(shell_name("default"), Rc::new(config));
         let op_root = PathBuf::from("op_root");
         let mut file_ops = FileOperations::rooted_at(&op_root);
 
-        s.link(&mut file_ops);
+        s.link(&mut file_ops, ConflictPolicy::Skip)
+            .expect("Link failed");
 
         let shell_root = s.root_path();
         assert_eq!(
             file_ops.operations(),
             &vec![
                 link_op_for(&shell_root, &op_root, ".bashrc"),
+                Op::MkDir(op_root.join(".boot")),
                 link_op_for(&shell_root, &op_root, ".boot/profile.boot")
             ]
         );
     }
 
+    fn conflicted_shell(home: &Path) -> Shell<MockConfig> {
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".bashrc", ".vimrc", ".emacs", ".profile"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let shell_root = s.root_path();
+        unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        fs::write(home.join(".vimrc"), "").unwrap();
+        fs::write(home.join(".vimrc.hermit-backup"), "").unwrap();
+        unix::fs::symlink("/elsewhere", home.join(".emacs")).unwrap();
+        s
+    }
+
+    #[test]
+    fn linking_skips_conflicts_by_default() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let conflicts = s
+            .link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![link_op_for(&s.root_path(), home, ".profile")]
+        );
+        assert_eq!(
+            conflicts,
+            vec![
+                Conflict {
+                    path: PathBuf::from(".vimrc"),
+                    state: LinkState::Blocked,
+                },
+                Conflict {
+                    path: PathBuf::from(".emacs"),
+                    state: LinkState::LinkedElsewhere(PathBuf::from("/elsewhere")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn linking_can_back_up_conflicts() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let conflicts = s
+            .link(&mut file_ops, ConflictPolicy::Backup)
+            .expect("Link failed");
+
+        let shell_root = s.root_path();
+        assert!(conflicts.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Move {
+                    from: home.join(".vimrc"),
+                    to: home.join(".vimrc.hermit-backup.1"),
+                },
+                link_op_for(&shell_root, home, ".vimrc"),
+                Op::Move {
+                    from: home.join(".emacs"),
+                    to: home.join(".emacs.hermit-backup"),
+                },
+                link_op_for(&shell_root, home, ".emacs"),
+                link_op_for(&shell_root, home, ".profile"),
+            ]
+        );
+    }
+
+    #[test]
+    fn linking_can_overwrite_conflicts() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::Overwrite)
+            .expect("Link failed");
+
+        let shell_root = s.root_path();
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Remove(home.join(".vimrc")),
+                link_op_for(&shell_root, home, ".vimrc"),
+                Op::Remove(home.join(".emacs")),
+                link_op_for(&shell_root, home, ".emacs"),
+                link_op_for(&shell_root, home, ".profile"),
+            ]
+        );
+    }
+
+    #[test]
+    fn linking_can_abort_on_conflicts() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let result = s.link(&mut file_ops, ConflictPolicy::Abort);
+
+        assert_eq!(result, Err(Error::LinkConflict(home.join(".vimrc"))));
+    }
+
+    #[test]
+    fn linking_treats_paths_being_removed_as_missing() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.remove(".emacs");
+
+        s.link(&mut file_ops, ConflictPolicy::Abort)
+            .expect_err("Link unexpectedly succeeded");
+        let conflicts = s
+            .link(&mut file_ops, ConflictPolicy::Skip)
+            .expect("Link failed");
+
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].path, PathBuf::from(".vimrc"));
+    }
+
     #[test]
-    fn can_unlink_all_paths() {
+    fn can_add_a_path() {
         let root_path = root_path("/Users/geoff/.config/hermit");
-        let mut config = MockConfig::with_root(&root_path);
-        config.set_paths(vec![".bashrc", ".boot/profile.boot"]);
-        let s = Shell::new("default", Rc::new(config));
+        let s = Shell::new(shell_name("default"), mock_config(&root_path));
         let op_root = PathBuf::from("op_root");
         let mut file_ops = FileOperations::rooted_at(&op_root);
 
-        s.unlink(&mut file_ops);
+        s.add(
+            &mut file_ops,
+            ".boot/profile.boot",
+            ".boot/profile.boot",
+            LinkMode::Symlink,
+        );
 
+        let shell_root = s.root_path();
         assert_eq!(
             file_ops.operations(),
             &vec![
-                Op::Remove(op_root.join(".bashrc")),
-                Op::Remove(op_root.join(".boot/profile.boot"))
+                Op::MkDir(shell_root.join(".boot")),
+                Op::Move {
+                    from: op_root.join(".boot/profile.boot"),
+                    to: shell_root.join(".boot/profile.boot"),
+                },
+                link_op_for(&shell_root, &op_root, ".boot/profile.boot"),
             ]
         );
     }
+
+    fn link_states(s: &Shell<MockConfig>, home: &Path) -> Vec<(PathBuf, Result<LinkState>)> {
+        s.link_states(home)
+            .expect("Could not find the shell's files")
+            .into_iter()
+            .map(|(file, state)| (file.path, state))
+            .collect()
+    }
+
+    #[test]
+    fn can_classify_the_link_state_of_its_files() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".bashrc", ".vimrc", ".profile", ".emacs"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let shell_root = s.root_path();
+        unix::fs::symlink(shell_root.join(".bashrc"), home.join(".bashrc")).unwrap();
+        fs::write(home.join(".vimrc"), "").unwrap();
+        unix::fs::symlink("/elsewhere", home.join(".emacs")).unwrap();
+
+        assert_eq!(
+            link_states(&s, home),
+            vec![
+                (PathBuf::from(".bashrc"), Ok(LinkState::Linked)),
+                (PathBuf::from(".vimrc"), Ok(LinkState::Blocked)),
+                (PathBuf::from(".profile"), Ok(LinkState::Missing)),
+                (
+                    PathBuf::from(".emacs"),
+                    Ok(LinkState::LinkedElsewhere(PathBuf::from("/elsewhere")))
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinking_only_removes_links_into_the_shell() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::RemoveLink {
+                path: home.join(".bashrc"),
+                owner: s.root_path(),
+            }]
+        );
+        assert_eq!(
+            skipped,
+            vec![
+                Conflict {
+                    path: PathBuf::from(".vimrc"),
+                    state: LinkState::Blocked,
+                },
+                Conflict {
+                    path: PathBuf::from(".emacs"),
+                    state: LinkState::LinkedElsewhere(PathBuf::from("/elsewhere")),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinking_removes_stale_links_into_the_shell() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        fs::remove_file(home.join(".emacs")).unwrap();
+        unix::fs::symlink(s.root_path().join("old/.emacs"), home.join(".emacs")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert!(file_ops.operations().contains(&Op::RemoveLink {
+            path: home.join(".emacs"),
+            owner: s.root_path(),
+        }));
+    }
+
+    #[test]
+    fn forced_unlinking_removes_whatever_is_there() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = conflicted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = s.unlink(&mut file_ops, true).expect("Unlink failed");
+
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: s.root_path(),
+                },
+                Op::Remove(home.join(".vimrc")),
+                Op::Remove(home.join(".emacs")),
+            ]
+        );
+    }
+
+    fn redacted_shell(home: &Path) -> Shell<MockConfig> {
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".netrc"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let shell_root = s.root_path();
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(shell_root.join(".netrc"), "machine example.com\n").unwrap();
+        let secrets = redact::secrets(
+            "machine example.com\n",
+            "machine example.com\npassword hunter2\n",
+        );
+        fs::write(shell_root.join(".netrc.hermit-secrets"), secrets).unwrap();
+        s
+    }
+
+    #[test]
+    fn linking_a_redacted_file_generates_the_complete_file() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = redacted_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::WriteFile {
+                path: home.join(".netrc"),
+                contents: "machine example.com\npassword hunter2\n".to_owned(),
+                mode: PRIVATE_FILE_MODE,
+            }]
+        );
+    }
+
+    #[test]
+    fn unlinking_only_removes_generated_files_that_are_unchanged() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = redacted_shell(home);
+        fs::write(
+            home.join(".netrc"),
+            "machine example.com\npassword hunter2\n",
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Remove(home.join(".netrc"))]
+        );
+
+        fs::write(
+            home.join(".netrc"),
+            "machine example.com\npassword changed\n",
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert!(file_ops.operations().is_empty());
+        assert_eq!(
+            skipped,
+            vec![Conflict {
+                path: PathBuf::from(".netrc"),
+                state: LinkState::Changed,
+            }]
+        );
+    }
+
+    #[test]
+    fn linking_encrypted_secrets_needs_them_unlocked() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = redacted_shell(home);
+        let key = Key::derive("correct horse", Key::new_salt());
+        let secrets_path = s.root_path().join(".netrc.hermit-secrets");
+        let secrets = fs::read_to_string(&secrets_path).unwrap();
+        fs::write(&secrets_path, key.encrypt(&secrets)).unwrap();
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        let result = s.link(&mut file_ops, ConflictPolicy::default());
+        assert_eq!(result, Err(Error::SecretsLocked));
+
+        fs::write(home.join(".hermit/secrets.key"), key.to_file_contents()).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::WriteFile {
+                path: home.join(".netrc"),
+                contents: "machine example.com\npassword hunter2\n".to_owned(),
+                mode: PRIVATE_FILE_MODE,
+            }]
+        );
+    }
+
+    fn templated_shell(home: &Path) -> Shell<MockConfig> {
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".gitconfig.tmpl"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let shell_root = s.root_path();
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(
+            shell_root.join(".gitconfig.tmpl"),
+            "email = {{ machine.email }}\n",
+        )
+        .unwrap();
+        fs::set_permissions(
+            shell_root.join(".gitconfig.tmpl"),
+            fs::Permissions::from_mode(0o750),
+        )
+        .unwrap();
+        fs::write(
+            home.join(".hermit/machine.toml"),
+            "email = \"geoff@example.com\"\n",
+        )
+        .unwrap();
+        s
+    }
+
+    #[test]
+    fn linking_a_template_renders_it_without_its_suffix() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = templated_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::WriteFile {
+                path: home.join(".gitconfig"),
+                contents: "email = geoff@example.com\n".to_owned(),
+                mode: 0o750,
+            }]
+        );
+    }
+
+    #[test]
+    fn renders_that_no_longer_match_their_template_are_stale() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = templated_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_state(s.config.state_path());
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        assert_eq!(
+            link_states(&s, home),
+            vec![(PathBuf::from(".gitconfig"), Ok(LinkState::Linked))]
+        );
+
+        fs::write(
+            home.join(".hermit/machine.toml"),
+            "email = \"geoff@work.example.com\"\n",
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        assert_eq!(
+            link_states(&s, home),
+            vec![(PathBuf::from(".gitconfig"), Ok(LinkState::Stale))]
+        );
+        s.unlink(&mut file_ops, false).expect("Unlink failed");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![Op::Remove(home.join(".gitconfig"))]
+        );
+    }
+
+    #[test]
+    fn renders_edited_by_hand_are_changed_not_stale() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = templated_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_state(s.config.state_path());
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        fs::write(home.join(".gitconfig"), "email = me@example.com\n").unwrap();
+        fs::write(
+            home.join(".hermit/machine.toml"),
+            "email = \"geoff@work.example.com\"\n",
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        assert_eq!(
+            link_states(&s, home),
+            vec![(PathBuf::from(".gitconfig"), Ok(LinkState::Changed))]
+        );
+        assert_eq!(
+            s.unlink(&mut file_ops, false).expect("Unlink failed"),
+            vec![Conflict {
+                path: PathBuf::from(".gitconfig"),
+                state: LinkState::Changed,
+            }]
+        );
+        assert!(file_ops.operations().is_empty());
+    }
+
+    fn moded_shell(home: &Path) -> Shell<MockConfig> {
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".bashrc", ".vimrc", ".config/Code/settings.json"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let shell_root = s.root_path();
+        fs::create_dir_all(shell_root.join(".config/Code")).unwrap();
+        for path in &[".bashrc", ".vimrc", ".config/Code/settings.json"] {
+            fs::write(shell_root.join(path), format!("# {}\n", path)).unwrap();
+        }
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \".bashrc\"\n\
+             mode = \"relative\"\n\
+             \n\
+             [[files]]\n\
+             path = \".vimrc\"\n\
+             mode = \"hardlink\"\n\
+             \n\
+             [[files]]\n\
+             path = \".config/Code\"\n\
+             mode = \"copy\"\n",
+        )
+        .unwrap();
+        s
+    }
+
+    #[test]
+    fn links_files_the_way_the_manifest_says() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = moded_shell(home);
+        let shell_root = s.root_path();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RelativeLink {
+                    path: home.join(".bashrc"),
+                    target: shell_root.join(".bashrc"),
+                },
+                Op::HardLink {
+                    path: home.join(".vimrc"),
+                    target: shell_root.join(".vimrc"),
+                },
+                Op::MkDir(home.join(".config/Code")),
+                Op::Copy {
+                    from: shell_root.join(".config/Code/settings.json"),
+                    to: home.join(".config/Code/settings.json"),
+                },
+            ]
+        );
+
+        file_ops.commit_atomic().expect("Commit failed");
+        assert_eq!(
+            link_states(&s, home),
+            vec![
+                (PathBuf::from(".bashrc"), Ok(LinkState::Linked)),
+                (PathBuf::from(".vimrc"), Ok(LinkState::Linked)),
+                (
+                    PathBuf::from(".config/Code/settings.json"),
+                    Ok(LinkState::Linked)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn copies_and_broken_hard_links_drift() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = moded_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        fs::write(home.join(".config/Code/settings.json"), "{}\n").unwrap();
+        fs::remove_file(home.join(".vimrc")).unwrap();
+        fs::write(home.join(".vimrc"), "# .vimrc\n").unwrap();
+
+        assert_eq!(
+            link_states(&s, home),
+            vec![
+                (PathBuf::from(".bashrc"), Ok(LinkState::Linked)),
+                (PathBuf::from(".vimrc"), Ok(LinkState::Drifted)),
+                (
+                    PathBuf::from(".config/Code/settings.json"),
+                    Ok(LinkState::Drifted)
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinking_undoes_every_link_mode() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = moded_shell(home);
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+        fs::write(home.join(".config/Code/settings.json"), "{}\n").unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: s.root_path(),
+                },
+                Op::Remove(home.join(".vimrc")),
+            ]
+        );
+        assert_eq!(
+            skipped,
+            vec![Conflict {
+                path: PathBuf::from(".config/Code/settings.json"),
+                state: LinkState::Drifted,
+            }]
+        );
+    }
+
+    fn layered_shell(home: &Path) -> Shell<MockConfig> {
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.add_shell("team-base", vec![".bashrc", ".vimrc"]);
+        config.set_paths(vec![".vimrc", ".gitconfig"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        fs::create_dir_all(s.root_path()).unwrap();
+        fs::write(s.root_path().join(".hbases"), "# shared\nteam-base\n").unwrap();
+        s
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = layered_shell(home);
+        let shells = home.join(".hermit/shells");
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            s.layers(),
+            Ok(vec![shell_name("team-base"), shell_name("default")])
+        );
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                link_op_for(&shells.join("team-base"), home, ".bashrc"),
+                link_op_for(&shells.join("default"), home, ".vimrc"),
+                link_op_for(&shells.join("default"), home, ".gitconfig"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinking_removes_links_into_every_layer() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = layered_shell(home);
+        let shells = home.join(".hermit/shells");
+        unix::fs::symlink(shells.join("team-base/.bashrc"), home.join(".bashrc")).unwrap();
+        unix::fs::symlink(shells.join("team-base/.vimrc"), home.join(".vimrc")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: shells.join("team-base"),
+                },
+                Op::RemoveLink {
+                    path: home.join(".vimrc"),
+                    owner: shells.join("team-base"),
+                },
+            ]
+        );
+    }
+
+    /// A shell with a neovim config of its own, written out under the
+    /// shell root along with everything in `extra`.
+    fn nvim_shell(
+        home: &Path,
+        base: Option<(&str, Vec<&str>)>,
+        extra: &[&str],
+    ) -> Shell<MockConfig> {
+        let paths = vec![
+            ".bashrc",
+            ".config/nvim/init.lua",
+            ".config/nvim/lua/plugins.lua",
+        ];
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(paths.clone());
+        let s = Shell::new(shell_name("default"), Rc::new(config.clone()));
+        let shells = config.shell_root_path();
+        let write = |shell: &str, path: &str| {
+            let file = shells.join(shell).join(path);
+            fs::create_dir_all(file.parent().unwrap()).unwrap();
+            fs::write(file, format!("-- {}\n", path)).unwrap();
+        };
+        for path in paths.iter().chain(extra) {
+            write("default", path);
+        }
+        if let Some((name, base_paths)) = base {
+            for path in &base_paths {
+                write(name, path);
+            }
+            config.add_shell(name, base_paths);
+            fs::write(shells.join("default/.hbases"), name).unwrap();
+        }
+        Shell::new(s.name, Rc::new(config))
+    }
+
+    #[test]
+    fn folds_directories_that_only_one_layer_has_files_in() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        fs::create_dir_all(home.join(".config")).unwrap();
+        let s = nvim_shell(home, None, &[]);
+        let shell_root = s.root_path();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                link_op_for(&shell_root, home, ".bashrc"),
+                link_op_for(&shell_root, home, ".config/nvim"),
+            ]
+        );
+        file_ops.commit_atomic().expect("Commit failed");
+        assert_eq!(
+            link_states(&s, home),
+            vec![
+                (PathBuf::from(".bashrc"), Ok(LinkState::Linked)),
+                (PathBuf::from(".config/nvim"), Ok(LinkState::Linked)),
+            ]
+        );
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: shell_root.clone(),
+                },
+                Op::RemoveLink {
+                    path: home.join(".config/nvim"),
+                    owner: shell_root,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn does_not_fold_directories_that_home_does_not_have_yet() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = nvim_shell(home, None, &[]);
+        let shell_root = s.root_path();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                link_op_for(&shell_root, home, ".bashrc"),
+                Op::MkDir(home.join(".config/nvim")),
+                link_op_for(&shell_root, home, ".config/nvim/init.lua"),
+                Op::MkDir(home.join(".config/nvim/lua")),
+                link_op_for(&shell_root, home, ".config/nvim/lua/plugins.lua"),
+            ]
+        );
+        file_ops.commit_atomic().expect("Commit failed");
+        assert!(!fs::symlink_metadata(home.join(".config"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        assert!(file_ops.operations().is_empty());
+    }
+
+    #[test]
+    fn does_not_fold_directories_with_files_that_would_show_through() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        fs::create_dir_all(home.join(".config")).unwrap();
+        let s = nvim_shell(home, None, &[".config/nvim/lua/.hignore"]);
+        let shell_root = s.root_path();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                link_op_for(&shell_root, home, ".bashrc"),
+                Op::MkDir(home.join(".config/nvim")),
+                link_op_for(&shell_root, home, ".config/nvim/init.lua"),
+                Op::MkDir(home.join(".config/nvim/lua")),
+                link_op_for(&shell_root, home, ".config/nvim/lua/plugins.lua"),
+            ]
+        );
+    }
+
+    #[test]
+    fn unfolds_directories_once_they_are_shared() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        fs::create_dir_all(home.join(".config")).unwrap();
+        let s = nvim_shell(home, None, &[]);
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        let s = nvim_shell(
+            home,
+            Some(("team-base", vec![".config/nvim/team.lua"])),
+            &[],
+        );
+        let shells = home.join(".hermit/shells");
+        let shell_root = s.root_path();
+        assert_eq!(
+            link_states(&s, home),
+            vec![
+                (
+                    PathBuf::from(".config/nvim/team.lua"),
+                    Ok(LinkState::Missing)
+                ),
+                (PathBuf::from(".bashrc"), Ok(LinkState::Linked)),
+                (
+                    PathBuf::from(".config/nvim/init.lua"),
+                    Ok(LinkState::Missing)
+                ),
+                (PathBuf::from(".config/nvim/lua"), Ok(LinkState::Missing)),
+            ]
+        );
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".config/nvim"),
+                    owner: shell_root.clone(),
+                },
+                Op::MkDir(home.join(".config/nvim")),
+                link_op_for(&shells.join("team-base"), home, ".config/nvim/team.lua"),
+                link_op_for(&shell_root, home, ".config/nvim/init.lua"),
+                link_op_for(&shell_root, home, ".config/nvim/lua"),
+            ]
+        );
+        file_ops.commit_atomic().expect("Commit failed");
+        assert!(link_states(&s, home)
+            .iter()
+            .all(|(_, state)| *state == Ok(LinkState::Linked)));
+        assert!(fs::symlink_metadata(shell_root.join(".config/nvim/team.lua")).is_err());
+    }
+
+    #[test]
+    fn always_folds_directories_the_manifest_says_to() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = nvim_shell(
+            home,
+            Some(("team-base", vec![".config/nvim/team.lua"])),
+            &[".config/nvim/.hignore"],
+        );
+        let shell_root = s.root_path();
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \".config/nvim\"\n\
+             fold = true\n",
+        )
+        .unwrap();
+        fs::create_dir_all(home.join(".config/nvim")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        let conflicts = s
+            .link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        assert_eq!(
+            conflicts,
+            vec![Conflict {
+                path: PathBuf::from(".config/nvim"),
+                state: LinkState::Blocked,
+            }]
+        );
+
+        fs::remove_dir(home.join(".config/nvim")).unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                link_op_for(&shell_root, home, ".config/nvim"),
+                link_op_for(&shell_root, home, ".bashrc"),
+            ]
+        );
+    }
+
+    #[test]
+    fn links_files_to_where_the_manifest_maps_them() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec!["dot_bashrc", "vscode/settings.json"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let shell_root = s.root_path();
+        fs::create_dir_all(shell_root.join("vscode")).unwrap();
+        fs::write(shell_root.join("dot_bashrc"), "").unwrap();
+        fs::write(shell_root.join("vscode/settings.json"), "{}").unwrap();
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "dot_prefix = true\n\
+             include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \"vscode\"\n\
+             target = \".config/Code/User\"\n",
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::Link {
+                    path: home.join(".bashrc"),
+                    target: shell_root.join("dot_bashrc"),
+                },
+                Op::MkDir(home.join(".config/Code/User")),
+                Op::Link {
+                    path: home.join(".config/Code/User/settings.json"),
+                    target: shell_root.join("vscode/settings.json"),
+                },
+            ]
+        );
+        file_ops.commit_atomic().expect("Commit failed");
+        assert_eq!(
+            link_states(&s, home),
+            vec![
+                (PathBuf::from(".bashrc"), Ok(LinkState::Linked)),
+                (
+                    PathBuf::from(".config/Code/User/settings.json"),
+                    Ok(LinkState::Linked)
+                ),
+            ]
+        );
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        s.unlink(&mut file_ops, false).expect("Unlink failed");
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: shell_root.clone(),
+                },
+                Op::RemoveLink {
+                    path: home.join(".config/Code/User/settings.json"),
+                    owner: shell_root,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinking_works_from_what_was_recorded() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let mut config = MockConfig::with_root(home.join(".hermit"));
+        config.set_paths(vec![".bashrc", ".vimrc"]);
+        let state_path = config.state_path();
+        let s = Shell::new(shell_name("default"), Rc::new(config.clone()));
+        let shell_root = s.root_path();
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(shell_root.join(".bashrc"), "").unwrap();
+        fs::write(shell_root.join(".vimrc"), "set number\n").unwrap();
+        fs::write(
+            shell_root.join("hermit.toml"),
+            "include = [\"**\"]\n\
+             \n\
+             [[files]]\n\
+             path = \".vimrc\"\n\
+             mode = \"copy\"\n",
+        )
+        .unwrap();
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_state(&state_path);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        fs::remove_file(shell_root.join(".bashrc")).unwrap();
+        fs::write(shell_root.join(".vimrc"), "set nonumber\n").unwrap();
+        config.set_paths(vec![".vimrc"]);
+        let s = Shell::new(shell_name("default"), Rc::new(config));
+        let orphans = s.orphans(home).expect("Could not read the state");
+        assert_eq!(
+            orphans
+                .iter()
+                .map(|record| record.path.clone())
+                .collect::<Vec<_>>(),
+            vec![home.join(".bashrc")]
+        );
+
+        let mut file_ops = FileOperations::rooted_at(home);
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations(),
+            &vec![
+                Op::RemoveLink {
+                    path: home.join(".bashrc"),
+                    owner: shell_root.join(".bashrc"),
+                },
+                Op::Remove(home.join(".vimrc")),
+            ]
+        );
+    }
+
+    #[test]
+    fn unlinking_removes_what_was_recorded_when_the_bases_are_gone() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = nvim_shell(home, Some(("team-base", vec![".vimrc"])), &[]);
+        let shells = home.join(".hermit/shells");
+        let state_path = s.config.state_path();
+        let mut file_ops = FileOperations::rooted_at(home);
+        file_ops.keep_state(&state_path);
+        s.link(&mut file_ops, ConflictPolicy::default())
+            .expect("Link failed");
+        file_ops.commit_atomic().expect("Commit failed");
+
+        fs::write(shells.join("default/.hbases"), "nope\n").unwrap();
+        assert!(s.layers().is_err());
+        let mut file_ops = FileOperations::rooted_at(home);
+        let skipped = s.unlink(&mut file_ops, false).expect("Unlink failed");
+
+        assert!(skipped.is_empty());
+        assert_eq!(
+            file_ops.operations().len(),
+            State::load(&state_path).unwrap().records.len()
+        );
+        assert!(file_ops.operations().contains(&Op::RemoveLink {
+            path: home.join(".vimrc"),
+            owner: shells.join("team-base/.vimrc"),
+        }));
+    }
+
+    #[test]
+    fn refuses_missing_and_circular_bases() {
+        let home_dir = set_up();
+        let home = home_dir.path();
+        let s = layered_shell(home);
+        let base_root = home.join(".hermit/shells/team-base");
+        fs::create_dir_all(&base_root).unwrap();
+
+        fs::write(base_root.join(".hbases"), "default\n").unwrap();
+        assert_eq!(
+            s.files(),
+            Err(Error::ShellBasedOnItself("default".to_owned()))
+        );
+
+        fs::write(base_root.join(".hbases"), "nope\n").unwrap();
+        assert_eq!(
+            s.files(),
+            Err(Error::MissingBaseShell {
+                shell: "team-base".to_owned(),
+                base: "nope".to_owned(),
+            })
+        );
+    }
 }
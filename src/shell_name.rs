@@ -0,0 +1,113 @@
+use crate::common::*;
+
+/// The name of a shell.
+///
+/// Shell names become directory names under the shell root, so they
+/// are checked to make sure a name can only ever refer to a directory
+/// directly inside it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ShellName(String);
+
+impl ShellName {
+    /// The longest name a shell can have, in bytes.
+    pub const MAX_LEN: usize = 64;
+
+    pub fn new(name: impl Into<String>) -> Result<ShellName> {
+        let name = name.into();
+        let problem = if name.is_empty() {
+            Some("it is empty")
+        } else if name.len() > ShellName::MAX_LEN {
+            Some("it is too long")
+        } else if name.contains(['/', '\\']) {
+            Some("it contains a path separator")
+        } else if name.contains("..") {
+            Some("it contains \"..\"")
+        } else if name.starts_with('.') {
+            Some("it starts with a dot")
+        } else if name.contains(char::is_control) {
+            Some("it contains a control character")
+        } else {
+            None
+        };
+
+        match problem {
+            Some(problem) => Err(Error::InvalidShellName(name, problem)),
+            None => Ok(ShellName(name)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::str::FromStr for ShellName {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ShellName> {
+        ShellName::new(s)
+    }
+}
+
+impl fmt::Display for ShellName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl AsRef<str> for ShellName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for ShellName {
+    fn as_ref(&self) -> &Path {
+        self.0.as_ref()
+    }
+}
+
+impl PartialEq<str> for ShellName {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for ShellName {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_ordinary_names() {
+        for name in &["default", "work", "team-base", "v2.laptop", "my_shell"] {
+            assert_eq!(
+                ShellName::new(*name).map(|name| name.to_string()),
+                Ok(name.to_string())
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_names_that_could_leave_the_shell_root() {
+        let problem = |name: &str| match ShellName::new(name) {
+            Err(Error::InvalidShellName(_, problem)) => problem,
+            other => panic!("Expected {:?} to be refused, not {:?}", name, other),
+        };
+
+        assert_eq!(problem(""), "it is empty");
+        assert_eq!(problem(&"a".repeat(65)), "it is too long");
+        assert_eq!(problem("../../.ssh"), "it contains a path separator");
+        assert_eq!(problem("work/other"), "it contains a path separator");
+        assert_eq!(problem("a..b"), "it contains \"..\"");
+        assert_eq!(problem(".."), "it contains \"..\"");
+        assert_eq!(problem(".hidden"), "it starts with a dot");
+        assert_eq!(problem("default\n"), "it contains a control character");
+        assert!(ShellName::new("a".repeat(64)).is_ok());
+    }
+}
@@ -0,0 +1,262 @@
+use crate::common::*;
+
+use blake2::{Blake2s256, Digest};
+use serde::{Deserialize, Serialize};
+
+use crate::{file_operations::relative_path, template};
+
+/// Everything hermit has put into the home directory and not taken
+/// out again, as recorded in the state file under the hermit root.
+///
+/// Linking records what it creates, and removing a path forgets it,
+/// so hermit can still find the files it made after their sources are
+/// gone from the shell.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default, rename = "links")]
+    pub records: Vec<Record>,
+}
+
+/// A file or directory that hermit put into the home directory.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Where it was put.
+    pub path: PathBuf,
+    /// The shell file or directory it was made from.
+    pub source: PathBuf,
+    pub strategy: Strategy,
+    /// A hash of the contents that were written, for the strategies
+    /// that make real files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+/// How a recorded file was put in place.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Strategy {
+    Symlink,
+    Relative,
+    Copy,
+    Hardlink,
+    /// Written by hermit, from a template or a redacted file.
+    Generated,
+}
+
+/// A change to the state that's waiting for its operations to commit.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change {
+    Record(Record),
+    Forget(PathBuf),
+}
+
+impl State {
+    /// Reads the state file at `path`. Without one, nothing has been
+    /// recorded yet.
+    pub fn load(path: &Path) -> Result<State> {
+        match fs::read_to_string(path) {
+            Ok(contents) => toml::from_str(&contents).map_err(|err| {
+                Error::BadStateFile(path.to_path_buf(), message::toml_error(&contents, &err))
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(State::default()),
+            Err(err) => Err(Error::BadStateFile(path.to_path_buf(), err.to_string())),
+        }
+    }
+
+    /// Writes the state to `path` by replacing it whole, so it's never
+    /// left half written.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let contents = toml::to_string(self).map_err(io::Error::other)?;
+        let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+        temp_name.push(".tmp");
+        let temp = path.with_file_name(temp_name);
+        fs::write(&temp, contents)?;
+        fs::rename(&temp, path)
+    }
+
+    pub fn record(&mut self, record: Record) {
+        self.forget(&record.path);
+        self.records.push(record);
+    }
+
+    /// Forgets `path` and anything that was reached through it.
+    pub fn forget(&mut self, path: &Path) {
+        self.records.retain(|record| !record.path.starts_with(path));
+    }
+
+    pub fn apply(&mut self, change: Change) {
+        match change {
+            Change::Record(record) => self.record(record),
+            Change::Forget(path) => self.forget(&path),
+        }
+    }
+
+    pub fn get(&self, path: &Path) -> Option<&Record> {
+        self.records.iter().find(|record| record.path == path)
+    }
+}
+
+impl Record {
+    /// Records that `placement` put the shell file at `source` into
+    /// the home directory at `path`.
+    pub fn new(path: &Path, source: &Path, placement: &Placement) -> Record {
+        let (strategy, hash) = match placement {
+            Placement::Link(_) => (Strategy::Symlink, None),
+            Placement::RelativeLink(_) => (Strategy::Relative, None),
+            Placement::Copy(_) => (Strategy::Copy, hash_file(source)),
+            Placement::HardLink(_) => (Strategy::Hardlink, hash_file(source)),
+            Placement::Generate(contents) | Placement::Render(contents, _) => {
+                (Strategy::Generated, Some(hash(contents.as_bytes())))
+            }
+        };
+        Record {
+            path: path.to_path_buf(),
+            source: source.to_path_buf(),
+            strategy,
+            hash,
+        }
+    }
+
+    /// Like `new`, but for a file at `path` that's being moved into
+    /// the shell at `source`, so its contents are hashed where they
+    /// are now.
+    pub fn moved(path: &Path, source: &Path, placement: &Placement) -> Record {
+        let record = Record::new(path, source, placement);
+        match record.strategy {
+            Strategy::Copy | Strategy::Hardlink => Record {
+                hash: hash_file(path),
+                ..record
+            },
+            _ => record,
+        }
+    }
+
+    /// Whether the recorded file is still what hermit left there.
+    /// Links have to point at their source, hard links have to be the
+    /// same file as it, and other real files have to have the
+    /// contents that were written.
+    pub fn state(&self) -> LinkState {
+        match self.strategy {
+            Strategy::Symlink => LinkState::of(&self.path, &self.source),
+            Strategy::Relative => {
+                let dir = self.path.parent().unwrap_or_else(|| Path::new("/"));
+                LinkState::of(&self.path, &relative_path(&self.source, dir))
+            }
+            Strategy::Hardlink => LinkState::of_copy(&self.path, &self.source, true),
+            Strategy::Copy | Strategy::Generated => match fs::symlink_metadata(&self.path) {
+                Err(_) => LinkState::Missing,
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    match fs::read_link(&self.path) {
+                        Ok(target) => LinkState::LinkedElsewhere(target),
+                        Err(_) => LinkState::Blocked,
+                    }
+                }
+                Ok(metadata) if metadata.is_dir() => LinkState::Blocked,
+                Ok(_) if hash_file(&self.path) == self.hash => LinkState::Linked,
+                Ok(_) if self.strategy == Strategy::Generated => LinkState::Changed,
+                Ok(_) => LinkState::Drifted,
+            },
+        }
+    }
+
+    /// Whether the recorded file is the complete view of a redacted
+    /// file, which holds its secrets.
+    pub fn is_secret(&self) -> bool {
+        self.strategy == Strategy::Generated && !template::is_template(&self.source)
+    }
+
+    /// Whether the recorded file is a link, rather than a real file.
+    pub fn is_link(&self) -> bool {
+        matches!(self.strategy, Strategy::Symlink | Strategy::Relative)
+    }
+}
+
+pub fn hash(contents: &[u8]) -> String {
+    hex::encode(Blake2s256::digest(contents))
+}
+
+pub fn hash_file(path: &Path) -> Option<String> {
+    fs::read(path).ok().map(|contents| hash(&contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    #[test]
+    fn keeps_what_it_records_until_it_is_forgotten() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let path = root.join("state.toml");
+        let link = |path: &str| Record {
+            path: root.join(path),
+            source: root.join("shells/default").join(path),
+            strategy: Strategy::Symlink,
+            hash: None,
+        };
+
+        let mut state = State::load(&path).unwrap();
+        assert_eq!(state, State::default());
+        state.apply(Change::Record(link(".bashrc")));
+        state.apply(Change::Record(link(".config/nvim/init.lua")));
+        state.apply(Change::Record(link(".bashrc")));
+        state.apply(Change::Forget(root.join(".config/nvim")));
+        state.save(&path).unwrap();
+
+        assert_eq!(State::load(&path).unwrap().records, vec![link(".bashrc")]);
+        fs::write(&path, "[[links]]\npath = 12\n").unwrap();
+        assert!(matches!(
+            State::load(&path),
+            Err(Error::BadStateFile(_, problem)) if problem.starts_with("line 2: ")
+        ));
+    }
+
+    #[test]
+    fn knows_whether_its_files_are_still_as_they_were_left() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let source = root.join("source");
+        fs::write(&source, "contents").unwrap();
+        let copy = Record::new(
+            &root.join("copy"),
+            &source,
+            &Placement::Copy(source.clone()),
+        );
+
+        assert_eq!(copy.state(), LinkState::Missing);
+        fs::write(root.join("copy"), "contents").unwrap();
+        fs::remove_file(&source).unwrap();
+        assert_eq!(copy.state(), LinkState::Linked);
+        fs::write(root.join("copy"), "edited").unwrap();
+        assert_eq!(copy.state(), LinkState::Drifted);
+
+        let link = Record::new(
+            &root.join("link"),
+            &source,
+            &Placement::Link(source.clone()),
+        );
+        unix::fs::symlink(&source, root.join("link")).unwrap();
+        assert_eq!(link.state(), LinkState::Linked);
+    }
+
+    #[test]
+    fn knows_hard_links_by_the_file_they_share() {
+        let test_root_dir = set_up();
+        let root = test_root_dir.path();
+        let source = root.join("source");
+        fs::write(root.join("added"), "contents").unwrap();
+        let placement = Placement::HardLink(source.clone());
+        let hard_link = Record::moved(&root.join("added"), &source, &placement);
+        assert!(hard_link.hash.is_some());
+
+        fs::rename(root.join("added"), &source).unwrap();
+        fs::hard_link(&source, root.join("added")).unwrap();
+        assert_eq!(hard_link.state(), LinkState::Linked);
+
+        fs::remove_file(root.join("added")).unwrap();
+        fs::write(root.join("added"), "contents").unwrap();
+        assert_eq!(hard_link.state(), LinkState::Drifted);
+    }
+}
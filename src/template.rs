@@ -0,0 +1,249 @@
+use crate::common::*;
+
+use std::collections::BTreeMap;
+
+use minijinja::{context, Environment, ErrorKind};
+
+/// The suffix that marks a shell file as a template. The file it
+/// renders to has the same name without the suffix.
+pub const TEMPLATE_SUFFIX: &str = ".tmpl";
+
+pub fn is_template(path: &Path) -> bool {
+    path.file_name()
+        .is_some_and(|name| name.to_string_lossy().ends_with(TEMPLATE_SUFFIX))
+}
+
+/// Where the shell file at `path` ends up, relative to the home
+/// directory. Only templates end up somewhere other than `path`.
+pub fn target_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    match file_name.strip_suffix(TEMPLATE_SUFFIX) {
+        Some(rendered) if !rendered.is_empty() => path.with_file_name(rendered),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// The template that renders to `path`, relative to the shell root.
+pub fn template_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(TEMPLATE_SUFFIX);
+    path.with_file_name(file_name)
+}
+
+/// The values that templates can use.
+///
+/// Besides what hermit can find out about the machine it's running
+/// on, templates can use `machine`, the values in the machine values
+/// file. That file is TOML, and lives outside of every shell so that
+/// each machine can have its own.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Variables {
+    pub hostname: String,
+    pub os: String,
+    pub username: String,
+    pub env: BTreeMap<String, String>,
+    pub machine: toml::Table,
+}
+
+impl Variables {
+    pub fn gather(machine_values_path: &Path) -> Result<Variables> {
+        let machine = match fs::read_to_string(machine_values_path) {
+            Ok(contents) => contents.parse().map_err(|err| {
+                Error::BadMachineValues(
+                    machine_values_path.to_path_buf(),
+                    message::toml_error(&contents, &err),
+                )
+            })?,
+            Err(_) => toml::Table::new(),
+        };
+        Ok(Variables {
+            hostname: whoami::fallible::hostname().unwrap_or_default(),
+            os: std::env::consts::OS.to_owned(),
+            username: whoami::username(),
+            env: env::vars().collect(),
+            machine,
+        })
+    }
+}
+
+/// Renders the template at `path`, relative to `shell_root`. Other
+/// files in the shell can be included by their path from the root.
+pub fn render(shell_root: &Path, path: &Path, variables: &Variables) -> Result<String> {
+    let mut environment = Environment::new();
+    let root = shell_root.to_path_buf();
+    environment.set_loader(move |name| load(&root, name));
+    environment.set_keep_trailing_newline(true);
+
+    let cannot_render =
+        |err: minijinja::Error| Error::CannotRender(shell_root.join(path), err.to_string());
+    let name = path.to_string_lossy();
+    environment
+        .get_template(&name)
+        .and_then(|template| {
+            template.render(context! {
+                hostname => variables.hostname,
+                os => variables.os,
+                username => variables.username,
+                env => variables.env,
+                machine => variables.machine,
+            })
+        })
+        .map_err(cannot_render)
+}
+
+/// Loads the template called `name` from the shell at `shell_root`.
+/// Unlike minijinja's own loader, this one has to load dotfiles, since
+/// that's what most shell files are.
+fn load(shell_root: &Path, name: &str) -> StdResult<Option<String>, minijinja::Error> {
+    let relative = Path::new(name);
+    let escapes = relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir));
+    if escapes {
+        return Ok(None);
+    }
+    match fs::read_to_string(shell_root.join(relative)) {
+        Ok(contents) => Ok(Some(contents)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(minijinja::Error::new(
+            ErrorKind::InvalidOperation,
+            "could not read template",
+        )
+        .with_source(err)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::test_helpers::filesystem::set_up;
+
+    fn variables() -> Variables {
+        Variables {
+            hostname: "laptop".to_owned(),
+            os: "linux".to_owned(),
+            username: "geoff".to_owned(),
+            env: vec![("SHELL".to_owned(), "/bin/zsh".to_owned())]
+                .into_iter()
+                .collect(),
+            machine: "work = true\nemail = \"geoff@example.com\"\n"
+                .parse()
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn renders_templates_to_the_file_without_their_suffix() {
+        assert!(is_template(Path::new(".config/git/config.tmpl")));
+        assert!(!is_template(Path::new(".bashrc")));
+        assert_eq!(
+            target_path(Path::new(".config/git/config.tmpl")),
+            PathBuf::from(".config/git/config")
+        );
+        assert_eq!(target_path(Path::new(".bashrc")), PathBuf::from(".bashrc"));
+        assert_eq!(target_path(Path::new(".tmpl")), PathBuf::from(".tmpl"));
+        assert_eq!(
+            template_path(Path::new(".bashrc")),
+            PathBuf::from(".bashrc.tmpl")
+        );
+    }
+
+    #[test]
+    fn renders_variables_and_conditionals() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+        fs::write(
+            shell_root.join(".gitconfig.tmpl"),
+            "# {{ username }}@{{ hostname }} on {{ os }}, using {{ env.SHELL }}\n\
+             {% if machine.work %}email = {{ machine.email }}\n{% endif %}",
+        )
+        .unwrap();
+
+        let rendered = render(shell_root, Path::new(".gitconfig.tmpl"), &variables());
+
+        assert_eq!(
+            rendered,
+            Ok("# geoff@laptop on linux, using /bin/zsh\n\
+                email = geoff@example.com\n"
+                .to_owned())
+        );
+    }
+
+    #[test]
+    fn includes_other_files_from_the_shell() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+        fs::create_dir_all(shell_root.join("partials")).unwrap();
+        fs::write(shell_root.join("partials/aliases"), "alias ll='ls -l'\n").unwrap();
+        fs::write(
+            shell_root.join(".bashrc.tmpl"),
+            "{% include \"partials/aliases\" %}export HOST={{ hostname }}\n",
+        )
+        .unwrap();
+
+        let rendered = render(shell_root, Path::new(".bashrc.tmpl"), &variables());
+
+        assert_eq!(
+            rendered,
+            Ok("alias ll='ls -l'\nexport HOST=laptop\n".to_owned())
+        );
+    }
+
+    #[test]
+    fn cannot_include_files_outside_of_the_shell() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path().join("shell");
+        fs::create_dir_all(&shell_root).unwrap();
+        fs::write(test_root_dir.path().join("secrets"), "hunter2\n").unwrap();
+        fs::write(
+            shell_root.join(".bashrc.tmpl"),
+            "{% include \"../secrets\" %}",
+        )
+        .unwrap();
+
+        let result = render(&shell_root, Path::new(".bashrc.tmpl"), &variables());
+
+        assert!(matches!(result, Err(Error::CannotRender(_, _))));
+    }
+
+    #[test]
+    fn reports_templates_that_cannot_be_rendered() {
+        let test_root_dir = set_up();
+        let shell_root = test_root_dir.path();
+        fs::write(shell_root.join(".bashrc.tmpl"), "{% if %}\n").unwrap();
+
+        let result = render(shell_root, Path::new(".bashrc.tmpl"), &variables());
+
+        match result {
+            Err(Error::CannotRender(path, _)) => {
+                assert_eq!(path, shell_root.join(".bashrc.tmpl"))
+            }
+            other => panic!("Expected a render error, not {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reads_machine_values_when_there_are_some() {
+        let test_root_dir = set_up();
+        let path = test_root_dir.path().join("machine.toml");
+
+        assert_eq!(
+            Variables::gather(&path).unwrap().machine,
+            toml::Table::new()
+        );
+
+        fs::write(&path, "work = true\n").unwrap();
+        let machine = Variables::gather(&path).unwrap().machine;
+        assert_eq!(machine.get("work"), Some(&toml::Value::Boolean(true)));
+
+        fs::write(&path, "work = true\nemail = \n").unwrap();
+        match Variables::gather(&path) {
+            Err(Error::BadMachineValues(bad_path, message)) => {
+                assert_eq!(bad_path, path);
+                assert!(message.starts_with("line 2: "), "{}", message);
+            }
+            other => panic!("Expected bad machine values, not {:?}", other),
+        }
+    }
+}
@@ -7,8 +7,8 @@ pub fn set_up() -> TempDir {
     let test_root_dir = tempdir().expect("failed to create tempdir");
     let test_root = test_root_dir.path();
 
-    match fs::create_dir_all(&test_root) {
-        Err(e) => match e.kind() {
+    if let Err(e) = fs::create_dir_all(test_root) {
+        match e.kind() {
             io::ErrorKind::AlreadyExists => {
                 write!(
                     io::stderr(),
@@ -17,9 +17,8 @@ pub fn set_up() -> TempDir {
                 )
                 .unwrap();
             }
-            _ => panic!(e),
-        },
-        Ok(()) => (),
+            _ => std::panic::panic_any(e),
+        }
     }
 
     test_root_dir
@@ -1,2 +1,9 @@
 pub mod filesystem;
 pub mod ops;
+
+use crate::shell_name::ShellName;
+
+/// Tests only ever use valid shell names.
+pub fn shell_name(name: &str) -> ShellName {
+    ShellName::new(name).expect("Test shell names are valid")
+}
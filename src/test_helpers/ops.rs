@@ -1,7 +1,7 @@
 use crate::file_operations::Op;
-use std::path::PathBuf;
+use std::path::Path;
 
-pub fn link_op_for(root_path: &PathBuf, op_root: &PathBuf, filename: &str) -> Op {
+pub fn link_op_for(root_path: &Path, op_root: &Path, filename: &str) -> Op {
     Op::Link {
         target: root_path.join(filename),
         path: op_root.join(filename),